@@ -0,0 +1,637 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::future::{ready, Ready};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    dev::{self, forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    mime::{self, Mime},
+    web::{self, Bytes, BytesMut},
+    Error, HttpMessage,
+};
+use futures::{Stream, StreamExt};
+use futures_util::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::auth::JwtAuth;
+use crate::config::AuditConfig;
+
+// `prev` of the first record ever written.
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+const REDACTED: &str = "[redacted]";
+
+// One line of the audit log. `hash` is the SHA-256 of the line as serialized
+// with an empty `hash`, and each record carries the hash of the one before,
+// so editing, removing or reordering lines breaks the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Record {
+    pub seq: u64,
+    // unix milliseconds
+    pub ts: u64,
+    // subject of a valid bearer token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub principal: Option<String>,
+    pub method: String,
+    pub path: String,
+    // the matched route pattern, absent for unmatched paths
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    pub status: u16,
+    // of the body bytes the handler read
+    pub body_size: u64,
+    pub body_sha256: String,
+    // redacted, only when `audit.body` is on and the body is small enough
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    pub prev: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+impl Record {
+    fn digest(&self) -> String {
+        let unhashed = Record {
+            hash: String::new(),
+            ..self.clone()
+        };
+        // a struct of strings and numbers always serializes
+        let bytes = serde_json::to_vec(&unhashed).unwrap();
+
+        hex::encode(Sha256::digest(bytes))
+    }
+}
+
+struct LogState {
+    file: File,
+    size: u64,
+    // of the last record written
+    seq: u64,
+    prev: String,
+}
+
+// The audit log file, rotated to `<path>.<last seq>` once it would grow past
+// `max_size`. The chain carries on across rotations.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    state: Mutex<LogState>,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> io::Result<Self> {
+        let path = config.path.clone();
+        // after a rotation the current file is empty until the next record
+        let last = match last_record(&path)? {
+            Some(record) => Some(record),
+            None => match rotated(&path)?.last() {
+                Some((_, rotated)) => last_record(rotated)?,
+                None => None,
+            },
+        };
+        let (seq, prev) = match last {
+            Some(record) => (record.seq, record.hash),
+            None => (0, String::from(GENESIS)),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(AuditLog {
+            path,
+            max_size: config.max_size,
+            keep: config.keep,
+            state: Mutex::new(LogState {
+                file,
+                size,
+                seq,
+                prev,
+            }),
+        })
+    }
+
+    // Chains `record` to the previous one and writes it, synced to disk.
+    pub fn append(&self, mut record: Record) -> io::Result<Record> {
+        let mut state = self.state.lock().unwrap();
+
+        record.seq = state.seq + 1;
+        record.prev = state.prev.clone();
+        record.hash = record.digest();
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        if state.size > 0 && state.size + line.len() as u64 > self.max_size {
+            self.rotate(&mut state)?;
+        }
+        state.file.write_all(&line)?;
+        state.file.sync_data()?;
+
+        state.size += line.len() as u64;
+        state.seq = record.seq;
+        state.prev = record.hash.clone();
+        Ok(record)
+    }
+
+    fn rotate(&self, state: &mut LogState) -> io::Result<()> {
+        let to = PathBuf::from(format!("{}.{}", self.path.display(), state.seq));
+        std::fs::rename(&self.path, &to)?;
+        state.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        state.size = 0;
+
+        let files = rotated(&self.path)?;
+        for (_, old) in files.iter().take(files.len().saturating_sub(self.keep)) {
+            std::fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+}
+
+// Rotated files of the log at `path` with the seq of their last record,
+// oldest first.
+pub fn rotated(path: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(Vec::new());
+    };
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let seq = file_name
+            .to_str()
+            .and_then(|f| f.strip_prefix(name)?.strip_prefix('.'))
+            .and_then(|seq| seq.parse::<u64>().ok());
+        if let Some(seq) = seq {
+            files.push((seq, entry.path()));
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+// The last record in the log at `path`. A last line that doesn't parse was
+// cut short by a crash mid-write; it's truncated away so the chain carries on
+// from the record before it.
+fn last_record(path: &Path) -> io::Result<Option<Record>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let last_line = |end: usize| {
+        let head = text[..end].trim_end();
+        let start = head.rfind('\n').map_or(0, |i| i + 1);
+        (start, &head[start..])
+    };
+
+    let (start, line) = last_line(text.len());
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let err = match serde_json::from_str(line) {
+        Ok(record) => return Ok(Some(record)),
+        Err(err) => err,
+    };
+    log::warn!(
+        "{}: dropping a partly written last record: {err}",
+        path.display()
+    );
+    OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_len(start as u64)?;
+
+    // only the last write can be torn, anything before it must parse
+    match last_line(start) {
+        (_, "") => Ok(None),
+        (_, line) => Ok(Some(serde_json::from_str(line)?)),
+    }
+}
+
+// What was wrong with a log, and on which line (1-based).
+#[derive(Debug)]
+pub struct VerifyError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Verified {
+    pub records: usize,
+    // seq and hash of the last record, `None` for an empty log
+    pub last: Option<(u64, String)>,
+}
+
+// Checks every record's hash and its link to the one before. `prev` is the
+// last hash of the preceding file when checking rotated files in order; a log
+// starting at seq 1 must start from `GENESIS`.
+pub fn verify(reader: impl BufRead, prev: Option<(u64, String)>) -> Result<Verified, VerifyError> {
+    let mut last = prev;
+    let mut records = 0;
+
+    for (i, line) in reader.lines().enumerate() {
+        let fail = |reason: String| VerifyError {
+            line: i + 1,
+            reason,
+        };
+        let line = line.map_err(|err| fail(err.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record: Record =
+            serde_json::from_str(&line).map_err(|err| fail(format!("not a record: {err}")))?;
+        if record.digest() != record.hash {
+            return Err(fail(format!("record {} was modified", record.seq)));
+        }
+        match &last {
+            Some((seq, hash)) if record.seq != seq + 1 || record.prev != *hash => {
+                return Err(fail(format!(
+                    "record {} does not follow record {seq}",
+                    record.seq
+                )));
+            }
+            None if record.seq == 1 && record.prev != GENESIS => {
+                return Err(fail(String::from(
+                    "the first record does not start the chain",
+                )));
+            }
+            _ => {}
+        }
+
+        records += 1;
+        last = Some((record.seq, record.hash));
+    }
+
+    Ok(Verified { records, last })
+}
+
+// Records every request other than GET and HEAD to the audit log, after the
+// response is ready and before it is sent. The body is hashed as the handler
+// reads it, without being buffered.
+#[derive(Clone)]
+pub struct Audit {
+    // `None` when auditing is off
+    log: Option<Arc<AuditLog>>,
+    jwt_auth: JwtAuth,
+    body: bool,
+    max_body: usize,
+    redact: Arc<Vec<String>>,
+}
+
+impl Audit {
+    pub fn new(config: &AuditConfig, jwt_auth: JwtAuth) -> io::Result<Self> {
+        let log = match config.enabled {
+            true => Some(Arc::new(AuditLog::open(config)?)),
+            false => None,
+        };
+
+        Ok(Audit {
+            log,
+            jwt_auth,
+            body: config.body,
+            max_body: config.max_body,
+            redact: Arc::new(config.redact.iter().map(|k| k.to_lowercase()).collect()),
+        })
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Audit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuditMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuditMiddleware {
+            service,
+            audit: self.clone(),
+        }))
+    }
+}
+
+pub struct AuditMiddleware<S> {
+    service: S,
+    audit: Audit,
+}
+
+// The body as the handler reads it.
+#[derive(Default)]
+struct Tap {
+    hasher: Sha256,
+    size: u64,
+    // the first `max_body` bytes
+    kept: BytesMut,
+}
+
+impl<S, B> Service<ServiceRequest> for AuditMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let log = match &self.audit.log {
+            Some(log) if !matches!(*req.method(), Method::GET | Method::HEAD) => Arc::clone(log),
+            _ => return Box::pin(self.service.call(req)),
+        };
+
+        let principal = self.audit.jwt_auth.subject(&req);
+        let content_type = req.mime_type().ok().flatten();
+        // one byte more than recorded, to tell when the body was too large
+        let keep = match self.audit.body {
+            true => self.audit.max_body + 1,
+            false => 0,
+        };
+
+        let tap = Rc::new(RefCell::new(Tap::default()));
+        let payload = req.take_payload().map({
+            let tap = Rc::clone(&tap);
+            move |chunk| {
+                if let Ok(bytes) = &chunk {
+                    let mut tap = tap.borrow_mut();
+                    tap.hasher.update(bytes);
+                    tap.size += bytes.len() as u64;
+                    let room = keep.saturating_sub(tap.kept.len());
+                    tap.kept.extend_from_slice(&bytes[..room.min(bytes.len())]);
+                }
+                chunk
+            }
+        });
+        let payload: Pin<Box<dyn Stream<Item = _>>> = Box::pin(payload);
+        req.set_payload(dev::Payload::from(payload));
+
+        let fut = self.service.call(req);
+        let audit = self.audit.clone();
+
+        Box::pin(async move {
+            let res = fut.await?;
+
+            let tap = tap.take();
+            let body = (audit.body && tap.kept.len() <= audit.max_body)
+                .then(|| redact(&tap.kept.freeze(), content_type.as_ref(), &audit.redact))
+                .flatten();
+            let record = Record {
+                seq: 0,
+                ts: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+                principal,
+                method: res.request().method().to_string(),
+                path: res.request().path().to_string(),
+                route: res.request().match_pattern(),
+                status: res.status().as_u16(),
+                body_size: tap.size,
+                body_sha256: hex::encode(tap.hasher.finalize()),
+                body,
+                prev: String::new(),
+                hash: String::new(),
+            };
+
+            match web::block(move || log.append(record)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => log::error!("could not write the audit log: {err}"),
+                Err(err) => log::error!("could not write the audit log: {err}"),
+            }
+            Ok(res)
+        })
+    }
+}
+
+// The body as recorded: JSON and form fields named in `keys` have their
+// values replaced and `text/*` is kept as is. Anything else, including JSON
+// or forms that don't parse, is left out since it can't be redacted; the
+// hash still covers it.
+fn redact(body: &Bytes, content_type: Option<&Mime>, keys: &[String]) -> Option<String> {
+    let hidden = |key: &str| keys.contains(&key.to_lowercase());
+    let content_type = content_type?;
+    let json = content_type.suffix().map(|s| s.as_str()) == Some("json")
+        || (content_type.type_(), content_type.subtype()) == (mime::APPLICATION, mime::JSON);
+
+    if json {
+        let mut value = serde_json::from_slice::<Value>(body).ok()?;
+        redact_json(&mut value, &hidden);
+        return Some(value.to_string());
+    }
+    if (content_type.type_(), content_type.subtype())
+        == (mime::APPLICATION, mime::WWW_FORM_URLENCODED)
+    {
+        let fields = serde_urlencoded::from_bytes::<Vec<(String, String)>>(body).ok()?;
+        let fields: Vec<_> = fields
+            .into_iter()
+            .map(|(key, value)| match hidden(&key) {
+                true => (key, String::from(REDACTED)),
+                false => (key, value),
+            })
+            .collect();
+        return serde_urlencoded::to_string(fields).ok();
+    }
+    if content_type.type_() == mime::TEXT {
+        return std::str::from_utf8(body).ok().map(str::to_string);
+    }
+
+    None
+}
+
+fn redact_json(value: &mut Value, hidden: &impl Fn(&str) -> bool) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if hidden(key) {
+                    *value = Value::from(REDACTED);
+                } else {
+                    redact_json(value, hidden);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| redact_json(v, hidden)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &str) -> Record {
+        Record {
+            seq: 0,
+            ts: 0,
+            principal: None,
+            method: String::from("POST"),
+            path: path.to_string(),
+            route: None,
+            status: 200,
+            body_size: 0,
+            body_sha256: hex::encode(Sha256::digest(b"")),
+            body: None,
+            prev: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    fn config(dir: &Path, max_size: u64) -> AuditConfig {
+        AuditConfig {
+            path: dir.join("audit.jsonl"),
+            max_size,
+            keep: 2,
+            ..AuditConfig::default()
+        }
+    }
+
+    fn verify_file(path: &Path) -> Result<Verified, VerifyError> {
+        verify(io::BufReader::new(File::open(path).unwrap()), None)
+    }
+
+    #[test]
+    fn chains_records_and_detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 1024 * 1024);
+        let log = AuditLog::open(&config).unwrap();
+        for path in ["/echo", "/form", "/person/auto"] {
+            log.append(record(path)).unwrap();
+        }
+        drop(log);
+
+        // reopening carries on from the last record
+        let log = AuditLog::open(&config).unwrap();
+        assert_eq!(log.append(record("/echo")).unwrap().seq, 4);
+
+        let verified = verify_file(&config.path).unwrap();
+        assert_eq!(verified.records, 4);
+
+        let text = std::fs::read_to_string(&config.path).unwrap();
+        let edited = text.replacen("/form", "/from", 1);
+        let err = verify(edited.as_bytes(), None).unwrap_err();
+        assert_eq!(err.line, 2);
+
+        let mut lines: Vec<_> = text.lines().collect();
+        lines.remove(1);
+        let err = verify(lines.join("\n").as_bytes(), None).unwrap_err();
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn drops_a_torn_last_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 1024 * 1024);
+        let log = AuditLog::open(&config).unwrap();
+        for path in ["/echo", "/form"] {
+            log.append(record(path)).unwrap();
+        }
+        drop(log);
+
+        let mut file = OpenOptions::new().append(true).open(&config.path).unwrap();
+        file.write_all(br#"{"seq":3,"ts":"#).unwrap();
+        drop(file);
+
+        let log = AuditLog::open(&config).unwrap();
+        assert_eq!(log.append(record("/echo")).unwrap().seq, 3);
+        assert_eq!(verify_file(&config.path).unwrap().records, 3);
+
+        // a torn record alone leaves an empty log
+        std::fs::write(&config.path, br#"{"seq":1"#).unwrap();
+        let log = AuditLog::open(&config).unwrap();
+        assert_eq!(log.append(record("/echo")).unwrap().seq, 1);
+    }
+
+    #[test]
+    fn rotates_and_keeps_the_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 600);
+        let log = AuditLog::open(&config).unwrap();
+        for _ in 0..12 {
+            log.append(record("/echo")).unwrap();
+        }
+
+        let rotated = rotated(&config.path).unwrap();
+        assert_eq!(rotated.len(), 2);
+
+        let mut prev = None;
+        for (_, file) in &rotated {
+            prev = verify(io::BufReader::new(File::open(file).unwrap()), prev)
+                .unwrap()
+                .last;
+        }
+        let verified = verify(io::BufReader::new(File::open(&config.path).unwrap()), prev);
+        assert_eq!(verified.unwrap().last.unwrap().0, 12);
+    }
+
+    #[test]
+    fn redacts_configured_fields() {
+        let keys = vec![String::from("password")];
+        let json: Mime = "application/json".parse().unwrap();
+        let form: Mime = "application/x-www-form-urlencoded".parse().unwrap();
+
+        let body = Bytes::from(r#"{"user":{"name":"ann","Password":"hunter2"}}"#);
+        assert_eq!(
+            redact(&body, Some(&json), &keys).unwrap(),
+            r#"{"user":{"Password":"[redacted]","name":"ann"}}"#
+        );
+        let body = Bytes::from("name=ann&password=hunter2");
+        assert_eq!(
+            redact(&body, Some(&form), &keys).unwrap(),
+            "name=ann&password=%5Bredacted%5D"
+        );
+        let text: Mime = "text/plain; charset=utf-8".parse().unwrap();
+        assert_eq!(
+            redact(&Bytes::from("hi"), Some(&text), &keys).unwrap(),
+            "hi"
+        );
+        assert_eq!(
+            redact(&Bytes::from_static(&[0xff]), Some(&text), &keys),
+            None
+        );
+    }
+
+    #[test]
+    fn leaves_out_bodies_it_cannot_redact() {
+        let keys = vec![String::from("password")];
+        let json: Mime = "application/json".parse().unwrap();
+        let other: Mime = "application/octet-stream".parse().unwrap();
+
+        let body = Bytes::from(r#"{"password":"hunter2",}"#);
+        assert_eq!(redact(&body, Some(&json), &keys), None);
+        let body = Bytes::from("password=hunter2");
+        assert_eq!(redact(&body, Some(&other), &keys), None);
+        assert_eq!(redact(&body, None, &keys), None);
+    }
+}
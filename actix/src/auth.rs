@@ -0,0 +1,311 @@
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    Error, HttpMessage, HttpResponse, ResponseError,
+};
+use futures_util::future::LocalBoxFuture;
+use jsonwebtoken::{decode, errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AuthConfig;
+use crate::error::ApiError;
+
+// Claims carried by the bearer token. Handlers behind `JwtAuth` can get them
+// with `web::ReqData<Claims>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub aud: Audience,
+    pub exp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<u64>,
+}
+
+// The `aud` claim, a single audience or a list of them (RFC 7519 section
+// 4.1.3).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<&str> for Audience {
+    fn from(audience: &str) -> Self {
+        Audience::One(audience.to_string())
+    }
+}
+
+// Authentication middleware factory. Validates HS256 JWTs signed with a
+// locally configured secret and meant for `audience`.
+#[derive(Clone)]
+pub struct JwtAuth {
+    key: Arc<DecodingKey>,
+    validation: Arc<Validation>,
+}
+
+impl JwtAuth {
+    pub fn new(secret: &[u8], audience: &str) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_audience(&[audience]);
+        validation.set_required_spec_claims(&["exp", "aud", "sub"]);
+        validation.validate_nbf = true;
+
+        JwtAuth {
+            key: Arc::new(DecodingKey::from_secret(secret)),
+            validation: Arc::new(validation),
+        }
+    }
+
+    // Fails without a secret; validation makes sure there is one.
+    pub fn from_config(config: &AuthConfig) -> std::io::Result<Self> {
+        let secret = config.secret.as_ref().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "auth.secret is not set")
+        })?;
+
+        Ok(JwtAuth::new(secret.as_bytes(), &config.audience))
+    }
+
+    // The subject of a valid bearer token, for routes that don't require one.
+    pub fn subject(&self, req: &ServiceRequest) -> Option<String> {
+        self.authenticate(req).ok().map(|claims| claims.sub)
+    }
+
+    fn authenticate(&self, req: &ServiceRequest) -> Result<Claims, AuthError> {
+        let value = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .ok_or(AuthError::MissingToken)?;
+
+        // the scheme is case-insensitive, RFC 9110 section 11.1
+        let token = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim_start().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim())
+            .filter(|t| !t.is_empty())
+            .ok_or(AuthError::MissingToken)?;
+
+        decode::<Claims>(token, &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|err| match err.kind() {
+                ErrorKind::InvalidAudience => {
+                    AuthError::InvalidToken("token is not valid for this audience")
+                }
+                ErrorKind::ExpiredSignature => AuthError::InvalidToken("token has expired"),
+                ErrorKind::ImmatureSignature => AuthError::InvalidToken("token is not valid yet"),
+                _ => AuthError::InvalidToken("token is malformed or has a bad signature"),
+            })
+    }
+}
+
+#[derive(Debug)]
+enum AuthError {
+    MissingToken,
+    InvalidToken(&'static str),
+}
+
+impl AuthError {
+    fn response(&self) -> HttpResponse {
+        // see RFC 6750 section 3 for the `WWW-Authenticate` format
+        let challenge = |error: &str, message: &str| {
+            Some(format!(
+                r#"Bearer realm="users", error="{error}", error_description="{message}""#
+            ))
+        };
+
+        let err = match self {
+            AuthError::MissingToken => ApiError::Unauthorized {
+                detail: String::from("missing bearer token"),
+                challenge: Some(String::from(r#"Bearer realm="users""#)),
+            },
+            AuthError::InvalidToken(message) => ApiError::Unauthorized {
+                detail: message.to_string(),
+                challenge: challenge("invalid_token", message),
+            },
+        };
+
+        err.error_response()
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for JwtAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = JwtAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(JwtAuthMiddleware {
+            service,
+            auth: self.clone(),
+        }))
+    }
+}
+
+pub struct JwtAuthMiddleware<S> {
+    service: S,
+    auth: JwtAuth,
+}
+
+impl<S, B> Service<ServiceRequest> for JwtAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        match self.auth.authenticate(&req) {
+            Ok(claims) => {
+                req.extensions_mut().insert(claims);
+
+                let fut = self.service.call(req);
+                Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+            }
+            Err(err) => {
+                let res = req.into_response(err.response()).map_into_right_body();
+                Box::pin(async move { Ok(res) })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECRET: &[u8] = b"test-secret";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn token(aud: impl Into<Audience>, exp: u64, nbf: Option<u64>) -> String {
+        let claims = Claims {
+            sub: String::from("alice"),
+            aud: aud.into(),
+            exp,
+            nbf,
+        };
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    async fn whoami(claims: web::ReqData<Claims>) -> String {
+        claims.sub.clone()
+    }
+
+    async fn status_for(auth: Option<String>) -> (StatusCode, bool) {
+        let app = test::init_service(
+            App::new().service(
+                web::scope("/users")
+                    .wrap(JwtAuth::new(SECRET, "actix"))
+                    .route("/me", web::get().to(whoami)),
+            ),
+        )
+        .await;
+
+        let mut req = test::TestRequest::get().uri("/users/me");
+        if let Some(auth) = auth {
+            req = req.insert_header((header::AUTHORIZATION, auth));
+        }
+        let res = test::call_service(&app, req.to_request()).await;
+        let challenged = res.headers().contains_key(header::WWW_AUTHENTICATE);
+
+        (res.status(), challenged)
+    }
+
+    #[actix_web::test]
+    async fn valid_token_reaches_handler() {
+        let bearer = format!("Bearer {}", token("actix", now() + 60, Some(now())));
+        assert_eq!(status_for(Some(bearer)).await, (StatusCode::OK, false));
+    }
+
+    #[actix_web::test]
+    async fn scheme_is_case_insensitive() {
+        let bearer = format!("bearer {}", token("actix", now() + 60, None));
+        assert_eq!(status_for(Some(bearer)).await, (StatusCode::OK, false));
+
+        let basic = format!("Basic {}", token("actix", now() + 60, None));
+        assert_eq!(
+            status_for(Some(basic)).await,
+            (StatusCode::UNAUTHORIZED, true)
+        );
+    }
+
+    #[actix_web::test]
+    async fn missing_or_garbage_token_is_unauthorized() {
+        assert_eq!(status_for(None).await, (StatusCode::UNAUTHORIZED, true));
+
+        let garbage = String::from("Bearer not-a-jwt");
+        assert_eq!(
+            status_for(Some(garbage)).await,
+            (StatusCode::UNAUTHORIZED, true)
+        );
+    }
+
+    #[actix_web::test]
+    async fn expired_and_immature_tokens_are_unauthorized() {
+        let expired = format!("Bearer {}", token("actix", now() - 120, None));
+        assert_eq!(
+            status_for(Some(expired)).await,
+            (StatusCode::UNAUTHORIZED, true)
+        );
+
+        let immature = format!("Bearer {}", token("actix", now() + 600, Some(now() + 300)));
+        assert_eq!(
+            status_for(Some(immature)).await,
+            (StatusCode::UNAUTHORIZED, true)
+        );
+    }
+
+    #[actix_web::test]
+    async fn wrong_audience_is_an_invalid_token() {
+        let bearer = format!("Bearer {}", token("other", now() + 60, None));
+        assert_eq!(
+            status_for(Some(bearer)).await,
+            (StatusCode::UNAUTHORIZED, true)
+        );
+    }
+
+    #[actix_web::test]
+    async fn audience_may_be_a_list() {
+        let audiences = Audience::Many(vec![String::from("other"), String::from("actix")]);
+        let bearer = format!("Bearer {}", token(audiences, now() + 60, None));
+        assert_eq!(status_for(Some(bearer)).await, (StatusCode::OK, false));
+
+        let audiences = Audience::Many(vec![String::from("other")]);
+        let bearer = format!("Bearer {}", token(audiences, now() + 60, None));
+        assert_eq!(
+            status_for(Some(bearer)).await,
+            (StatusCode::UNAUTHORIZED, true)
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use actix::audit;
+use clap::Parser;
+
+// Checks the hash chain of audit logs written by the server, rotated files
+// included. Exits with 1 when a log was tampered with and 2 when it could not
+// be read.
+#[derive(Parser)]
+#[command(about = "Verify actix audit logs")]
+struct Args {
+    /// Audit log files, each checked after its rotated `<file>.<seq>` files
+    #[arg(required = true)]
+    logs: Vec<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let mut ok = true;
+
+    for log in &args.logs {
+        let mut files = match audit::rotated(log) {
+            Ok(rotated) => rotated
+                .into_iter()
+                .map(|(_, path)| path)
+                .collect::<Vec<_>>(),
+            Err(err) => {
+                eprintln!("{}: {err}", log.display());
+                return ExitCode::from(2);
+            }
+        };
+        files.push(log.clone());
+
+        // the oldest kept file may start mid-chain, the rest must follow on
+        let mut prev = None;
+        for path in &files {
+            let file = match File::open(path) {
+                Ok(file) => file,
+                Err(err) => {
+                    eprintln!("{}: {err}", path.display());
+                    return ExitCode::from(2);
+                }
+            };
+
+            match audit::verify(BufReader::new(file), prev.take()) {
+                Ok(verified) => {
+                    println!("{}: {} records, ok", path.display(), verified.records);
+                    prev = verified.last;
+                }
+                Err(err) => {
+                    println!("{}: {err}", path.display());
+                    ok = false;
+                    break;
+                }
+            }
+        }
+    }
+
+    if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(1)
+    }
+}
@@ -0,0 +1,40 @@
+use std::io::{self, BufRead};
+use std::process::ExitCode;
+
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::Argon2;
+use clap::Parser;
+
+// Prints the argon2 hash of a password read from stdin, for the
+// `password_hash` of `[[sessions.accounts]]`. The password is not taken as an
+// argument so it stays out of the shell history.
+#[derive(Parser)]
+#[command(about = "Hash a password for actix session accounts")]
+struct Args {}
+
+fn main() -> ExitCode {
+    Args::parse();
+
+    let mut password = String::new();
+    if let Err(err) = io::stdin().lock().read_line(&mut password) {
+        eprintln!("could not read the password: {err}");
+        return ExitCode::from(2);
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eprintln!("the password must not be empty");
+        return ExitCode::FAILURE;
+    }
+
+    let salt = SaltString::encode_b64(uuid::Uuid::new_v4().as_bytes()).unwrap();
+    match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => {
+            println!("{hash}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("could not hash the password: {err}");
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,646 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::{ready, Ready};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    body::{self, BodySize, BodyStream, BoxBody, EitherBody, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        Method, StatusCode,
+    },
+    web::{Bytes, BytesMut},
+    Error, HttpRequest, HttpResponse,
+};
+use futures_util::{future::LocalBoxFuture, stream, StreamExt};
+use sha2::{Digest, Sha256};
+
+use crate::config::CacheConfig;
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::tenants;
+
+// Request host and URI, and the values of the request headers the response varies on.
+type Key = (String, Vec<Option<HeaderValue>>);
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    expires: Instant,
+    // position in `Lru::order`
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Key, Entry>,
+    // least recently used first
+    order: BTreeMap<u64, Key>,
+    // header names each URI varies on, from every response stored for it
+    // (identity responses carry no `Vary: accept-encoding`), and how many
+    // entries the URI has; dropped with its last entry
+    vary: HashMap<String, (Vec<HeaderName>, usize)>,
+    bytes: usize,
+    tick: u64,
+}
+
+impl Lru {
+    fn key(&self, uri: &str, headers: &HeaderMap) -> Key {
+        let names = self.vary.get(uri).map_or(&[][..], |(names, _)| names);
+        variant(uri, names, headers)
+    }
+
+    fn get(&mut self, uri: &str, headers: &HeaderMap, now: Instant) -> Option<&Entry> {
+        let key = self.key(uri, headers);
+        let entry = self.entries.get(&key)?;
+        if entry.expires <= now {
+            self.remove(&key);
+            return None;
+        }
+
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(&key)?;
+        self.order.remove(&entry.tick);
+        self.order.insert(tick, key);
+        entry.tick = tick;
+
+        Some(entry)
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+            self.bytes -= entry.body.len();
+
+            if let Some((_, count)) = self.vary.get_mut(&key.0) {
+                *count -= 1;
+                if *count == 0 {
+                    self.vary.remove(&key.0);
+                }
+            }
+        }
+    }
+
+    // Stores `entry`, evicting the least recently used entries to stay
+    // within the limits. Returns how many were evicted.
+    fn insert(
+        &mut self,
+        uri: &str,
+        vary: Vec<HeaderName>,
+        headers: &HeaderMap,
+        mut entry: Entry,
+        config: &CacheConfig,
+    ) -> usize {
+        if entry.body.len() > config.max_bytes {
+            return 0;
+        }
+        let mut names = self.vary.get(uri).map_or_else(Vec::new, |(n, _)| n.clone());
+        for name in vary {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        let key = variant(uri, &names, headers);
+        self.remove(&key);
+
+        let mut evicted = 0;
+        while self.entries.len() >= config.max_entries
+            || self.bytes + entry.body.len() > config.max_bytes
+        {
+            let Some(oldest) = self.order.values().next().cloned() else {
+                break;
+            };
+            self.remove(&oldest);
+            evicted += 1;
+        }
+
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.bytes += entry.body.len();
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, entry);
+        let slot = self.vary.entry(uri.to_string()).or_default();
+        slot.0 = names;
+        slot.1 += 1;
+
+        evicted
+    }
+}
+
+fn variant(uri: &str, names: &[HeaderName], headers: &HeaderMap) -> Key {
+    let values = names.iter().map(|n| headers.get(n).cloned()).collect();
+    (uri.to_string(), values)
+}
+
+fn directives(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+        .collect()
+}
+
+// How long a response may be kept in the shared cache, from the handler's
+// `Cache-Control`. Responses without one are not kept, so nothing is served
+// stale unless its handler said it can be.
+fn shared_ttl(headers: &HeaderMap, cap: Duration) -> Option<Duration> {
+    let mut public = false;
+    let mut max_age = None;
+    let mut s_maxage = None;
+
+    for directive in directives(headers) {
+        let (name, value) = directive
+            .split_once('=')
+            .map_or((directive.as_str(), None), |(n, v)| (n, Some(v)));
+        let secs = || value.and_then(|v| v.trim_matches('"').parse::<u64>().ok());
+
+        match name {
+            "no-store" | "no-cache" | "private" => return None,
+            "public" => public = true,
+            "max-age" => max_age = secs(),
+            "s-maxage" => s_maxage = secs(),
+            _ => {}
+        }
+    }
+
+    let ttl = match s_maxage.or(max_age) {
+        Some(secs) => Duration::from_secs(secs).min(cap),
+        None if public => cap,
+        None => return None,
+    };
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+// Strong, since the body is hashed as sent: `Compress` runs inside this
+// middleware, so every content coding gets its own tag.
+fn etag(body: &[u8]) -> HeaderValue {
+    let digest = Sha256::digest(body);
+    let tag = format!("\"{}\"", hex::encode(&digest[..16]));
+    HeaderValue::from_str(&tag).unwrap()
+}
+
+// Reads a streamed body, e.g. a compressed one, whole if it is at most
+// `limit` bytes. A longer one is handed back as the part read so far followed
+// by the rest.
+async fn buffer<B>(body: B, limit: usize) -> Result<Result<Bytes, BoxBody>, Error>
+where
+    B: MessageBody + 'static,
+{
+    let mut body = Box::pin(body);
+    let mut read = BytesMut::new();
+    while let Some(chunk) = std::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+        let chunk = chunk.map_err(|err| ApiError::Internal(err.into().to_string()))?;
+        read.extend_from_slice(&chunk);
+
+        if read.len() > limit {
+            let rest = stream::poll_fn(move |cx| body.as_mut().poll_next(cx))
+                .map(|chunk| chunk.map_err(Into::into));
+            let all = stream::once(async move { Ok(read.freeze()) }).chain(rest);
+            return Ok(Err(BoxBody::new(BodyStream::new(all))));
+        }
+    }
+
+    Ok(Ok(read.freeze()))
+}
+
+// `If-None-Match` uses the weak comparison, `W/` prefixes are ignored.
+fn not_modified(req: &HttpRequest, headers: &HeaderMap) -> bool {
+    let Some(etag) = headers.get(header::ETAG).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let etag = etag.trim_start_matches("W/");
+
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// Answers with a 304 when the client already has this representation.
+fn respond(req: &HttpRequest, mut res: HttpResponse<Bytes>, metrics: &Metrics) -> HttpResponse {
+    if !not_modified(req, res.headers()) {
+        return res.map_into_boxed_body();
+    }
+    metrics.cache_event("not_modified");
+
+    let mut not_modified = HttpResponse::NotModified();
+    for name in [
+        header::ETAG,
+        header::CACHE_CONTROL,
+        header::VARY,
+        header::EXPIRES,
+        header::CONTENT_LOCATION,
+        header::AGE,
+    ] {
+        if let Some(value) = res.headers_mut().remove(&name).next() {
+            not_modified.insert_header((name, value));
+        }
+    }
+    not_modified.finish()
+}
+
+// Response caching: strong ETags on buffered `200`s, `304 Not Modified` for
+// matching `If-None-Match`, and an in-memory LRU of the GET responses that
+// handlers allow shared caches to keep. Requests with credentials never use
+// the LRU. Like `Metrics`, one instance is shared by every worker.
+#[derive(Clone)]
+pub struct Cache {
+    lru: Arc<Mutex<Lru>>,
+    config: CacheConfig,
+    metrics: Metrics,
+}
+
+impl Cache {
+    pub fn new(config: &CacheConfig, metrics: Metrics) -> Self {
+        Cache {
+            lru: Arc::new(Mutex::new(Lru::default())),
+            config: config.clone(),
+            metrics,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Cache
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CacheMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CacheMiddleware {
+            service,
+            cache: self.clone(),
+        }))
+    }
+}
+
+pub struct CacheMiddleware<S> {
+    service: S,
+    cache: Cache,
+}
+
+impl<S, B> Service<ServiceRequest> for CacheMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let method = req.method().clone();
+        if method != Method::GET && method != Method::HEAD {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        }
+
+        let asked = directives(req.headers());
+        let shared = self.cache.config.lru
+            && method == Method::GET
+            && !req.headers().contains_key(header::AUTHORIZATION)
+            && !asked.iter().any(|d| d == "no-store");
+        // with the host, tenants answer the same path differently
+        let uri = format!(
+            "{}{}",
+            tenants::host(req.head()).unwrap_or_default(),
+            req.uri()
+                .path_and_query()
+                .map_or_else(|| req.path(), |p| p.as_str())
+        );
+
+        if shared && !asked.iter().any(|d| d == "no-cache") {
+            let now = Instant::now();
+            let hit = self
+                .cache
+                .lru
+                .lock()
+                .unwrap()
+                .get(&uri, req.headers(), now)
+                .map(|entry| {
+                    let mut res = HttpResponse::with_body(entry.status, entry.body.clone());
+                    *res.headers_mut() = entry.headers.clone();
+                    let age = now.duration_since(entry.stored).as_secs();
+                    res.headers_mut()
+                        .insert(header::AGE, HeaderValue::from(age));
+                    res
+                });
+
+            if let Some(res) = hit {
+                self.cache.metrics.cache_event("hit");
+                let res = respond(req.request(), res, &self.cache.metrics);
+                return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+            }
+            self.cache.metrics.cache_event("miss");
+        }
+
+        let cache = self.cache.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+
+            // only reasonably small responses are buffered; of streams, only the
+            // ones compression made out of a response, never an event stream
+            let limit = cache.config.max_body;
+            let size = res.response().body().size();
+            let headers = res.headers();
+            let small = match size {
+                BodySize::Sized(len) => len <= limit as u64,
+                BodySize::Stream => {
+                    headers.contains_key(header::CONTENT_ENCODING)
+                        && headers
+                            .get(header::CONTENT_TYPE)
+                            .is_none_or(|ct| !ct.as_bytes().starts_with(b"text/event-stream"))
+                }
+                BodySize::None => false,
+            };
+            if res.status() != StatusCode::OK || !small {
+                return Ok(res.map_into_left_body());
+            }
+
+            let (req, res) = res.into_parts();
+            let (head, body) = res.into_parts();
+            let body = match size {
+                BodySize::Stream => match buffer(body, limit).await? {
+                    Ok(body) => body,
+                    Err(rest) => {
+                        let res = head.set_body(rest);
+                        return Ok(ServiceResponse::new(req, res).map_into_right_body());
+                    }
+                },
+                _ => body::to_bytes(body)
+                    .await
+                    .map_err(|err| ApiError::Internal(err.into().to_string()))?,
+            };
+            let mut res = head.set_body(body);
+
+            if !res.headers().contains_key(header::ETAG) {
+                let etag = etag(res.body());
+                res.headers_mut().insert(header::ETAG, etag);
+            }
+
+            let cap = Duration::from_secs(cache.config.ttl);
+            let vary: Option<Vec<HeaderName>> = res
+                .headers()
+                .get_all(header::VARY)
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .map(|name| HeaderName::try_from(name.trim()).ok())
+                .collect();
+            match (shared_ttl(res.headers(), cap), vary) {
+                (Some(ttl), Some(vary))
+                    if shared && !res.headers().contains_key(header::SET_COOKIE) =>
+                {
+                    let now = Instant::now();
+                    let entry = Entry {
+                        status: res.status(),
+                        headers: res.headers().clone(),
+                        body: res.body().clone(),
+                        stored: now,
+                        expires: now + ttl,
+                        tick: 0,
+                    };
+                    let evicted = cache.lru.lock().unwrap().insert(
+                        &uri,
+                        vary,
+                        req.headers(),
+                        entry,
+                        &cache.config,
+                    );
+
+                    cache.metrics.cache_event("store");
+                    for _ in 0..evicted {
+                        cache.metrics.cache_event("evict");
+                    }
+                }
+                // `Vary: *` is not a header name, such responses are not kept
+                _ => {}
+            }
+
+            let res = respond(&req, res, &cache.metrics);
+            Ok(ServiceResponse::new(req, res).map_into_right_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{CacheControl, CacheDirective};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn config() -> CacheConfig {
+        CacheConfig {
+            max_entries: 2,
+            ..CacheConfig::default()
+        }
+    }
+
+    #[test]
+    fn ttl_follows_cache_control() {
+        let cap = Duration::from_secs(300);
+        let ttl = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_str(value).unwrap());
+            shared_ttl(&headers, cap)
+        };
+
+        assert_eq!(ttl("public, max-age=60"), Some(Duration::from_secs(60)));
+        assert_eq!(
+            ttl("max-age=60, s-maxage=10"),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(ttl("max-age=9999"), Some(cap));
+        assert_eq!(ttl("public"), Some(cap));
+        assert_eq!(ttl("private, max-age=60"), None);
+        assert_eq!(ttl("no-store"), None);
+        assert_eq!(ttl("max-age=0"), None);
+        assert_eq!(shared_ttl(&HeaderMap::new(), cap), None);
+    }
+
+    #[actix_web::test]
+    async fn answers_304_and_serves_from_the_lru() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let metrics = Metrics::new();
+        let app = init_service(
+            App::new()
+                .wrap(Cache::new(&config(), metrics.clone()))
+                .route(
+                    "/cached/{n}",
+                    web::get().to({
+                        let calls = calls.clone();
+                        move |n: web::Path<u32>| {
+                            calls.fetch_add(1, Ordering::Relaxed);
+                            async move {
+                                HttpResponse::Ok()
+                                    .insert_header(CacheControl(vec![
+                                        CacheDirective::Public,
+                                        CacheDirective::MaxAge(60),
+                                    ]))
+                                    .body(format!("item {n}"))
+                            }
+                        }
+                    }),
+                )
+                .route("/fresh", web::get().to(|| async { "fresh" })),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/cached/1").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        assert!(etag.to_str().unwrap().starts_with('"'));
+        assert_eq!(read_body(res).await, "item 1");
+
+        // served from the LRU, and not sent again when the client has it
+        let res = call_service(&app, TestRequest::get().uri("/cached/1").to_request()).await;
+        assert!(res.headers().contains_key(header::AGE));
+        assert_eq!(read_body(res).await, "item 1");
+        let req = TestRequest::get()
+            .uri("/cached/1")
+            .insert_header((header::IF_NONE_MATCH, etag.clone()));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers().get(header::ETAG), Some(&etag));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // filling the LRU evicts the least recently used entry
+        for n in [2, 3] {
+            let uri = format!("/cached/{n}");
+            call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        }
+        call_service(&app, TestRequest::get().uri("/cached/1").to_request()).await;
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+        // credentials bypass the LRU
+        let req = TestRequest::get()
+            .uri("/cached/1")
+            .insert_header((header::AUTHORIZATION, "Bearer x"));
+        call_service(&app, req.to_request()).await;
+        assert_eq!(calls.load(Ordering::Relaxed), 5);
+
+        // responses without `Cache-Control` still get an ETag
+        let res = call_service(&app, TestRequest::get().uri("/fresh").to_request()).await;
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        let req = TestRequest::get()
+            .uri("/fresh")
+            .insert_header((header::IF_NONE_MATCH, etag));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(r#"http_cache_events_total{event="hit"} 2"#));
+        assert!(rendered.contains(r#"http_cache_events_total{event="evict"} 2"#));
+    }
+
+    #[actix_web::test]
+    async fn keeps_one_entry_per_variant() {
+        let app = init_service(
+            App::new()
+                .wrap(Cache::new(&config(), Metrics::new()))
+                .route(
+                    "/",
+                    web::get().to(|req: HttpRequest| async move {
+                        let accept = req.headers().get(header::ACCEPT).cloned();
+                        HttpResponse::Ok()
+                            .insert_header((header::CACHE_CONTROL, "max-age=60"))
+                            .insert_header((header::VARY, "accept"))
+                            .body(format!("{accept:?}"))
+                    }),
+                ),
+        )
+        .await;
+
+        for _ in 0..2 {
+            for accept in ["application/json", "application/cbor"] {
+                let req = TestRequest::get()
+                    .uri("/")
+                    .insert_header((header::ACCEPT, accept));
+                let body = read_body(call_service(&app, req.to_request()).await).await;
+                assert!(std::str::from_utf8(&body).unwrap().contains(accept));
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn compressed_variants_get_their_own_tags() {
+        let body = "compress me ".repeat(100);
+        let app = init_service(
+            App::new()
+                .wrap(actix_web::middleware::Compress::default())
+                .wrap(Cache::new(&config(), Metrics::new()))
+                .route(
+                    "/",
+                    web::get().to(move || {
+                        let body = body.clone();
+                        async move {
+                            HttpResponse::Ok()
+                                .insert_header((header::CACHE_CONTROL, "max-age=60"))
+                                .body(body)
+                        }
+                    }),
+                ),
+        )
+        .await;
+
+        let mut tags = Vec::new();
+        for encoding in ["gzip", "identity", "gzip"] {
+            let req = TestRequest::get()
+                .uri("/")
+                .insert_header((header::ACCEPT_ENCODING, encoding));
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let coding = res.headers().get(header::CONTENT_ENCODING).cloned();
+            assert_eq!(coding.is_some(), encoding == "gzip");
+            tags.push(res.headers().get(header::ETAG).unwrap().clone());
+        }
+        assert_ne!(tags[0], tags[1]);
+        assert_eq!(tags[0], tags[2]);
+    }
+
+    #[test]
+    fn forgets_vary_with_the_last_entry() {
+        let mut lru = Lru::default();
+        let now = Instant::now();
+        let entry = || Entry {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(b"x"),
+            stored: now,
+            expires: now + Duration::from_secs(60),
+            tick: 0,
+        };
+
+        for n in 0..10 {
+            let uri = format!("/json/response/{n}");
+            lru.insert(
+                &uri,
+                vec![header::ACCEPT],
+                &HeaderMap::new(),
+                entry(),
+                &config(),
+            );
+        }
+        assert_eq!(lru.entries.len(), 2);
+        assert_eq!(lru.vary.len(), 2);
+    }
+}
@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use actix_ws::{AggregatedMessage, AggregatedMessageStream, CloseCode, CloseReason, ProtocolError};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::auth::Claims;
+use crate::config::WsConfig;
+use crate::error::{ApiError, Problem};
+
+// events queued for a client that reads slower than its rooms talk; once
+// full, further events are dropped for that client
+const QUEUE_SIZE: usize = 64;
+
+const MAX_ROOM_NAME: usize = 64;
+
+// Timings and limits of a connection, see `config::WsConfig`.
+#[derive(Debug, Clone)]
+pub struct ChatSettings {
+    pub heartbeat: Duration,
+    pub client_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub max_message: usize,
+}
+
+impl From<&WsConfig> for ChatSettings {
+    fn from(config: &WsConfig) -> Self {
+        ChatSettings {
+            heartbeat: Duration::from_secs(config.heartbeat),
+            client_timeout: Duration::from_secs(config.client_timeout),
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            max_message: config.max_message,
+        }
+    }
+}
+
+// Text frames sent by clients.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Command {
+    Join { room: String },
+    Leave { room: String },
+    Send { room: String, text: String },
+}
+
+// Text frames sent to clients.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event<'a> {
+    Joined {
+        room: &'a str,
+        members: usize,
+    },
+    Left {
+        room: &'a str,
+    },
+    Presence {
+        room: &'a str,
+        user: &'a str,
+        joined: bool,
+    },
+    Message {
+        room: &'a str,
+        from: &'a str,
+        text: &'a str,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl Event<'_> {
+    fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+struct Member {
+    user: String,
+    events: mpsc::Sender<String>,
+}
+
+#[derive(Default)]
+struct Rooms {
+    next_id: u64,
+    rooms: HashMap<String, HashMap<u64, Member>>,
+}
+
+impl Rooms {
+    // Sends `event` to everyone in `room` except `skip`.
+    fn broadcast(&self, room: &str, skip: u64, event: &Event) {
+        let Some(members) = self.rooms.get(room) else {
+            return;
+        };
+
+        let text = event.encode();
+        for (id, member) in members {
+            if *id != skip && member.events.try_send(text.clone()).is_err() {
+                log::debug!("dropping chat event for slow client {id}");
+            }
+        }
+    }
+
+    fn leave(&mut self, room: &str, id: u64) -> bool {
+        let Some(members) = self.rooms.get_mut(room) else {
+            return false;
+        };
+        let Some(member) = members.remove(&id) else {
+            return false;
+        };
+
+        if members.is_empty() {
+            self.rooms.remove(room);
+        } else {
+            let event = Event::Presence {
+                room,
+                user: &member.user,
+                joined: false,
+            };
+            self.broadcast(room, id, &event);
+        }
+        true
+    }
+}
+
+// Chat rooms shared by the connections of every worker. Create it once
+// outside `HttpServer::new`.
+#[derive(Clone)]
+pub struct ChatHub {
+    rooms: Arc<Mutex<Rooms>>,
+    settings: ChatSettings,
+}
+
+impl ChatHub {
+    pub fn new(settings: ChatSettings) -> Self {
+        ChatHub {
+            rooms: Arc::new(Mutex::new(Rooms::default())),
+            settings,
+        }
+    }
+
+    fn register(&self) -> u64 {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.next_id += 1;
+        rooms.next_id
+    }
+
+    // Runs one command for connection `id` and returns the reply for it.
+    fn handle(&self, id: u64, user: &str, events: &mpsc::Sender<String>, text: &str) -> String {
+        let command = match serde_json::from_str::<Command>(text) {
+            Ok(command) => command,
+            Err(err) => return error(format!("invalid command: {err}")),
+        };
+        let mut rooms = self.rooms.lock().unwrap();
+
+        match command {
+            Command::Join { room } => {
+                if room.is_empty()
+                    || room.len() > MAX_ROOM_NAME
+                    || room.chars().any(char::is_control)
+                {
+                    return error(format!(
+                        "room names must have 1 to {MAX_ROOM_NAME} printable characters"
+                    ));
+                }
+
+                let members = rooms.rooms.entry(room.clone()).or_default();
+                let new = members
+                    .insert(
+                        id,
+                        Member {
+                            user: user.to_string(),
+                            events: events.clone(),
+                        },
+                    )
+                    .is_none();
+                let count = members.len();
+
+                if new {
+                    let event = Event::Presence {
+                        room: &room,
+                        user,
+                        joined: true,
+                    };
+                    rooms.broadcast(&room, id, &event);
+                }
+                Event::Joined {
+                    room: &room,
+                    members: count,
+                }
+                .encode()
+            }
+            Command::Leave { room } => match rooms.leave(&room, id) {
+                true => Event::Left { room: &room }.encode(),
+                false => error(format!("not in room {room:?}")),
+            },
+            Command::Send { room, text } => {
+                let joined = rooms.rooms.get(&room).is_some_and(|m| m.contains_key(&id));
+                if !joined {
+                    return error(format!("not in room {room:?}"));
+                }
+
+                let event = Event::Message {
+                    room: &room,
+                    from: user,
+                    text: &text,
+                };
+                rooms.broadcast(&room, id, &event);
+                // delivered to the others, nothing to tell the sender
+                String::new()
+            }
+        }
+    }
+
+    fn disconnect(&self, id: u64) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let joined: Vec<String> = rooms
+            .rooms
+            .iter()
+            .filter(|(_, members)| members.contains_key(&id))
+            .map(|(room, _)| room.clone())
+            .collect();
+
+        for room in joined {
+            rooms.leave(&room, id);
+        }
+    }
+}
+
+fn error(message: String) -> String {
+    Event::Error { message }.encode()
+}
+
+fn close(code: CloseCode, description: &str) -> Option<CloseReason> {
+    Some(CloseReason {
+        code,
+        description: Some(description.to_string()),
+    })
+}
+
+// Upgrades to a WebSocket chat connection. Clients send JSON commands
+// (`join`, `leave` and `send`, each with a `room`) and get the messages of
+// the rooms they joined. Sits behind `JwtAuth`, the token subject is the
+// name other members see.
+#[utoipa::path(
+    get,
+    path = "/ws",
+    security(("bearer" = [])),
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol"),
+        (status = 400, response = Problem),
+        (status = 401, response = Problem),
+    )
+)]
+pub async fn connect(
+    req: HttpRequest,
+    body: web::Payload,
+    hub: web::Data<ChatHub>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, ApiError> {
+    let (res, session, stream) =
+        actix_ws::handle(&req, body).map_err(|err| ApiError::BadRequest(err.to_string()))?;
+
+    let max_message = hub.settings.max_message;
+    let stream = stream
+        .max_frame_size(max_message)
+        .aggregate_continuations()
+        .max_continuation_size(max_message);
+
+    rt::spawn(run(
+        hub.get_ref().clone(),
+        claims.into_inner().sub,
+        session,
+        stream,
+    ));
+
+    Ok(res)
+}
+
+async fn run(
+    hub: ChatHub,
+    user: String,
+    mut session: actix_ws::Session,
+    mut stream: AggregatedMessageStream,
+) {
+    let id = hub.register();
+    let (events, mut queued) = mpsc::channel(QUEUE_SIZE);
+    let settings = hub.settings.clone();
+    let mut heartbeat = rt::time::interval(settings.heartbeat);
+    // any frame, pongs included
+    let mut last_seen = Instant::now();
+    let mut last_command = Instant::now();
+
+    let reason = loop {
+        tokio::select! {
+            msg = stream.next() => {
+                last_seen = Instant::now();
+
+                match msg {
+                    Some(Ok(AggregatedMessage::Text(text))) => {
+                        last_command = Instant::now();
+                        let reply = hub.handle(id, &user, &events, &text);
+                        if !reply.is_empty() && session.text(reply).await.is_err() {
+                            break None;
+                        }
+                    }
+                    Some(Ok(AggregatedMessage::Binary(_))) => {
+                        let reply = error(String::from("binary frames are not supported"));
+                        if session.text(reply).await.is_err() {
+                            break None;
+                        }
+                    }
+                    Some(Ok(AggregatedMessage::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            break None;
+                        }
+                    }
+                    Some(Ok(AggregatedMessage::Pong(_))) => {}
+                    Some(Ok(AggregatedMessage::Close(reason))) => break reason,
+                    Some(Err(ProtocolError::Overflow)) => {
+                        break close(CloseCode::Size, "message too large");
+                    }
+                    Some(Err(err)) => break close(CloseCode::Protocol, &err.to_string()),
+                    None => break None,
+                }
+            }
+            Some(event) = queued.recv() => {
+                if session.text(event).await.is_err() {
+                    break None;
+                }
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > settings.client_timeout {
+                    break close(CloseCode::Away, "heartbeat timeout");
+                }
+                if last_command.elapsed() > settings.idle_timeout {
+                    break close(CloseCode::Normal, "idle timeout");
+                }
+                if session.ping(b"").await.is_err() {
+                    break None;
+                }
+            }
+        }
+    };
+
+    hub.disconnect(id);
+    let _ = session.close(reason).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Audience, JwtAuth};
+    use actix_web::App;
+    use awc::ws::{Codec, Frame, Message};
+    use awc::BoxedSocket;
+    use futures::SinkExt;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    type Conn = actix_codec::Framed<BoxedSocket, Codec>;
+
+    const SECRET: &[u8] = b"chat-test-secret";
+
+    fn token(sub: &str) -> String {
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let claims = Claims {
+            sub: sub.to_string(),
+            aud: Audience::from("actix"),
+            exp,
+            nbf: None,
+        };
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    fn server(settings: ChatSettings) -> actix_test::TestServer {
+        let hub = web::Data::new(ChatHub::new(settings));
+        actix_test::start(move || {
+            App::new().app_data(hub.clone()).service(
+                web::resource("/ws")
+                    .wrap(JwtAuth::new(SECRET, "actix"))
+                    .route(web::get().to(connect)),
+            )
+        })
+    }
+
+    fn settings() -> ChatSettings {
+        ChatSettings {
+            heartbeat: Duration::from_secs(5),
+            client_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(30),
+            max_message: 1024,
+        }
+    }
+
+    async fn connect_as(srv: &actix_test::TestServer, user: &str) -> Conn {
+        let (_, conn) = awc::Client::new()
+            .ws(srv.url("/ws"))
+            .bearer_auth(token(user))
+            .connect()
+            .await
+            .unwrap();
+        conn
+    }
+
+    async fn send(conn: &mut Conn, command: Value) {
+        conn.send(Message::Text(command.to_string().into()))
+            .await
+            .unwrap();
+    }
+
+    // Next text or close frame, skipping heartbeats.
+    async fn next(conn: &mut Conn) -> Frame {
+        loop {
+            match conn.next().await.unwrap().unwrap() {
+                Frame::Ping(_) | Frame::Pong(_) => continue,
+                frame => return frame,
+            }
+        }
+    }
+
+    async fn next_event(conn: &mut Conn) -> Value {
+        match next(conn).await {
+            Frame::Text(text) => serde_json::from_slice(&text).unwrap(),
+            frame => panic!("expected a text frame, got {frame:?}"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn broadcasts_within_a_room() {
+        let srv = server(settings());
+        let mut alice = connect_as(&srv, "alice").await;
+        let mut bob = connect_as(&srv, "bob").await;
+        let mut carol = connect_as(&srv, "carol").await;
+
+        send(&mut alice, json!({"type": "join", "room": "general"})).await;
+        assert_eq!(next_event(&mut alice).await["members"], 1);
+        send(&mut bob, json!({"type": "join", "room": "general"})).await;
+        assert_eq!(next_event(&mut bob).await["members"], 2);
+        send(&mut carol, json!({"type": "join", "room": "other"})).await;
+        next_event(&mut carol).await;
+
+        assert_eq!(
+            next_event(&mut alice).await,
+            json!({"type": "presence", "room": "general", "user": "bob", "joined": true})
+        );
+
+        send(
+            &mut alice,
+            json!({"type": "send", "room": "general", "text": "hi"}),
+        )
+        .await;
+        assert_eq!(
+            next_event(&mut bob).await,
+            json!({"type": "message", "room": "general", "from": "alice", "text": "hi"})
+        );
+
+        // senders don't get their own messages back
+        send(
+            &mut bob,
+            json!({"type": "send", "room": "general", "text": "yo"}),
+        )
+        .await;
+        assert_eq!(next_event(&mut alice).await["text"], "yo");
+
+        // carol saw nothing from `general`, the first thing she gets is her error
+        send(
+            &mut carol,
+            json!({"type": "send", "room": "general", "text": "?"}),
+        )
+        .await;
+        assert_eq!(
+            next_event(&mut carol).await["message"],
+            "not in room \"general\""
+        );
+
+        bob.close().await.unwrap();
+        assert_eq!(
+            next_event(&mut alice).await,
+            json!({"type": "presence", "room": "general", "user": "bob", "joined": false})
+        );
+    }
+
+    #[actix_web::test]
+    async fn rejects_clients_without_token() {
+        let srv = server(settings());
+        let res = awc::Client::new().ws(srv.url("/ws")).connect().await;
+
+        assert!(matches!(
+            res,
+            Err(awc::error::WsClientError::InvalidResponseStatus(status)) if status == 401
+        ));
+    }
+
+    #[actix_web::test]
+    async fn closes_idle_and_silent_clients() {
+        let srv = server(ChatSettings {
+            heartbeat: Duration::from_millis(20),
+            idle_timeout: Duration::from_millis(100),
+            ..settings()
+        });
+        let mut conn = connect_as(&srv, "dave").await;
+        // the test client never answers pings, the long client timeout
+        // leaves the idle timeout to hit first
+        let Frame::Close(Some(reason)) = next(&mut conn).await else {
+            panic!("expected a close frame");
+        };
+        assert_eq!(reason.description.as_deref(), Some("idle timeout"));
+
+        let srv = server(ChatSettings {
+            heartbeat: Duration::from_millis(20),
+            client_timeout: Duration::from_millis(100),
+            ..settings()
+        });
+        let mut conn = connect_as(&srv, "erin").await;
+        let Frame::Close(Some(reason)) = next(&mut conn).await else {
+            panic!("expected a close frame");
+        };
+        assert_eq!(reason.description.as_deref(), Some("heartbeat timeout"));
+    }
+
+    #[actix_web::test]
+    async fn rejects_oversized_messages() {
+        let srv = server(settings());
+        let mut conn = connect_as(&srv, "frank").await;
+
+        send(&mut conn, json!({"type": "join", "room": "x".repeat(2000)})).await;
+        let Frame::Close(Some(reason)) = next(&mut conn).await else {
+            panic!("expected a close frame");
+        };
+        assert_eq!(reason.code, CloseCode::Size);
+    }
+}
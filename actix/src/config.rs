@@ -0,0 +1,1233 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::cors::Policy;
+use crate::rate_limit::Rule;
+use crate::sessions::Account;
+
+// Server configuration. Values are layered, each layer overriding the one
+// before it:
+// 1. built-in defaults
+// 2. the TOML file (`actix.toml`, or the path given by `--config`)
+// 3. `ACTIX_*` environment variables
+// 4. command-line flags
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub app_name: String,
+    pub bind: Vec<String>,
+    // defaults to the number of physical CPUs
+    pub workers: Option<usize>,
+    pub limits: Limits,
+    pub log: LogConfig,
+    pub middleware: MiddlewareConfig,
+    pub counters: CounterConfig,
+    pub people: PeopleConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub uploads: UploadConfig,
+    pub jobs: JobsConfig,
+    pub shutdown: ShutdownConfig,
+    pub ws: WsConfig,
+    pub events: EventsConfig,
+    pub tls: TlsConfig,
+    pub cache: CacheConfig,
+    pub idempotency: IdempotencyConfig,
+    pub cors: CorsConfig,
+    pub audit: AuditConfig,
+    pub sessions: SessionConfig,
+    // requests whose host matches no tenant
+    pub unknown_host: UnknownHost,
+    // domain the tenants' subdomains are under, e.g. `example.com`
+    pub base_domain: Option<String>,
+    pub tenants: Vec<TenantConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    // raw payloads, e.g. `/person/manual` and `/echo`
+    pub payload: usize,
+    pub json: usize,
+    pub form: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: String,
+    // formats for the two access loggers, see `actix_web::middleware::Logger`
+    pub format: String,
+    pub agent_format: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiddlewareConfig {
+    // free-form text access logs
+    pub logger: bool,
+    // one JSON line per request on the `access` log target
+    pub json_log: bool,
+    pub compress: bool,
+    // prints every request path to stdout
+    pub trace: bool,
+    // request counters and histograms served at `/metrics`
+    pub metrics: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    Memory,
+    File,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CounterConfig {
+    pub store: StoreKind,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub enabled: bool,
+    // the first policy whose path matches applies, other paths get no CORS
+    // headers
+    pub policies: Vec<Policy>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    // every request other than GET and HEAD is recorded
+    pub enabled: bool,
+    // JSON lines, each chained to the previous one by its hash
+    pub path: PathBuf,
+    // bytes; the file is rotated to `<path>.<last seq>` before growing past it
+    pub max_size: u64,
+    // rotated files kept, older ones are deleted
+    pub keep: usize,
+    // also record bodies of up to `max_body` bytes, with the `redact` JSON and
+    // form fields hidden; only JSON, form and text bodies are recorded
+    pub body: bool,
+    pub max_body: usize,
+    // matched case-insensitively
+    pub redact: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    // at least 32 bytes, cookies are signed with a key derived from it;
+    // without one a random key is used and sessions end on restart
+    pub secret: Option<String>,
+    // encrypt cookies rather than only sign them
+    pub encrypt: bool,
+    pub cookie_name: String,
+    // `Secure` cookies, defaults to whether HTTPS is served
+    pub secure: Option<bool>,
+    pub store: StoreKind,
+    // one file per session when store = "file"
+    pub dir: PathBuf,
+    // seconds without a request before a session ends
+    pub idle_timeout: u64,
+    // seconds a session lasts at most, however active
+    pub max_age: u64,
+    // seconds before a session gets a new id, 0 to keep it
+    pub rotate: u64,
+    // sessions kept at once; beyond it only logins start new ones
+    pub max_sessions: usize,
+    // users who can log in at `/login`
+    pub accounts: Vec<Account>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownHost {
+    // served with the top-level `app_name`, counters and every scope
+    Default,
+    // 404
+    Reject,
+}
+
+// Route groups a tenant can be given. `/`, `/hey`, `/app`, `/api`, `/echo`
+// and the person, form and session endpoints are always served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Counters,
+    Users,
+    Uploads,
+    Chat,
+    Events,
+    Jobs,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 6] = [
+        Scope::Counters,
+        Scope::Users,
+        Scope::Uploads,
+        Scope::Chat,
+        Scope::Events,
+        Scope::Jobs,
+    ];
+}
+
+// A virtual host with its own greeting and counters. Users, uploads, chat,
+// events and jobs are shared by every tenant that enables them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    pub name: String,
+    // exact host names, without the port
+    pub hosts: Vec<String>,
+    // matches `<subdomain>.<base_domain>`
+    pub subdomain: Option<String>,
+    // greeting of `/`, defaults to `name`
+    pub app_name: Option<String>,
+    pub scopes: Vec<Scope>,
+    pub counters: CounterConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeopleConfig {
+    // SQLite database of posted people, `:memory:` to keep it in memory
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // HS256 secret used to verify bearer tokens, at least 32 bytes; the
+    // server doesn't start without one
+    pub secret: Option<String>,
+    pub audience: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    // rules under `/users` are keyed by token subject, the rest by client IP
+    pub rules: Vec<Rule>,
+    // reverse proxies whose `X-Forwarded-For` names the client IP, other
+    // peers are limited and logged by their own address
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    pub dir: PathBuf,
+    // in bytes
+    pub max_file_size: u64,
+    pub max_total_size: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    // background threads running jobs
+    pub workers: usize,
+    // attempts of a failing job before it is marked failed
+    pub max_attempts: u32,
+    // milliseconds before the first retry, doubled for each further one
+    pub backoff: u64,
+    // pending and recently finished jobs, kept across restarts
+    pub path: PathBuf,
+    // finished jobs still reported by `GET /jobs/{id}`
+    pub keep: usize,
+    // queued and running jobs; `POST /jobs` answers 503 beyond it
+    pub max_queued: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    // seconds `/readyz` fails before the server stops accepting connections
+    pub drain: u64,
+    // seconds in-flight requests get to finish after that
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WsConfig {
+    // seconds between pings
+    pub heartbeat: u64,
+    // seconds without any frame, pongs included, before a client is dropped
+    pub client_timeout: u64,
+    // seconds without a chat command before a client is disconnected
+    pub idle_timeout: u64,
+    // largest accepted frame, in bytes
+    pub max_message: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    // events kept for clients resuming with `Last-Event-ID`
+    pub buffer: usize,
+    // seconds between keep-alive comments on idle streams
+    pub keep_alive: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    // PEM files, HTTPS is served when both are set
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    // HTTPS listeners
+    pub bind: Vec<String>,
+    // the plain `bind` listeners only redirect to HTTPS
+    pub redirect: bool,
+    // seconds between checks for a renewed certificate
+    pub reload: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    // strong ETags and `304 Not Modified` for GET and HEAD
+    pub enabled: bool,
+    // keep responses that handlers mark cacheable in memory
+    pub lru: bool,
+    pub max_entries: usize,
+    // bytes of response bodies kept, across all entries
+    pub max_bytes: usize,
+    // seconds, upper bound for the handler's `max-age`
+    pub ttl: u64,
+    // larger responses are streamed through without an ETag
+    pub max_body: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    // POSTs with an `Idempotency-Key` header are answered once, retries get
+    // the stored response
+    pub enabled: bool,
+    // seconds a response is kept for retries
+    pub window: u64,
+    // largest request and response body, in bytes; larger keyed requests are
+    // rejected with 413
+    pub max_body: usize,
+    // keys and stored response bytes kept at once; new keys get a 503 while
+    // either is used up
+    pub max_entries: usize,
+    pub max_bytes: usize,
+}
+
+impl TlsConfig {
+    pub fn enabled(&self) -> bool {
+        self.cert.is_some() && self.key.is_some()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            app_name: String::from("actix web"),
+            bind: vec![String::from("0.0.0.0:8081")],
+            workers: None,
+            limits: Limits::default(),
+            log: LogConfig::default(),
+            middleware: MiddlewareConfig::default(),
+            counters: CounterConfig::default(),
+            people: PeopleConfig::default(),
+            auth: AuthConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            uploads: UploadConfig::default(),
+            jobs: JobsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            ws: WsConfig::default(),
+            events: EventsConfig::default(),
+            tls: TlsConfig::default(),
+            cache: CacheConfig::default(),
+            idempotency: IdempotencyConfig::default(),
+            cors: CorsConfig::default(),
+            audit: AuditConfig::default(),
+            sessions: SessionConfig::default(),
+            unknown_host: UnknownHost::Default,
+            base_domain: None,
+            tenants: Vec::new(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            payload: 262_144, // 256k
+            json: 32_768,
+            form: 16_384,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: String::from("info"),
+            format: String::from(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#),
+            agent_format: String::from("%a %{User-Agent}i%D"),
+        }
+    }
+}
+
+impl Default for MiddlewareConfig {
+    fn default() -> Self {
+        MiddlewareConfig {
+            logger: false,
+            json_log: true,
+            compress: true,
+            trace: true,
+            metrics: true,
+        }
+    }
+}
+
+impl Default for CounterConfig {
+    fn default() -> Self {
+        CounterConfig {
+            store: StoreKind::Memory,
+            path: PathBuf::from("counters.log"),
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            enabled: true,
+            policies: Vec::new(),
+        }
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            enabled: true,
+            path: PathBuf::from("audit.jsonl"),
+            max_size: 10 * 1024 * 1024,
+            keep: 10,
+            body: false,
+            max_body: 4096,
+            redact: vec![
+                String::from("password"),
+                String::from("secret"),
+                String::from("token"),
+            ],
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            secret: None,
+            encrypt: false,
+            cookie_name: String::from("session"),
+            secure: None,
+            store: StoreKind::Memory,
+            dir: PathBuf::from("sessions"),
+            idle_timeout: 30 * 60,
+            max_age: 24 * 60 * 60,
+            rotate: 15 * 60,
+            max_sessions: 10_000,
+            accounts: Vec::new(),
+        }
+    }
+}
+
+impl Default for TenantConfig {
+    fn default() -> Self {
+        TenantConfig {
+            name: String::new(),
+            hosts: Vec::new(),
+            subdomain: None,
+            app_name: None,
+            scopes: Scope::ALL.to_vec(),
+            counters: CounterConfig::default(),
+        }
+    }
+}
+
+impl Default for PeopleConfig {
+    fn default() -> Self {
+        PeopleConfig {
+            path: PathBuf::from("people.db"),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            secret: None,
+            audience: String::from("actix"),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let rule = |path: &str| Rule {
+            path: path.to_string(),
+            capacity: 20,
+            per_second: 5.0,
+        };
+
+        RateLimitConfig {
+            enabled: true,
+            rules: vec![
+                rule("/echo"),
+                rule("/person/manual"),
+                rule("/jobs"),
+                rule("/session"),
+                // password guessing: a few tries, then one every ten seconds
+                Rule {
+                    path: String::from("/login"),
+                    capacity: 5,
+                    per_second: 0.1,
+                },
+            ],
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            dir: PathBuf::from("uploads"),
+            max_file_size: 100 * 1024 * 1024,
+            max_total_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            workers: 2,
+            max_attempts: 3,
+            backoff: 1000,
+            path: PathBuf::from("jobs.json"),
+            keep: 1000,
+            max_queued: 1000,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            drain: 5,
+            timeout: 30,
+        }
+    }
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        WsConfig {
+            heartbeat: 5,
+            client_timeout: 15,
+            idle_timeout: 300,
+            max_message: 65_536,
+        }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig {
+            buffer: 1000,
+            keep_alive: 15,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert: None,
+            key: None,
+            bind: vec![String::from("0.0.0.0:8443")],
+            redirect: true,
+            reload: 60,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: true,
+            lru: true,
+            max_entries: 1000,
+            max_bytes: 16 * 1024 * 1024,
+            ttl: 300,
+            max_body: 1024 * 1024,
+        }
+    }
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig {
+            enabled: true,
+            window: 24 * 60 * 60,
+            max_body: 1024 * 1024,
+            max_entries: 10_000,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+// Command-line flags. Every flag can also be given as an environment variable.
+#[derive(Debug, Default, Parser)]
+#[command(about = "actix web example server")]
+pub struct Cli {
+    /// TOML configuration file
+    #[arg(long, env = "ACTIX_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[arg(long, env = "ACTIX_APP_NAME")]
+    pub app_name: Option<String>,
+
+    /// Address to listen on, can be repeated or comma separated
+    #[arg(long, env = "ACTIX_BIND", value_delimiter = ',')]
+    pub bind: Vec<String>,
+
+    #[arg(long, env = "ACTIX_WORKERS")]
+    pub workers: Option<usize>,
+
+    #[arg(long, env = "ACTIX_PAYLOAD_LIMIT")]
+    pub payload_limit: Option<usize>,
+
+    #[arg(long, env = "ACTIX_JSON_LIMIT")]
+    pub json_limit: Option<usize>,
+
+    #[arg(long, env = "ACTIX_FORM_LIMIT")]
+    pub form_limit: Option<usize>,
+
+    #[arg(long, env = "ACTIX_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    #[arg(long, env = "ACTIX_LOG_FORMAT")]
+    pub log_format: Option<String>,
+
+    #[arg(long, env = "ACTIX_LOGGER")]
+    pub logger: Option<bool>,
+
+    #[arg(long, env = "ACTIX_JSON_LOG")]
+    pub json_log: Option<bool>,
+
+    #[arg(long, env = "ACTIX_COMPRESS")]
+    pub compress: Option<bool>,
+
+    #[arg(long, env = "ACTIX_TRACE")]
+    pub trace: Option<bool>,
+
+    #[arg(long, env = "ACTIX_METRICS")]
+    pub metrics: Option<bool>,
+
+    #[arg(long, env = "ACTIX_RATE_LIMIT")]
+    pub rate_limit: Option<bool>,
+
+    #[arg(long, env = "ACTIX_COUNTER_STORE")]
+    pub counter_store: Option<StoreKind>,
+
+    #[arg(long, env = "ACTIX_COUNTER_PATH")]
+    pub counter_path: Option<PathBuf>,
+
+    #[arg(long, env = "ACTIX_PEOPLE_PATH")]
+    pub people_path: Option<PathBuf>,
+
+    #[arg(long, env = "ACTIX_UPLOAD_DIR")]
+    pub upload_dir: Option<PathBuf>,
+
+    /// Background threads running jobs
+    #[arg(long, env = "ACTIX_JOB_WORKERS")]
+    pub job_workers: Option<usize>,
+
+    #[arg(long, env = "ACTIX_JOBS_PATH")]
+    pub jobs_path: Option<PathBuf>,
+
+    #[arg(long, env = "ACTIX_JWT_SECRET", hide_env_values = true)]
+    pub jwt_secret: Option<String>,
+
+    #[arg(long, env = "ACTIX_JWT_AUDIENCE")]
+    pub jwt_audience: Option<String>,
+
+    /// Seconds to report not ready before shutting down
+    #[arg(long, env = "ACTIX_SHUTDOWN_DRAIN")]
+    pub shutdown_drain: Option<u64>,
+
+    /// PEM certificate chain, enables HTTPS together with `--tls-key`
+    #[arg(long, env = "ACTIX_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    #[arg(long, env = "ACTIX_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    #[arg(long, env = "ACTIX_TLS_BIND", value_delimiter = ',')]
+    pub tls_bind: Vec<String>,
+
+    #[arg(long, env = "ACTIX_CACHE")]
+    pub cache: Option<bool>,
+
+    #[arg(long, env = "ACTIX_AUDIT")]
+    pub audit: Option<bool>,
+
+    #[arg(long, env = "ACTIX_AUDIT_PATH")]
+    pub audit_path: Option<PathBuf>,
+
+    /// Secret session cookies are signed with, at least 32 bytes
+    #[arg(long, env = "ACTIX_SESSION_SECRET", hide_env_values = true)]
+    pub session_secret: Option<String>,
+
+    #[arg(long, env = "ACTIX_SESSION_STORE")]
+    pub session_store: Option<StoreKind>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "could not read {}: {source}", path.display())
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "could not parse {}: {source}", path.display())
+            }
+            ConfigError::Invalid(problems) => {
+                writeln!(f, "invalid configuration:")?;
+                for problem in problems {
+                    writeln!(f, "  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// file used when `--config` is not given, it's fine for it to be missing
+const DEFAULT_CONFIG_FILE: &str = "actix.toml";
+
+impl Config {
+    // Loads the configuration from the process' arguments and environment.
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::from_cli(Cli::parse())
+    }
+
+    pub fn from_cli(cli: Cli) -> Result<Config, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply(cli);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    fn apply(&mut self, cli: Cli) {
+        if let Some(app_name) = cli.app_name {
+            self.app_name = app_name;
+        }
+        if !cli.bind.is_empty() {
+            self.bind = cli.bind;
+        }
+        if cli.workers.is_some() {
+            self.workers = cli.workers;
+        }
+        if let Some(limit) = cli.payload_limit {
+            self.limits.payload = limit;
+        }
+        if let Some(limit) = cli.json_limit {
+            self.limits.json = limit;
+        }
+        if let Some(limit) = cli.form_limit {
+            self.limits.form = limit;
+        }
+        if let Some(level) = cli.log_level {
+            self.log.level = level;
+        }
+        if let Some(format) = cli.log_format {
+            self.log.format = format;
+        }
+        if let Some(logger) = cli.logger {
+            self.middleware.logger = logger;
+        }
+        if let Some(json_log) = cli.json_log {
+            self.middleware.json_log = json_log;
+        }
+        if let Some(compress) = cli.compress {
+            self.middleware.compress = compress;
+        }
+        if let Some(trace) = cli.trace {
+            self.middleware.trace = trace;
+        }
+        if let Some(metrics) = cli.metrics {
+            self.middleware.metrics = metrics;
+        }
+        if let Some(enabled) = cli.rate_limit {
+            self.rate_limit.enabled = enabled;
+        }
+        if let Some(store) = cli.counter_store {
+            self.counters.store = store;
+        }
+        if let Some(path) = cli.counter_path {
+            self.counters.path = path;
+        }
+        if let Some(path) = cli.people_path {
+            self.people.path = path;
+        }
+        if let Some(dir) = cli.upload_dir {
+            self.uploads.dir = dir;
+        }
+        if let Some(workers) = cli.job_workers {
+            self.jobs.workers = workers;
+        }
+        if let Some(path) = cli.jobs_path {
+            self.jobs.path = path;
+        }
+        if cli.jwt_secret.is_some() {
+            self.auth.secret = cli.jwt_secret;
+        }
+        if let Some(audience) = cli.jwt_audience {
+            self.auth.audience = audience;
+        }
+        if let Some(drain) = cli.shutdown_drain {
+            self.shutdown.drain = drain;
+        }
+        if cli.tls_cert.is_some() {
+            self.tls.cert = cli.tls_cert;
+        }
+        if cli.tls_key.is_some() {
+            self.tls.key = cli.tls_key;
+        }
+        if !cli.tls_bind.is_empty() {
+            self.tls.bind = cli.tls_bind;
+        }
+        if let Some(enabled) = cli.cache {
+            self.cache.enabled = enabled;
+        }
+        if let Some(enabled) = cli.audit {
+            self.audit.enabled = enabled;
+        }
+        if let Some(path) = cli.audit_path {
+            self.audit.path = path;
+        }
+        if cli.session_secret.is_some() {
+            self.sessions.secret = cli.session_secret;
+        }
+        if let Some(store) = cli.session_store {
+            self.sessions.store = store;
+        }
+    }
+
+    // Collects every problem instead of stopping at the first one, so they
+    // can all be fixed in one go.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.bind.is_empty() {
+            problems.push(String::from("bind: at least one address is required"));
+        }
+        for addr in &self.bind {
+            if addr.parse::<SocketAddr>().is_err() {
+                problems.push(format!("bind: {addr:?} is not a valid `ip:port` address"));
+            }
+        }
+        if self.workers == Some(0) {
+            problems.push(String::from("workers: must be at least 1"));
+        }
+        for (name, limit) in [
+            ("limits.payload", self.limits.payload),
+            ("limits.json", self.limits.json),
+            ("limits.form", self.limits.form),
+        ] {
+            if limit == 0 {
+                problems.push(format!("{name}: must be greater than 0"));
+            }
+        }
+        if self.log.level.parse::<log::LevelFilter>().is_err() {
+            problems.push(format!("log.level: unknown level {:?}", self.log.level));
+        }
+        if self.counters.store == StoreKind::File && self.counters.path.as_os_str().is_empty() {
+            problems.push(String::from(
+                "counters.path: required when counters.store = \"file\"",
+            ));
+        }
+        if self.people.path.as_os_str().is_empty() {
+            problems.push(String::from("people.path: must not be empty"));
+        }
+        match &self.auth.secret {
+            None => problems.push(String::from(
+                "auth.secret: required, or set ACTIX_JWT_SECRET",
+            )),
+            Some(secret) if secret.len() < 32 => {
+                problems.push(String::from("auth.secret: must be at least 32 bytes"))
+            }
+            Some(_) => {}
+        }
+        for rule in &self.rate_limit.rules {
+            if rule.capacity == 0 || rule.per_second.is_nan() || rule.per_second <= 0.0 {
+                problems.push(format!(
+                    "rate_limit.rules: {:?} needs a capacity and per_second above 0",
+                    rule.path
+                ));
+            }
+        }
+        if self.uploads.max_file_size == 0 || self.uploads.max_total_size == 0 {
+            problems.push(String::from("uploads: size limits must be greater than 0"));
+        }
+        if self.uploads.max_file_size > self.uploads.max_total_size {
+            problems.push(String::from(
+                "uploads.max_file_size: must not exceed uploads.max_total_size",
+            ));
+        }
+        if self.jobs.workers == 0 || self.jobs.max_attempts == 0 || self.jobs.max_queued == 0 {
+            problems.push(String::from(
+                "jobs: workers, max_attempts and max_queued must be at least 1",
+            ));
+        }
+        if self.jobs.path.as_os_str().is_empty() {
+            problems.push(String::from("jobs.path: must not be empty"));
+        }
+        if self.audit.enabled && self.audit.path.as_os_str().is_empty() {
+            problems.push(String::from("audit.path: required when audit is enabled"));
+        }
+        if self.audit.max_size == 0 {
+            problems.push(String::from("audit.max_size: must be greater than 0"));
+        }
+        self.validate_sessions(&mut problems);
+        if self.ws.heartbeat == 0 || self.ws.client_timeout <= self.ws.heartbeat {
+            problems.push(String::from(
+                "ws.client_timeout: must be longer than ws.heartbeat, which must be above 0",
+            ));
+        }
+        if self.ws.idle_timeout == 0 || self.ws.max_message == 0 {
+            problems.push(String::from(
+                "ws: idle_timeout and max_message must be greater than 0",
+            ));
+        }
+        if self.events.buffer == 0 || self.events.keep_alive == 0 {
+            problems.push(String::from(
+                "events: buffer and keep_alive must be greater than 0",
+            ));
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            problems.push(String::from("tls: cert and key must be set together"));
+        }
+        if self.tls.enabled() {
+            if self.tls.bind.is_empty() {
+                problems.push(String::from("tls.bind: at least one address is required"));
+            }
+            for addr in &self.tls.bind {
+                if addr.parse::<SocketAddr>().is_err() {
+                    problems.push(format!(
+                        "tls.bind: {addr:?} is not a valid `ip:port` address"
+                    ));
+                }
+            }
+            if self.tls.reload == 0 {
+                problems.push(String::from("tls.reload: must be greater than 0"));
+            }
+        }
+        if self.cache.lru
+            && (self.cache.max_entries == 0 || self.cache.max_bytes == 0 || self.cache.ttl == 0)
+        {
+            problems.push(String::from(
+                "cache: max_entries, max_bytes and ttl must be greater than 0",
+            ));
+        }
+        if self.idempotency.enabled
+            && (self.idempotency.window == 0 || self.idempotency.max_body == 0)
+        {
+            problems.push(String::from(
+                "idempotency: window and max_body must be greater than 0",
+            ));
+        }
+        if self.auth.audience.is_empty() {
+            problems.push(String::from("auth.audience: must not be empty"));
+        }
+        if self.cors.enabled {
+            problems.extend(self.cors.policies.iter().flat_map(Policy::problems));
+        }
+        self.validate_tenants(&mut problems);
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    fn validate_sessions(&self, problems: &mut Vec<String>) {
+        let sessions = &self.sessions;
+        if sessions.secret.as_ref().is_some_and(|s| s.len() < 32) {
+            problems.push(String::from("sessions.secret: must be at least 32 bytes"));
+        }
+        let token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        if sessions.cookie_name.is_empty() || !sessions.cookie_name.chars().all(token) {
+            problems.push(format!(
+                "sessions.cookie_name: {:?} is not a valid cookie name",
+                sessions.cookie_name
+            ));
+        }
+        if sessions.store == StoreKind::File && sessions.dir.as_os_str().is_empty() {
+            problems.push(String::from(
+                "sessions.dir: required when sessions.store = \"file\"",
+            ));
+        }
+        if sessions.idle_timeout == 0 || sessions.max_age == 0 || sessions.max_sessions == 0 {
+            problems.push(String::from(
+                "sessions: idle_timeout, max_age and max_sessions must be greater than 0",
+            ));
+        }
+
+        let mut usernames = HashSet::new();
+        for account in &sessions.accounts {
+            let name = &account.username;
+            if name.is_empty() {
+                problems.push(String::from(
+                    "sessions.accounts.username: must not be empty",
+                ));
+            } else if !usernames.insert(name) {
+                problems.push(format!("sessions.accounts: {name:?} is defined twice"));
+            }
+            if argon2::PasswordHash::new(&account.password_hash).is_err() {
+                problems.push(format!(
+                    "sessions.accounts.{name}.password_hash: not a PHC hash string"
+                ));
+            }
+        }
+    }
+
+    fn validate_tenants(&self, problems: &mut Vec<String>) {
+        if self.unknown_host == UnknownHost::Reject && self.tenants.is_empty() {
+            problems.push(String::from(
+                "unknown_host: \"reject\" needs at least one tenant",
+            ));
+        }
+
+        if let Some(base) = &self.base_domain {
+            if base.trim_matches('.').is_empty() || base.starts_with('.') {
+                problems.push(format!("base_domain: {base:?} is not a domain name"));
+            }
+        }
+
+        let mut names = HashSet::new();
+        let mut hosts = HashSet::new();
+        let mut subdomains = HashSet::new();
+        let mut logs = HashSet::new();
+        if self.counters.store == StoreKind::File {
+            logs.insert(&self.counters.path);
+        }
+
+        for tenant in &self.tenants {
+            let name = &tenant.name;
+            if name.is_empty() {
+                problems.push(String::from("tenants.name: must not be empty"));
+            } else if !names.insert(name) {
+                problems.push(format!("tenants: {name:?} is defined twice"));
+            }
+            if tenant.hosts.is_empty() && tenant.subdomain.is_none() {
+                problems.push(format!("tenants.{name}: needs hosts or a subdomain"));
+            }
+            for host in &tenant.hosts {
+                if !hosts.insert(host.trim_end_matches('.').to_ascii_lowercase()) {
+                    problems.push(format!(
+                        "tenants.{name}.hosts: {host:?} belongs to another tenant"
+                    ));
+                }
+            }
+            if let Some(subdomain) = &tenant.subdomain {
+                if self.base_domain.is_none() {
+                    problems.push(format!(
+                        "tenants.{name}.subdomain: needs base_domain to be set"
+                    ));
+                }
+                if subdomain.is_empty() || subdomain.contains('.') {
+                    problems.push(format!("tenants.{name}.subdomain: must be a single label"));
+                } else if !subdomains.insert(subdomain.to_ascii_lowercase()) {
+                    problems.push(format!(
+                        "tenants.{name}.subdomain: {subdomain:?} belongs to another tenant"
+                    ));
+                }
+            }
+            if tenant.counters.store == StoreKind::File {
+                if tenant.counters.path.as_os_str().is_empty() {
+                    problems.push(format!(
+                        "tenants.{name}.counters.path: required when store = \"file\""
+                    ));
+                } else if !logs.insert(&tenant.counters.path) {
+                    problems.push(format!(
+                        "tenants.{name}.counters.path: {} is used by another counter store",
+                        tenant.counters.path.display()
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers_override_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("actix.toml");
+        std::fs::write(
+            &path,
+            r#"
+            app_name = "from file"
+            bind = ["127.0.0.1:9000"]
+            workers = 2
+
+            [limits]
+            json = 1024
+
+            [auth]
+            secret = "0123456789abcdef0123456789abcdef"
+            "#,
+        )
+        .unwrap();
+
+        let cli = Cli::try_parse_from([
+            "actix",
+            "--config",
+            path.to_str().unwrap(),
+            "--bind",
+            "127.0.0.1:1,[::1]:2",
+            "--compress",
+            "false",
+        ])
+        .unwrap();
+        let config = Config::from_cli(cli).unwrap();
+
+        assert_eq!(config.app_name, "from file");
+        assert_eq!(config.bind, ["127.0.0.1:1", "[::1]:2"]);
+        assert_eq!(config.workers, Some(2));
+        assert_eq!(config.limits.json, 1024);
+        assert_eq!(config.limits.form, Limits::default().form);
+        assert!(!config.middleware.compress);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let config = Config {
+            bind: vec![String::from("localhost")],
+            workers: Some(0),
+            limits: Limits {
+                json: 0,
+                ..Limits::default()
+            },
+            ..Config::default()
+        };
+
+        // the missing auth.secret is one of them
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems.len(), 4),
+            other => panic!("expected validation errors, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn checks_tenants() {
+        let config: Config = toml::from_str(
+            r#"
+            unknown_host = "reject"
+
+            [[tenants]]
+            name = "acme"
+            hosts = ["acme.test"]
+            scopes = ["counters", "events"]
+
+            [[tenants]]
+            name = "acme"
+            hosts = ["ACME.test"]
+            subdomain = "a.b"
+
+            [auth]
+            secret = "0123456789abcdef0123456789abcdef"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.tenants[0].scopes, [Scope::Counters, Scope::Events]);
+        assert_eq!(config.tenants[1].scopes, Scope::ALL);
+
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(
+                problems,
+                [
+                    "tenants: \"acme\" is defined twice",
+                    "tenants.acme.hosts: \"ACME.test\" belongs to another tenant",
+                    "tenants.acme.subdomain: needs base_domain to be set",
+                    "tenants.acme.subdomain: must be a single label",
+                ]
+            ),
+            other => panic!("expected validation errors, got {other:?}"),
+        }
+
+        let config = Config {
+            unknown_host: UnknownHost::Reject,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let err = toml::from_str::<Config>("bnid = []").unwrap_err();
+        assert!(err.to_string().contains("bnid"));
+    }
+}
@@ -0,0 +1,500 @@
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        Method,
+    },
+    Error, HttpResponse, ResponseError,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::Deserialize;
+
+use crate::error::ApiError;
+
+// Cross-origin access to every path starting with `path`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub path: String,
+    // `https://app.example.com`, `https://*.example.com` for its subdomains,
+    // or `*` for any origin
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    // request headers the browser may send, `*` for any
+    pub headers: Vec<String>,
+    // response headers scripts may read besides the safelisted ones
+    pub expose: Vec<String>,
+    // cookies and `Authorization`; not allowed with the `*` origin
+    pub credentials: bool,
+    // seconds browsers may cache a preflight
+    pub max_age: u64,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            path: String::from("/"),
+            origins: Vec::new(),
+            methods: vec![
+                String::from("GET"),
+                String::from("HEAD"),
+                String::from("POST"),
+            ],
+            headers: vec![String::from("content-type")],
+            expose: Vec::new(),
+            credentials: false,
+            max_age: 600,
+        }
+    }
+}
+
+impl Policy {
+    fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(self.path.trim_end_matches('/')) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    // Everything wrong with the policy, for `Config::validate`.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let path = &self.path;
+
+        if self.origins.is_empty() {
+            problems.push(format!("cors.policies: {path:?} allows no origin"));
+        }
+        for origin in &self.origins {
+            if origin != "*" && !origin.contains("://") {
+                problems.push(format!(
+                    "cors.policies: {origin:?} is not `*` or `scheme://host[:port]`"
+                ));
+            }
+        }
+        if self.credentials && self.origins.iter().any(|o| o == "*") {
+            problems.push(format!(
+                "cors.policies: {path:?} can't allow credentials from any origin"
+            ));
+        }
+        for method in &self.methods {
+            if Method::from_bytes(method.as_bytes()).is_err() {
+                problems.push(format!("cors.policies: {method:?} is not a method"));
+            }
+        }
+        for name in self.headers.iter().chain(&self.expose) {
+            if name != "*" && HeaderName::from_bytes(name.as_bytes()).is_err() {
+                problems.push(format!("cors.policies: {name:?} is not a header name"));
+            }
+        }
+
+        problems
+    }
+}
+
+enum Origin {
+    Any,
+    Exact(String),
+    // scheme with `://`, and the domain with its leading dot
+    Subdomains(String, String),
+}
+
+impl Origin {
+    fn parse(pattern: &str) -> Origin {
+        if pattern == "*" {
+            return Origin::Any;
+        }
+
+        let pattern = pattern.trim_end_matches('/').to_ascii_lowercase();
+        match pattern.split_once("://*.") {
+            Some((scheme, domain)) => {
+                Origin::Subdomains(format!("{scheme}://"), format!(".{domain}"))
+            }
+            None => Origin::Exact(pattern),
+        }
+    }
+
+    // `origin` is lowercase.
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            Origin::Any => true,
+            Origin::Exact(exact) => exact == origin,
+            Origin::Subdomains(scheme, domain) => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|host| host.strip_suffix(domain.as_str()))
+                .is_some_and(|sub| !sub.is_empty() && !sub.contains(['/', ':', '@'])),
+        }
+    }
+}
+
+// A policy with its header values worked out up front.
+struct Rules {
+    policy: Policy,
+    origins: Vec<Origin>,
+    methods: Vec<Method>,
+    // `None` allows any request header
+    headers: Option<Vec<HeaderName>>,
+    allow_methods: HeaderValue,
+    expose: Option<HeaderValue>,
+}
+
+fn joined(values: &[String]) -> Option<HeaderValue> {
+    HeaderValue::try_from(values.join(", ")).ok()
+}
+
+fn forbidden(detail: String) -> ApiError {
+    ApiError::Forbidden {
+        detail,
+        challenge: None,
+    }
+}
+
+impl Rules {
+    fn new(policy: Policy) -> Self {
+        let methods: Vec<_> = policy
+            .methods
+            .iter()
+            .filter_map(|m| Method::from_bytes(m.to_ascii_uppercase().as_bytes()).ok())
+            .collect();
+        let names: Vec<_> = methods.iter().map(|m| m.to_string()).collect();
+        let headers = (!policy.headers.iter().any(|h| h == "*")).then(|| {
+            policy
+                .headers
+                .iter()
+                .filter_map(|h| HeaderName::from_bytes(h.as_bytes()).ok())
+                .collect()
+        });
+
+        Rules {
+            origins: policy.origins.iter().map(|o| Origin::parse(o)).collect(),
+            allow_methods: joined(&names).unwrap_or(HeaderValue::from_static("")),
+            expose: (!policy.expose.is_empty())
+                .then(|| joined(&policy.expose))
+                .flatten(),
+            methods,
+            headers,
+            policy,
+        }
+    }
+
+    fn any_origin(&self) -> bool {
+        self.origins.iter().any(|o| matches!(o, Origin::Any))
+    }
+
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+        let origin = origin.to_ascii_lowercase();
+
+        self.origins.iter().any(|o| o.matches(&origin))
+    }
+
+    // Headers every response to an allowed origin gets.
+    fn insert_origin(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        let allowed = match self.any_origin() {
+            true => HeaderValue::from_static("*"),
+            false => origin.clone(),
+        };
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+        if self.policy.credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    fn preflight(
+        &self,
+        req: &ServiceRequest,
+        origin: &HeaderValue,
+    ) -> Result<HttpResponse, ApiError> {
+        if !self.allows(origin) {
+            return Err(forbidden(format!(
+                "origin {} may not call {}",
+                origin.to_str().unwrap_or("?"),
+                req.path()
+            )));
+        }
+
+        let method = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| Method::from_bytes(v.as_bytes()).ok())
+            .ok_or_else(|| {
+                ApiError::BadRequest(String::from("invalid Access-Control-Request-Method"))
+            })?;
+        if !self.methods.contains(&method) {
+            return Err(forbidden(format!(
+                "{method} is not allowed on {}",
+                req.path()
+            )));
+        }
+
+        let requested = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let requested: Vec<_> = requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        if let Some(allowed) = &self.headers {
+            let denied = requested.iter().find(|name| {
+                !allowed
+                    .iter()
+                    .any(|a| a.as_str().eq_ignore_ascii_case(name))
+            });
+            if let Some(name) = denied {
+                return Err(forbidden(format!("header {name} is not allowed")));
+            }
+        }
+
+        let mut res = HttpResponse::NoContent();
+        res.insert_header((
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            self.allow_methods.clone(),
+        ))
+        .insert_header((header::ACCESS_CONTROL_MAX_AGE, self.policy.max_age))
+        .insert_header((
+            header::VARY,
+            "origin, access-control-request-method, access-control-request-headers",
+        ));
+        if !requested.is_empty() {
+            // only what was asked for, which has been checked above
+            res.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, requested.join(", ")));
+        }
+
+        let mut res = res.finish();
+        self.insert_origin(res.headers_mut(), origin);
+        Ok(res)
+    }
+}
+
+// CORS middleware. Each request with an `Origin` gets the first policy
+// whose path matches; preflights are answered here and never reach the
+// routes, so they aren't rate limited or authenticated. Other requests are
+// handled as usual and get the CORS headers added, error responses included,
+// so scripts can read those too (e.g. the 405 for `HEAD /app`). Paths
+// without a policy get no CORS headers, which browsers take as a denial.
+#[derive(Clone)]
+pub struct Cors {
+    rules: Arc<Vec<Rules>>,
+}
+
+impl Cors {
+    pub fn new(policies: Vec<Policy>) -> Self {
+        Cors {
+            rules: Arc::new(policies.into_iter().map(Rules::new).collect()),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Cors
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CorsMiddleware {
+            service,
+            cors: self.clone(),
+        }))
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: S,
+    cors: Cors,
+}
+
+impl<S, B> Service<ServiceRequest> for CorsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let origin = req.headers().get(header::ORIGIN).cloned();
+        let index = self
+            .cors
+            .rules
+            .iter()
+            .position(|rules| rules.policy.matches(req.path()));
+        let (Some(origin), Some(index)) = (origin, index) else {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        };
+
+        let rules = &self.cors.rules[index];
+        if req.method() == Method::OPTIONS
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            let res = rules
+                .preflight(&req, &origin)
+                .unwrap_or_else(|err| err.error_response());
+            let res = req.into_response(res).map_into_right_body();
+            return Box::pin(async move { Ok(res) });
+        }
+
+        let cors = self.cors.clone();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let rules = &cors.rules[index];
+            let headers = res.headers_mut();
+
+            if !rules.any_origin() {
+                headers.append(header::VARY, HeaderValue::from_static("origin"));
+            }
+            if rules.allows(&origin) {
+                rules.insert_origin(headers, &origin);
+                if let Some(expose) = &rules.expose {
+                    headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, expose.clone());
+                }
+            }
+
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    #[test]
+    fn matches_exact_and_wildcard_origins() {
+        let exact = Origin::parse("https://App.example.com/");
+        assert!(exact.matches("https://app.example.com"));
+        assert!(!exact.matches("http://app.example.com"));
+
+        let wildcard = Origin::parse("https://*.example.com");
+        assert!(wildcard.matches("https://app.example.com"));
+        assert!(wildcard.matches("https://a.b.example.com"));
+        assert!(!wildcard.matches("https://example.com"));
+        assert!(!wildcard.matches("https://app.example.com:8443"));
+        assert!(!wildcard.matches("https://evil-example.com"));
+        assert!(!wildcard.matches("http://app.example.com"));
+    }
+
+    #[actix_web::test]
+    async fn answers_preflights_and_decorates_responses() {
+        let app = init_service(
+            App::new()
+                .wrap(Cors::new(vec![Policy {
+                    path: String::from("/api"),
+                    origins: vec![String::from("https://*.example.com")],
+                    methods: vec![String::from("GET"), String::from("PUT")],
+                    expose: vec![String::from("x-request-id")],
+                    credentials: true,
+                    ..Policy::default()
+                }]))
+                .route("/api/item", web::get().to(HttpResponse::Ok))
+                .route("/other", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let preflight = |origin: &str, method: &str, headers: &str| {
+            TestRequest::default()
+                .method(Method::OPTIONS)
+                .uri("/api/item")
+                .insert_header((header::ORIGIN, origin))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, headers))
+                .to_request()
+        };
+
+        let res = call_service(
+            &app,
+            preflight("https://app.example.com", "PUT", "Content-Type"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let headers = res.headers();
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "https://app.example.com"
+        );
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap(),
+            "GET, PUT"
+        );
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap(),
+            "Content-Type"
+        );
+        assert_eq!(
+            headers
+                .get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS)
+                .unwrap(),
+            "true"
+        );
+        assert_eq!(headers.get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+
+        for req in [
+            preflight("https://example.org", "PUT", ""),
+            preflight("https://app.example.com", "DELETE", ""),
+            preflight("https://app.example.com", "GET", "x-secret"),
+        ] {
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            assert!(!res
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        }
+
+        let req = TestRequest::get()
+            .uri("/api/item")
+            .insert_header((header::ORIGIN, "https://app.example.com"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://app.example.com"
+        );
+        assert_eq!(
+            res.headers()
+                .get(header::ACCESS_CONTROL_EXPOSE_HEADERS)
+                .unwrap(),
+            "x-request-id"
+        );
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "origin");
+
+        // no policy for the path
+        let req = TestRequest::get()
+            .uri("/other")
+            .insert_header((header::ORIGIN, "https://app.example.com"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -0,0 +1,394 @@
+use std::fmt;
+use std::io;
+
+use actix_web::{
+    error::{
+        BlockingError, JsonPayloadError, PathError, PayloadError, QueryPayloadError,
+        UrlencodedError,
+    },
+    http::{header, StatusCode},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use serde::Serialize;
+use utoipa::{ToResponse, ToSchema};
+
+use crate::validate::FieldError;
+
+// Crate-wide error type. Every variant renders as an RFC 7807
+// `application/problem+json` body carrying a stable `code` that clients can
+// match on instead of parsing the human readable `detail`.
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidJson(String),
+    InvalidForm(String),
+    InvalidQuery(String),
+    InvalidPath(String),
+    // a body in one of the negotiated formats that could not be decoded
+    InvalidBody(String),
+    // the body could not be read at all, e.g. the connection broke mid-way
+    InvalidPayload(String),
+    BadRequest(String),
+    // the body was understood but broke the type's `Validate` rules
+    Validation(Vec<FieldError>),
+    PayloadTooLarge {
+        limit: usize,
+    },
+    UnsupportedMediaType(String),
+    NotAcceptable(String),
+    // `challenge` is sent back as the `WWW-Authenticate` header
+    Unauthorized {
+        detail: String,
+        challenge: Option<String>,
+    },
+    Forbidden {
+        detail: String,
+        challenge: Option<String>,
+    },
+    NotFound(String),
+    MethodNotAllowed,
+    PreconditionFailed(String),
+    Conflict(String),
+    // an `Idempotency-Key` sent again with a different request
+    IdempotencyKeyReused,
+    // the idempotency store holds `max_entries` keys or `max_bytes` already
+    IdempotencyStoreFull,
+    // the server-wide storage quota is used up
+    QuotaExceeded(String),
+    // the job queue holds `max_queued` unfinished jobs already
+    QueueFull(String),
+    // `retry_after` is in seconds and sent back as the `Retry-After` header
+    TooManyRequests {
+        retry_after: u64,
+    },
+    Storage(io::Error),
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidJson(_) => "invalid_json",
+            ApiError::InvalidForm(_) => "invalid_form",
+            ApiError::InvalidQuery(_) => "invalid_query",
+            ApiError::InvalidPath(_) => "invalid_path",
+            ApiError::InvalidBody(_) => "invalid_body",
+            ApiError::InvalidPayload(_) => "invalid_payload",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::PayloadTooLarge { .. } => "payload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::NotAcceptable(_) => "not_acceptable",
+            ApiError::Unauthorized { .. } => "unauthorized",
+            ApiError::Forbidden { .. } => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::MethodNotAllowed => "method_not_allowed",
+            ApiError::PreconditionFailed(_) => "precondition_failed",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::IdempotencyKeyReused => "idempotency_key_reused",
+            ApiError::IdempotencyStoreFull => "idempotency_store_full",
+            ApiError::QuotaExceeded(_) => "quota_exceeded",
+            ApiError::QueueFull(_) => "queue_full",
+            ApiError::TooManyRequests { .. } => "rate_limited",
+            ApiError::Storage(_) => "storage_unavailable",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ApiError::InvalidJson(_) => "Request body is not valid JSON for this endpoint",
+            ApiError::InvalidForm(_) => "Request body is not a valid form for this endpoint",
+            ApiError::InvalidQuery(_) => "Query string is invalid",
+            ApiError::InvalidPath(_) => "Path parameters are invalid",
+            ApiError::InvalidBody(_) => "Request body could not be decoded",
+            ApiError::InvalidPayload(_) => "Request body could not be read",
+            ApiError::BadRequest(_) => "Bad request",
+            ApiError::Validation(_) => "Request failed validation",
+            ApiError::PayloadTooLarge { .. } => "Payload too large",
+            ApiError::UnsupportedMediaType(_) => "Unsupported media type",
+            ApiError::NotAcceptable(_) => "No acceptable representation",
+            ApiError::Unauthorized { .. } => "Authentication required",
+            ApiError::Forbidden { .. } => "Access denied",
+            ApiError::NotFound(_) => "Resource not found",
+            ApiError::MethodNotAllowed => "Method not allowed",
+            ApiError::PreconditionFailed(_) => "Precondition failed",
+            ApiError::Conflict(_) => "Conflict",
+            ApiError::IdempotencyKeyReused => "Idempotency key reused",
+            ApiError::IdempotencyStoreFull => "Idempotency store full",
+            ApiError::QuotaExceeded(_) => "Storage quota exceeded",
+            ApiError::QueueFull(_) => "Job queue full",
+            ApiError::TooManyRequests { .. } => "Too many requests",
+            ApiError::Storage(_) => "Storage unavailable",
+            ApiError::Internal(_) => "Internal server error",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidJson(detail)
+            | ApiError::InvalidForm(detail)
+            | ApiError::InvalidQuery(detail)
+            | ApiError::InvalidPath(detail)
+            | ApiError::InvalidBody(detail)
+            | ApiError::InvalidPayload(detail)
+            | ApiError::BadRequest(detail)
+            | ApiError::UnsupportedMediaType(detail)
+            | ApiError::NotAcceptable(detail)
+            | ApiError::NotFound(detail)
+            | ApiError::PreconditionFailed(detail)
+            | ApiError::Conflict(detail)
+            | ApiError::QuotaExceeded(detail)
+            | ApiError::QueueFull(detail)
+            | ApiError::Unauthorized { detail, .. }
+            | ApiError::Forbidden { detail, .. } => f.write_str(detail),
+            ApiError::Validation(errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("; ")?;
+                    }
+                    write!(f, "{} {}", error.field, error.message)?;
+                }
+                Ok(())
+            }
+            ApiError::PayloadTooLarge { limit } => {
+                write!(f, "payload exceeds the limit of {limit} bytes")
+            }
+            ApiError::MethodNotAllowed => f.write_str("method is not allowed for this resource"),
+            ApiError::IdempotencyKeyReused => {
+                f.write_str("the Idempotency-Key was already used for a different request")
+            }
+            ApiError::IdempotencyStoreFull => {
+                f.write_str("too many Idempotency-Keys are in use, retry later")
+            }
+            ApiError::TooManyRequests { retry_after } => {
+                write!(f, "rate limit exceeded, retry in {retry_after} seconds")
+            }
+            // don't leak internals to clients, they are logged instead
+            ApiError::Storage(_) | ApiError::Internal(_) => {
+                f.write_str("the server could not complete the request")
+            }
+        }
+    }
+}
+
+// `application/problem+json` body of every error response.
+#[derive(Serialize, ToSchema, ToResponse)]
+#[response(
+    description = "Problem details",
+    content_type = "application/problem+json"
+)]
+pub struct Problem<'a> {
+    #[serde(rename = "type")]
+    kind: String,
+    title: &'a str,
+    status: u16,
+    detail: String,
+    code: &'a str,
+    // per-field failures of a `validation_failed` problem
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a [FieldError]>,
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidJson(_)
+            | ApiError::InvalidForm(_)
+            | ApiError::InvalidQuery(_)
+            | ApiError::InvalidPath(_)
+            | ApiError::InvalidBody(_)
+            | ApiError::InvalidPayload(_)
+            | ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Validation(_) | ApiError::IdempotencyKeyReused => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ApiError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            ApiError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            ApiError::QueueFull(_) | ApiError::IdempotencyStoreFull => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ApiError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Storage(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ApiError::Storage(err) => log::error!("storage error: {err}"),
+            ApiError::Internal(err) => log::error!("internal error: {err}"),
+            _ => {}
+        }
+
+        let status = self.status_code();
+        let problem = Problem {
+            kind: format!("/problems/{}", self.code().replace('_', "-")),
+            title: self.title(),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            code: self.code(),
+            errors: match self {
+                ApiError::Validation(errors) => Some(errors),
+                _ => None,
+            },
+        };
+
+        let mut res = HttpResponse::build(status);
+        res.content_type("application/problem+json");
+
+        if let ApiError::Unauthorized {
+            challenge: Some(challenge),
+            ..
+        }
+        | ApiError::Forbidden {
+            challenge: Some(challenge),
+            ..
+        } = self
+        {
+            res.insert_header((header::WWW_AUTHENTICATE, challenge.as_str()));
+        }
+        if let ApiError::TooManyRequests { retry_after } = self {
+            res.insert_header((header::RETRY_AFTER, *retry_after));
+        }
+
+        res.body(serde_json::to_string(&problem).unwrap_or_default())
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(err: io::Error) -> Self {
+        ApiError::Storage(err)
+    }
+}
+
+impl From<BlockingError> for ApiError {
+    fn from(err: BlockingError) -> Self {
+        ApiError::Internal(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(err: serde_json::Error) -> Self {
+        ApiError::InvalidJson(err.to_string())
+    }
+}
+
+impl From<PayloadError> for ApiError {
+    fn from(err: PayloadError) -> Self {
+        ApiError::InvalidPayload(err.to_string())
+    }
+}
+
+impl From<JsonPayloadError> for ApiError {
+    fn from(err: JsonPayloadError) -> Self {
+        match err {
+            JsonPayloadError::OverflowKnownLength { limit, .. }
+            | JsonPayloadError::Overflow { limit } => ApiError::PayloadTooLarge { limit },
+            JsonPayloadError::ContentType => {
+                ApiError::UnsupportedMediaType(String::from("expected application/json"))
+            }
+            JsonPayloadError::Payload(err) => err.into(),
+            err => ApiError::InvalidJson(err.to_string()),
+        }
+    }
+}
+
+impl From<UrlencodedError> for ApiError {
+    fn from(err: UrlencodedError) -> Self {
+        match err {
+            UrlencodedError::Overflow { limit, .. } => ApiError::PayloadTooLarge { limit },
+            UrlencodedError::ContentType => ApiError::UnsupportedMediaType(String::from(
+                "expected application/x-www-form-urlencoded",
+            )),
+            UrlencodedError::Payload(err) => err.into(),
+            err => ApiError::InvalidForm(err.to_string()),
+        }
+    }
+}
+
+// Error handlers for the built-in extractors, registered through
+// `JsonConfig`, `FormConfig`, `QueryConfig` and `PathConfig`.
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::from(err).into()
+}
+
+pub fn form_error(err: UrlencodedError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::from(err).into()
+}
+
+pub fn query_error(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::InvalidQuery(err.to_string()).into()
+}
+
+pub fn path_error(err: PathError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::InvalidPath(err.to_string()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body::to_bytes, test, web, App};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn renders_problem_json() {
+        let res = ApiError::PayloadTooLarge { limit: 16 }.error_response();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/problem+json"
+        );
+
+        let body: Value =
+            serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["code"], "payload_too_large");
+        assert_eq!(body["status"], 413);
+        assert_eq!(body["type"], "/problems/payload-too-large");
+    }
+
+    #[actix_web::test]
+    async fn unreadable_bodies_are_bad_requests() {
+        let err = ApiError::from(PayloadError::Incomplete(None));
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.code(), "invalid_payload");
+
+        let err = ApiError::from(JsonPayloadError::Payload(PayloadError::Incomplete(None)));
+        assert_eq!(err.code(), "invalid_payload");
+        let err = ApiError::from(UrlencodedError::Payload(PayloadError::Incomplete(None)));
+        assert_eq!(err.code(), "invalid_payload");
+    }
+
+    #[actix_web::test]
+    async fn extractor_errors_use_problem_json() {
+        async fn echo(body: web::Json<Value>) -> web::Json<Value> {
+            body
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::JsonConfig::default().error_handler(json_error))
+                .route("/", web::post().to(echo)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload("{not json")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "invalid_json");
+    }
+}
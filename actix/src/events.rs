@@ -0,0 +1,336 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{
+    http::header::{self, CacheDirective, ContentEncoding},
+    rt, web, HttpRequest, HttpResponse,
+};
+use futures::{stream, Stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::config::EventsConfig;
+
+// How often clients should try to reconnect, sent once per stream.
+const RETRY_MS: u64 = 3000;
+
+// A published state change.
+#[derive(Debug)]
+pub struct ServerEvent {
+    pub id: u64,
+    pub kind: &'static str,
+    // JSON
+    pub data: String,
+}
+
+impl ServerEvent {
+    fn frame(&self) -> web::Bytes {
+        web::Bytes::from(format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            self.id, self.kind, self.data
+        ))
+    }
+}
+
+struct Buffer {
+    next_id: u64,
+    events: VecDeque<Arc<ServerEvent>>,
+    capacity: usize,
+}
+
+// Events published after the requested id are no longer buffered. `latest`
+// is the id of the newest event.
+#[derive(Debug, PartialEq)]
+pub struct Gap {
+    pub latest: u64,
+}
+
+type Replay = Result<Vec<Arc<ServerEvent>>, Gap>;
+
+// Fan-out of state changes to `/events` subscribers. The most recent events
+// are kept so clients that reconnect with `Last-Event-ID` miss nothing. One
+// bus created outside `HttpServer::new` is shared by every worker.
+#[derive(Clone)]
+pub struct EventBus {
+    buffer: Arc<Mutex<Buffer>>,
+    sender: broadcast::Sender<Arc<ServerEvent>>,
+    keep_alive: Duration,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize, keep_alive: Duration) -> Self {
+        EventBus {
+            buffer: Arc::new(Mutex::new(Buffer {
+                next_id: 1,
+                events: VecDeque::with_capacity(capacity),
+                capacity,
+            })),
+            sender: broadcast::channel(capacity).0,
+            keep_alive,
+        }
+    }
+
+    pub fn from_config(config: &EventsConfig) -> Self {
+        EventBus::new(config.buffer, Duration::from_secs(config.keep_alive))
+    }
+
+    pub fn publish(&self, kind: &'static str, data: &impl Serialize) {
+        let data = match serde_json::to_string(data) {
+            Ok(data) => data,
+            Err(err) => {
+                log::warn!("could not serialize {kind} event: {err}");
+                return;
+            }
+        };
+
+        // sent under the lock so subscribers see events in id order
+        let mut buffer = self.buffer.lock().unwrap();
+        let event = Arc::new(ServerEvent {
+            id: buffer.next_id,
+            kind,
+            data,
+        });
+        buffer.next_id += 1;
+
+        if buffer.events.len() == buffer.capacity {
+            buffer.events.pop_front();
+        }
+        buffer.events.push_back(Arc::clone(&event));
+        // fails only when nobody is listening
+        let _ = self.sender.send(event);
+    }
+
+    // Buffered events after `last_id`.
+    pub fn since(&self, last_id: u64) -> Replay {
+        let buffer = self.buffer.lock().unwrap();
+        since(&buffer, last_id)
+    }
+
+    // The events after `last_id` and a receiver for everything published
+    // later, taken together so nothing falls in between.
+    fn subscribe(&self, last_id: Option<u64>) -> (Replay, broadcast::Receiver<Arc<ServerEvent>>) {
+        let buffer = self.buffer.lock().unwrap();
+        let replay = match last_id {
+            Some(last_id) => since(&buffer, last_id),
+            None => Ok(Vec::new()),
+        };
+
+        (replay, self.sender.subscribe())
+    }
+}
+
+fn since(buffer: &Buffer, last_id: u64) -> Replay {
+    let oldest = buffer.events.front().map_or(buffer.next_id, |e| e.id);
+    // ids from before a restart are ahead of ours, nothing can be resumed
+    if last_id + 1 < oldest || last_id >= buffer.next_id {
+        return Err(Gap {
+            latest: buffer.next_id - 1,
+        });
+    }
+
+    Ok(buffer
+        .events
+        .iter()
+        .filter(|e| e.id > last_id)
+        .cloned()
+        .collect())
+}
+
+// Tells the client that events were lost and it should reload its state.
+fn reset_frame(latest: u64) -> web::Bytes {
+    web::Bytes::from(format!("id: {latest}\nevent: reset\ndata: {{}}\n\n"))
+}
+
+struct Subscription {
+    bus: EventBus,
+    receiver: broadcast::Receiver<Arc<ServerEvent>>,
+    keep_alive: rt::time::Interval,
+    last_id: u64,
+}
+
+impl Subscription {
+    async fn next_frame(&mut self) -> web::Bytes {
+        loop {
+            tokio::select! {
+                event = self.receiver.recv() => match event {
+                    Ok(event) => {
+                        // already sent as part of the replay
+                        if event.id <= self.last_id {
+                            continue;
+                        }
+                        self.last_id = event.id;
+                        return event.frame();
+                    }
+                    // this client fell behind the channel, catch up from the buffer
+                    Err(RecvError::Lagged(_)) => {
+                        self.receiver = self.receiver.resubscribe();
+                        match self.bus.since(self.last_id) {
+                            Ok(missed) => {
+                                let Some(last) = missed.last() else {
+                                    continue;
+                                };
+                                self.last_id = last.id;
+                                return missed.iter().flat_map(|e| e.frame()).collect();
+                            }
+                            Err(Gap { latest }) => {
+                                self.last_id = latest;
+                                return reset_frame(latest);
+                            }
+                        }
+                    }
+                    Err(RecvError::Closed) => {
+                        // the bus lives as long as the app, keep the stream open
+                        std::future::pending::<()>().await;
+                    }
+                },
+                _ = self.keep_alive.tick() => return web::Bytes::from_static(b": keep-alive\n\n"),
+            }
+        }
+    }
+}
+
+fn last_event_id(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+}
+
+fn event_stream(
+    bus: EventBus,
+    last_id: Option<u64>,
+) -> impl Stream<Item = Result<web::Bytes, actix_web::Error>> {
+    let (replay, receiver) = bus.subscribe(last_id);
+
+    let mut head = format!("retry: {RETRY_MS}\n\n").into_bytes();
+    let mut last_id = last_id.unwrap_or(0);
+    match replay {
+        Ok(events) => {
+            for event in events {
+                last_id = event.id;
+                head.extend_from_slice(&event.frame());
+            }
+        }
+        Err(Gap { latest }) => {
+            last_id = latest;
+            head.extend_from_slice(&reset_frame(latest));
+        }
+    }
+
+    let mut keep_alive = rt::time::interval(bus.keep_alive);
+    keep_alive.reset();
+    let subscription = Subscription {
+        keep_alive,
+        bus,
+        receiver,
+        last_id,
+    };
+
+    stream::once(async move { Ok(web::Bytes::from(head)) })
+        .chain(stream::unfold(subscription, |mut sub| async move {
+            Some((Ok(sub.next_frame().await), sub))
+        }))
+}
+
+// Server-Sent Events stream of state changes: `counter`, `counter_deleted`
+// and `upload`. It needs no token, so nothing from behind `JwtAuth` (users)
+// is published here. Clients that reconnect with
+// `Last-Event-ID` get the events they missed, or a `reset` event when those
+// are no longer buffered.
+#[utoipa::path(
+    get,
+    path = "/events",
+    params(("last-event-id" = Option<u64>, Header)),
+    responses((status = 200, body = String, content_type = "text/event-stream"))
+)]
+pub async fn events(req: HttpRequest, bus: web::Data<EventBus>) -> HttpResponse {
+    let stream = event_stream(bus.get_ref().clone(), last_event_id(&req));
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(header::CacheControl(vec![CacheDirective::NoCache]))
+        // compressing would hold events back until a block fills up
+        .insert_header(ContentEncoding::Identity)
+        .streaming(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use serde_json::json;
+    use std::pin::pin;
+
+    #[test]
+    fn replays_from_the_buffer() {
+        let bus = EventBus::new(3, Duration::from_secs(15));
+        for value in 1..=5 {
+            bus.publish("counter", &json!({ "value": value }));
+        }
+
+        // ids 3 to 5 are still buffered
+        let ids = |events: Vec<Arc<ServerEvent>>| events.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(bus.since(2).map(ids), Ok(vec![3, 4, 5]));
+        assert_eq!(bus.since(4).map(ids), Ok(vec![5]));
+        assert_eq!(bus.since(5).map(ids), Ok(vec![]));
+        assert_eq!(bus.since(1).map(ids), Err(Gap { latest: 5 }));
+        assert_eq!(bus.since(9).map(ids), Err(Gap { latest: 5 }));
+    }
+
+    #[actix_web::test]
+    async fn streams_and_resumes_events() {
+        let bus = EventBus::new(10, Duration::from_millis(50));
+        bus.publish("counter", &json!({ "name": "a", "value": 1 }));
+        bus.publish("counter", &json!({ "name": "a", "value": 2 }));
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(bus.clone()))
+                .route("/events", web::get().to(events)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/events")
+            .insert_header(("last-event-id", "1"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+
+        let mut body = Box::pin(res.into_body());
+        let mut body = stream::poll_fn(move |cx| body.as_mut().poll_next(cx));
+
+        let head = body.next().await.unwrap().unwrap();
+        assert_eq!(
+            head,
+            "retry: 3000\n\nid: 2\nevent: counter\ndata: {\"name\":\"a\",\"value\":2}\n\n"
+        );
+
+        bus.publish("counter_deleted", &json!({ "name": "a" }));
+        let live = body.next().await.unwrap().unwrap();
+        assert_eq!(
+            live,
+            "id: 3\nevent: counter_deleted\ndata: {\"name\":\"a\"}\n\n"
+        );
+
+        assert_eq!(body.next().await.unwrap().unwrap(), ": keep-alive\n\n");
+    }
+
+    #[actix_web::test]
+    async fn sends_reset_when_events_were_dropped() {
+        let bus = EventBus::new(2, Duration::from_secs(15));
+        for value in 1..=4 {
+            bus.publish("counter", &json!({ "value": value }));
+        }
+
+        let mut stream = pin!(event_stream(bus, Some(1)));
+        let head = stream.next().await.unwrap().unwrap();
+        assert_eq!(head, "retry: 3000\n\nid: 4\nevent: reset\ndata: {}\n\n");
+    }
+}
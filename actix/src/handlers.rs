@@ -1,112 +1,281 @@
-use actix_web::{error, get, post, web, HttpResponse, Responder};
+use actix_web::{get, http::header, post, web, HttpRequest, HttpResponse, Responder};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::config::Limits;
+use crate::error::{ApiError, Problem};
+use crate::negotiate::{Format, Negotiated};
+use crate::people::{PersonRepo, Source};
+use crate::sessions::{Session, CSRF_HEADER};
+use crate::validate::{Rule, Valid, Validate, Validator};
 
 pub fn scoped_config(cfg: &mut web::ServiceConfig) {
-    cfg.service(
-        web::resource("/test").route(web::get().to(|| async { HttpResponse::Ok().body("test") })),
-    );
+    cfg.service(web::resource("/test").route(web::get().to(scoped_test)));
+}
+
+#[utoipa::path(get, path = "/test", responses((status = 200, body = String)))]
+pub async fn scoped_test() -> HttpResponse {
+    HttpResponse::Ok().body("test")
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::resource("/app")
-            .route(web::get().to(|| async { HttpResponse::Ok().body("app") }))
-            .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            .route(web::get().to(app))
+            .route(web::head().to(app_head)),
     );
 }
 
-pub async fn handle_unauthorized() -> HttpResponse {
-    HttpResponse::Unauthorized().body("Unauthorized")
+#[utoipa::path(get, path = "/app", responses((status = 200, body = String)))]
+pub async fn app() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=60"))
+        .body("app")
+}
+
+#[utoipa::path(head, path = "/app", responses((status = 405, response = Problem)))]
+pub async fn app_head() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::MethodNotAllowed)
 }
 
-pub async fn handle_404() -> HttpResponse {
-    HttpResponse::NotFound().body("Not found")
+pub async fn handle_404() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::NotFound(String::from("Not found")))
 }
 
-// deserialize a json body with serde
-#[derive(Deserialize, Serialize)]
+pub const USERNAME: &[Rule] = &[
+    Rule::Required,
+    Rule::Length(1, 32),
+    Rule::Charset("letters, digits, `_`, `-` and `.`", |c| {
+        c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')
+    }),
+];
+
+// deserialize a body with serde, in any of the negotiated formats
+#[derive(Deserialize, Serialize, ToSchema)]
 pub struct Info {
-    username: String,
+    // missing is reported by validation rather than by serde
+    #[serde(default)]
+    pub username: String,
+}
+
+impl Validate for Info {
+    fn rules(&self, v: &mut Validator) {
+        v.check("username", &self.username, USERNAME);
+    }
 }
 
+#[utoipa::path(
+    request_body(content(
+        (Info = "application/json"),
+        (Info = "application/cbor"),
+        (Info = "application/msgpack"),
+        (Info = "application/xml"),
+        (Info = "application/x-www-form-urlencoded"),
+    )),
+    responses(
+        (status = 200, body = String),
+        (status = 400, response = Problem),
+        (status = 413, response = Problem),
+        (status = 415, response = Problem),
+        (status = 422, response = Problem),
+        (status = 500, response = Problem),
+    )
+)]
 #[post("/person/auto")]
-pub async fn person_auto(info: web::Json<Info>) -> actix_web::Result<String> {
-    return Ok(format!("welcome {}!", info.username));
+pub async fn person_auto(
+    repo: web::Data<PersonRepo>,
+    info: Valid<Negotiated<Info>>,
+) -> Result<String, ApiError> {
+    let username = info.username.clone();
+    web::block(move || repo.insert(&username, None, Source::Auto)).await??;
+
+    Ok(format!("welcome {}!", info.username))
 }
 
 // manual deserialization
-#[derive(Serialize, Deserialize)]
-struct Obj {
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Obj {
+    #[serde(default)]
     name: String,
+    // XML has no null, an empty element would not read back as `None`
+    #[serde(skip_serializing_if = "Option::is_none")]
     number: Option<i32>,
 }
 
-const MAX_SIZE: usize = 262_144; //max payload size is 256k
+impl Validate for Obj {
+    fn rules(&self, v: &mut Validator) {
+        v.check("name", &self.name, &[Rule::Required, Rule::Length(1, 64)]);
+        v.check("number", &self.number, &[Rule::Range(0, 1000)]);
+    }
+}
 
+#[utoipa::path(
+    request_body(content(
+        (Obj = "application/json"),
+        (Obj = "application/cbor"),
+        (Obj = "application/msgpack"),
+        (Obj = "application/xml"),
+        (Obj = "application/x-www-form-urlencoded"),
+    )),
+    responses(
+        (status = 200, content(
+            (Obj = "application/json"),
+            (Obj = "application/cbor"),
+            (Obj = "application/msgpack"),
+            (Obj = "application/xml"),
+            (Obj = "application/x-www-form-urlencoded"),
+        )),
+        (status = 400, response = Problem),
+        (status = 406, response = Problem),
+        (status = 413, response = Problem),
+        (status = 415, response = Problem),
+        (status = 422, response = Problem),
+        (status = 500, response = Problem),
+    )
+)]
 #[post("/person/manual")]
 pub async fn person_manual(
+    req: HttpRequest,
+    repo: web::Data<PersonRepo>,
+    limits: web::Data<Limits>,
     mut payload: web::Payload,
-) -> std::result::Result<HttpResponse, actix_web::Error> {
+) -> Result<Negotiated<Obj>, ApiError> {
+    // a body without `Content-Type` is still taken as JSON
+    let format = if req.headers().contains_key(header::CONTENT_TYPE) {
+        Format::of_request(&req)?
+    } else {
+        Format::Json
+    };
+    // checked up front, so a request whose answer can't be sent stores nothing
+    Format::negotiate(&req)?;
+
+    let max_size = limits.payload;
     let mut body = web::BytesMut::new();
     while let Some(chunk) = payload.next().await {
         let chunk = chunk?;
 
-        if (body.len() + chunk.len()) > MAX_SIZE {
-            return Err(error::ErrorBadRequest("overflow"));
+        if (body.len() + chunk.len()) > max_size {
+            return Err(ApiError::PayloadTooLarge { limit: max_size });
         }
 
         body.extend_from_slice(&chunk);
     }
 
-    let obj = serde_json::from_slice::<Obj>(&body)?;
-    return Ok(HttpResponse::Ok().json(obj));
+    let obj = format.decode::<Obj>(&body)?;
+    obj.validate()?;
+
+    let (name, number) = (obj.name.clone(), obj.number);
+    web::block(move || repo.insert(&name, number, Source::Manual)).await??;
+    Ok(Negotiated(obj))
 }
 
 // handling a form
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct FormData {
+    #[serde(default)]
     username: String,
     number: Option<i32>,
+    // the session's token, from `GET /session`; can be sent as the
+    // `X-CSRF-Token` header instead
+    #[serde(default)]
+    csrf_token: Option<String>,
 }
 
+impl Validate for FormData {
+    fn rules(&self, v: &mut Validator) {
+        v.check("username", &self.username, USERNAME);
+        v.check(
+            "number",
+            &self.number,
+            &[Rule::Required, Rule::Range(0, 1000)],
+        );
+    }
+}
+
+#[utoipa::path(
+    request_body(content = FormData, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, body = String),
+        (status = 400, response = Problem),
+        (status = 403, response = Problem),
+        (status = 413, response = Problem),
+        (status = 422, response = Problem),
+    )
+)]
 #[post("/form")]
-pub async fn form(form: web::Form<FormData>) -> HttpResponse {
-    let num = match form.number {
-        Some(n) => n,
-        None => 0,
+pub async fn form(
+    req: HttpRequest,
+    session: Session,
+    form: Valid<web::Form<FormData>>,
+) -> Result<HttpResponse, ApiError> {
+    let token = match &form.csrf_token {
+        Some(token) => Some(token.as_str()),
+        None => req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok()),
     };
+    if !session.check_csrf(token) {
+        return Err(ApiError::Forbidden {
+            detail: String::from("missing or invalid CSRF token"),
+            challenge: None,
+        });
+    }
+
+    // `number` is required, forms without it never get here
+    let num = form.number.unwrap_or_default();
 
-    HttpResponse::Ok().body(format!("Username: {}, Number: {}", form.username, num))
+    Ok(HttpResponse::Ok().body(format!("Username: {}, Number: {}", form.username, num)))
 }
 
 // stream request
+#[utoipa::path(
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses((status = 200))
+)]
 #[get("/stream")]
-pub async fn stream_request(
-    mut body: web::Payload,
-) -> std::result::Result<HttpResponse, actix_web::Error> {
+pub async fn stream_request(mut body: web::Payload) -> Result<HttpResponse, ApiError> {
     let mut bytes = web::BytesMut::new();
 
     while let Some(item) = body.next().await {
         let item = item?;
-        println!("Chunk: {:?}", &item);
+        println!("Chunk: {:?}", item);
         bytes.extend_from_slice(&item);
     }
 
     Ok(HttpResponse::Ok().finish())
 }
 
-// json response
-#[derive(Serialize)]
+// response in the format the client accepts, JSON by default
+#[derive(Serialize, ToSchema)]
 pub struct JsonResp {
     name: String,
 }
 
+#[utoipa::path(responses(
+    (status = 200, content(
+        (JsonResp = "application/json"),
+        (JsonResp = "application/cbor"),
+        (JsonResp = "application/msgpack"),
+        (JsonResp = "application/xml"),
+        (JsonResp = "application/x-www-form-urlencoded"),
+    )),
+    (status = 406, response = Problem),
+))]
 #[get("/json/response/{name}")]
-pub async fn json_response(name: web::Path<String>) -> actix_web::Result<impl Responder> {
+pub async fn json_response(req: HttpRequest, name: web::Path<String>) -> HttpResponse {
     let obj = JsonResp {
         name: name.to_string(),
     };
 
-    Ok(web::Json(obj))
+    let mut res = Negotiated(obj).respond_to(&req);
+    // the same for every request, one per `Accept`
+    if res.status().is_success() {
+        res.headers_mut().insert(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_static("public, max-age=60"),
+        );
+    }
+    res
 }
@@ -0,0 +1,133 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{dev::ServerHandle, rt, web, HttpResponse};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+// Whether this instance should get traffic. It is flipped off as soon as a
+// shutdown starts, so the load balancer stops sending requests while the
+// ones already accepted are finished.
+#[derive(Clone)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn new() -> Self {
+        Readiness(Arc::new(AtomicBool::new(true)))
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.0.store(ready, Ordering::Relaxed);
+    }
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness::new()
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Health {
+    status: &'static str,
+}
+
+// Liveness: the process is up and serving requests.
+#[utoipa::path(get, path = "/healthz", responses((status = 200, body = Health)))]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(Health { status: "ok" })
+}
+
+// Readiness: the process wants traffic.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, body = Health),
+        (status = 503, description = "Shutting down", body = Health),
+    )
+)]
+pub async fn readyz(readiness: web::Data<Readiness>) -> HttpResponse {
+    if readiness.is_ready() {
+        HttpResponse::Ok().json(Health { status: "ready" })
+    } else {
+        HttpResponse::ServiceUnavailable().json(Health { status: "draining" })
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz));
+}
+
+// Resolves on Ctrl-C, or on SIGTERM on unix.
+async fn shutdown_signal() {
+    let ctrl_c = Box::pin(async {
+        let _ = rt::signal::ctrl_c().await;
+    });
+
+    #[cfg(unix)]
+    {
+        use rt::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            let term = Box::pin(async move {
+                term.recv().await;
+            });
+            futures::future::select(ctrl_c, term).await;
+            return;
+        }
+    }
+
+    ctrl_c.await;
+}
+
+// Graceful shutdown for a server started with `disable_signals()`: on the
+// first signal `/readyz` starts failing, and the server is stopped `drain`
+// later, once the load balancer has noticed.
+pub async fn drain_on_signal(server: ServerHandle, readiness: Readiness, drain: Duration) {
+    shutdown_signal().await;
+
+    log::info!("shutting down, draining for {}s", drain.as_secs());
+    readiness.set_ready(false);
+    rt::time::sleep(drain).await;
+
+    server.stop(true).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{http::StatusCode, App};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn readyz_follows_readiness() {
+        let readiness = Readiness::new();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(readiness.clone()))
+                .configure(config),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        readiness.set_ready(false);
+        let res = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body["status"], "draining");
+
+        // liveness does not change while draining
+        let res = call_service(&app, TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,561 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    body::{self, BodySize, EitherBody, MessageBody},
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{self, HeaderMap, HeaderName},
+        Method, StatusCode,
+    },
+    web::{Bytes, BytesMut},
+    Error, HttpMessage, HttpResponse, ResponseError,
+};
+use futures_util::{future::LocalBoxFuture, StreamExt};
+use sha2::{Digest, Sha256};
+
+use crate::config::IdempotencyConfig;
+use crate::error::ApiError;
+use crate::rate_limit;
+use crate::sessions::{Session, CSRF_HEADER};
+use crate::tenants;
+
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+// set on responses that are replays of an earlier one
+const REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+// The client's key, scoped by host, credentials and session so one client
+// can't replay another's responses, nor a tenant another tenant's. Clients
+// with neither are scoped by their address.
+type Key = (String, String);
+
+struct Stored {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Stored {
+    // what `max_bytes` counts
+    fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        headers + self.body.len()
+    }
+}
+
+enum State {
+    // the first request is still being handled
+    Pending,
+    Done(Stored),
+}
+
+struct Record {
+    fingerprint: [u8; 32],
+    state: State,
+    // identifies this record's entry in `Store::expiry`
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Store {
+    records: HashMap<Key, Record>,
+    // in insertion order, which is expiry order since the window is fixed
+    expiry: VecDeque<(Instant, Key)>,
+    // total size of the stored responses
+    bytes: usize,
+}
+
+impl Store {
+    fn remove(&mut self, key: &Key) {
+        if let Some(Record {
+            state: State::Done(stored),
+            ..
+        }) = self.records.remove(key)
+        {
+            self.bytes -= stored.size();
+        }
+    }
+
+    fn purge(&mut self, now: Instant) {
+        while let Some((expires, _)) = self.expiry.front() {
+            if *expires > now {
+                break;
+            }
+            // a key that was dropped and used again has a newer entry, this
+            // one must leave its record alone
+            if let Some((expires, key)) = self.expiry.pop_front() {
+                if self.records.get(&key).is_some_and(|r| r.expires == expires) {
+                    self.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+fn fingerprint(req: &ServiceRequest, body: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str());
+    hasher.update([0]);
+    hasher.update(req.uri().to_string());
+    hasher.update([0]);
+    if let Some(content_type) = req.headers().get(header::CONTENT_TYPE) {
+        hasher.update(content_type.as_bytes());
+    }
+    hasher.update([0]);
+    // a replay must carry the CSRF token the first request was checked with
+    if let Some(token) = req.headers().get(CSRF_HEADER) {
+        hasher.update(token.as_bytes());
+    }
+    hasher.update([0]);
+    hasher.update(body);
+    hasher.finalize().into()
+}
+
+fn scope(req: &ServiceRequest, trusted_proxies: &[IpAddr]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(tenants::host(req.head()).unwrap_or_default());
+    hasher.update([0]);
+    let auth = req.headers().get(header::AUTHORIZATION);
+    if let Some(auth) = auth {
+        hasher.update(auth.as_bytes());
+    }
+    hasher.update([0]);
+    let session = req.extensions().get::<Session>().and_then(Session::scope);
+    if let Some(session) = &session {
+        hasher.update(session);
+    }
+    hasher.update([0]);
+    if auth.is_none() && session.is_none() {
+        if let Some(ip) = rate_limit::client_ip(req, trusted_proxies) {
+            hasher.update(ip.to_string());
+        }
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn replay(stored: &Stored) -> HttpResponse {
+    let mut res = HttpResponse::with_body(stored.status, stored.body.clone());
+    *res.headers_mut() = stored.headers.clone();
+    res.headers_mut()
+        .insert(REPLAYED, header::HeaderValue::from_static("true"));
+    res.map_into_boxed_body()
+}
+
+// Clears the pending record when the first request fails or is dropped
+// before it has a response to store, so the client can retry.
+struct Claim {
+    store: Arc<Mutex<Store>>,
+    key: Option<Key>,
+}
+
+impl Claim {
+    // a response that doesn't fit in `max_bytes` isn't kept, the claim is
+    // dropped instead
+    fn complete(mut self, stored: Stored, max_bytes: usize) {
+        let size = stored.size();
+        let mut store = self.store.lock().unwrap();
+        if store.bytes + size > max_bytes {
+            return;
+        }
+        if let Some(key) = self.key.take() {
+            if let Some(record) = store.records.get_mut(&key) {
+                record.state = State::Done(stored);
+                store.bytes += size;
+            }
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.store.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+// `Idempotency-Key` support for POST: the first response to a key is kept
+// for `window` and replayed for retries of the same request. A retry while
+// the first one is still running gets a 409, the key with another request a
+// 422. Server errors are not kept, those may be retried for real. New keys
+// get a 503 while the store is full. Like `RateLimit`, one instance is shared
+// by every worker.
+#[derive(Clone)]
+pub struct Idempotency {
+    store: Arc<Mutex<Store>>,
+    window: Duration,
+    max_body: usize,
+    max_entries: usize,
+    max_bytes: usize,
+    trusted_proxies: Arc<Vec<IpAddr>>,
+}
+
+impl Idempotency {
+    pub fn new(config: &IdempotencyConfig, trusted_proxies: Vec<IpAddr>) -> Self {
+        Idempotency {
+            store: Arc::new(Mutex::new(Store::default())),
+            window: Duration::from_secs(config.window),
+            max_body: config.max_body,
+            max_entries: config.max_entries,
+            max_bytes: config.max_bytes,
+            trusted_proxies: Arc::new(trusted_proxies),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Idempotency
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IdempotencyMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IdempotencyMiddleware {
+            service: Rc::new(service),
+            idempotency: self.clone(),
+        }))
+    }
+}
+
+pub struct IdempotencyMiddleware<S> {
+    // shared with the response future, which calls it once the body is read
+    service: Rc<S>,
+    idempotency: Idempotency,
+}
+
+impl<S, B> Service<ServiceRequest> for IdempotencyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let key = match req.headers().get(IDEMPOTENCY_KEY) {
+            Some(key) if req.method() == Method::POST => key.to_str().ok().map(str::to_string),
+            _ => {
+                let fut = self.service.call(req);
+                return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+            }
+        };
+
+        let service = Rc::clone(&self.service);
+        let Idempotency {
+            store,
+            window,
+            max_body,
+            max_entries,
+            max_bytes,
+            trusted_proxies,
+        } = self.idempotency.clone();
+
+        Box::pin(async move {
+            let reject = |req: ServiceRequest, err: ApiError| {
+                Ok(req
+                    .into_response(err.error_response())
+                    .map_into_right_body())
+            };
+
+            let key = match key {
+                Some(key) if (1..=255).contains(&key.len()) => key,
+                _ => {
+                    let err = ApiError::BadRequest(String::from(
+                        "Idempotency-Key must be 1 to 255 visible ASCII characters",
+                    ));
+                    return reject(req, err);
+                }
+            };
+
+            let mut payload = req.take_payload();
+            let mut body = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > max_body {
+                    return reject(req, ApiError::PayloadTooLarge { limit: max_body });
+                }
+                body.extend_from_slice(&chunk);
+            }
+            let body = body.freeze();
+            let fingerprint = fingerprint(&req, &body);
+            req.set_payload(Payload::from(body));
+
+            let key = (scope(&req, &trusted_proxies), key);
+            let claim = {
+                let mut table = store.lock().unwrap();
+                let now = Instant::now();
+                table.purge(now);
+
+                match table.records.get(&key) {
+                    Some(record) if record.fingerprint != fingerprint => {
+                        drop(table);
+                        return reject(req, ApiError::IdempotencyKeyReused);
+                    }
+                    Some(Record {
+                        state: State::Done(stored),
+                        ..
+                    }) => {
+                        let res = replay(stored);
+                        drop(table);
+                        return Ok(req.into_response(res).map_into_right_body());
+                    }
+                    Some(Record {
+                        state: State::Pending,
+                        ..
+                    }) => {
+                        drop(table);
+                        let err = ApiError::Conflict(String::from(
+                            "a request with this Idempotency-Key is still being processed",
+                        ));
+                        return reject(req, err);
+                    }
+                    None if table.records.len() >= max_entries || table.bytes >= max_bytes => {
+                        drop(table);
+                        return reject(req, ApiError::IdempotencyStoreFull);
+                    }
+                    None => {
+                        let expires = now + window;
+                        table.records.insert(
+                            key.clone(),
+                            Record {
+                                fingerprint,
+                                state: State::Pending,
+                                expires,
+                            },
+                        );
+                        table.expiry.push_back((expires, key.clone()));
+                        Claim {
+                            store: Arc::clone(&store),
+                            key: Some(key),
+                        }
+                    }
+                }
+            };
+
+            let res = service.call(req).await?;
+
+            let storable = !res.status().is_server_error()
+                && matches!(
+                    res.response().body().size(),
+                    BodySize::Sized(len) if len <= max_body as u64
+                );
+            if !storable {
+                // dropping the claim lets the client retry
+                return Ok(res.map_into_left_body());
+            }
+
+            let (req, res) = res.into_parts();
+            let (head, body) = res.into_parts();
+            let body = body::to_bytes(body)
+                .await
+                .map_err(|err| ApiError::Internal(err.into().to_string()))?;
+            let res = head.set_body(body);
+
+            claim.complete(
+                Stored {
+                    status: res.status(),
+                    headers: res.headers().clone(),
+                    body: res.body().clone(),
+                },
+                max_bytes,
+            );
+
+            Ok(ServiceResponse::new(req, res.map_into_boxed_body()).map_into_right_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[actix_web::test]
+    async fn replays_the_first_response() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = init_service(
+            App::new()
+                .wrap(Idempotency::new(&IdempotencyConfig::default(), Vec::new()))
+                .route(
+                    "/orders",
+                    web::post().to({
+                        let calls = calls.clone();
+                        move |body: String| {
+                            let n = calls.fetch_add(1, Ordering::Relaxed) + 1;
+                            async move {
+                                HttpResponse::Created()
+                                    .insert_header(("x-order", n.to_string()))
+                                    .body(format!("order {n}: {body}"))
+                            }
+                        }
+                    }),
+                )
+                .route(
+                    "/fail",
+                    web::post().to({
+                        let calls = calls.clone();
+                        move || {
+                            calls.fetch_add(1, Ordering::Relaxed);
+                            async { HttpResponse::ServiceUnavailable().finish() }
+                        }
+                    }),
+                ),
+        )
+        .await;
+        let post = |uri: &str, key: &str, body: &'static str| {
+            TestRequest::post()
+                .uri(uri)
+                .insert_header((IDEMPOTENCY_KEY, key))
+                .set_payload(body)
+                .to_request()
+        };
+
+        let res = call_service(&app, post("/orders", "k1", "tea")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert!(!res.headers().contains_key(REPLAYED));
+        assert_eq!(read_body(res).await, "order 1: tea");
+
+        let res = call_service(&app, post("/orders", "k1", "tea")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers().get("x-order").unwrap(), "1");
+        assert_eq!(res.headers().get(REPLAYED).unwrap(), "true");
+        assert_eq!(read_body(res).await, "order 1: tea");
+
+        // same key, different body
+        let res = call_service(&app, post("/orders", "k1", "coffee")).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // the key is per client
+        let req = TestRequest::post()
+            .uri("/orders")
+            .insert_header((IDEMPOTENCY_KEY, "k1"))
+            .insert_header((header::AUTHORIZATION, "Bearer other"))
+            .set_payload("tea")
+            .to_request();
+        assert_eq!(
+            read_body(call_service(&app, req).await).await,
+            "order 2: tea"
+        );
+
+        // server errors are not kept
+        for _ in 0..2 {
+            let res = call_service(&app, post("/fail", "k2", "")).await;
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+    }
+
+    #[actix_web::test]
+    async fn limits_the_store_and_scopes_anonymous_clients() {
+        let config = IdempotencyConfig {
+            max_entries: 2,
+            ..IdempotencyConfig::default()
+        };
+        let app = init_service(
+            App::new()
+                .wrap(Idempotency::new(&config, Vec::new()))
+                .route(
+                    "/orders",
+                    web::post()
+                        .to(|body: String| async move { HttpResponse::Created().body(body) }),
+                ),
+        )
+        .await;
+        let post = |peer: &str, key: &str| {
+            TestRequest::post()
+                .uri("/orders")
+                .peer_addr(peer.parse().unwrap())
+                .insert_header((IDEMPOTENCY_KEY, key))
+                .set_payload("tea")
+                .to_request()
+        };
+
+        // the same key from two addresses is two entries, not a replay
+        let res = call_service(&app, post("10.0.0.1:1000", "k1")).await;
+        assert!(!res.headers().contains_key(REPLAYED));
+        let res = call_service(&app, post("10.0.0.2:1000", "k1")).await;
+        assert!(!res.headers().contains_key(REPLAYED));
+
+        let res = call_service(&app, post("10.0.0.3:1000", "k1")).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        // known keys are still replayed
+        let res = call_service(&app, post("10.0.0.1:1000", "k1")).await;
+        assert_eq!(res.headers().get(REPLAYED).unwrap(), "true");
+
+        // responses past `max_bytes` aren't kept
+        let config = IdempotencyConfig {
+            max_bytes: 2,
+            ..IdempotencyConfig::default()
+        };
+        let idempotency = Idempotency::new(&config, Vec::new());
+        let store = Arc::clone(&idempotency.store);
+        let app = init_service(App::new().wrap(idempotency).route(
+            "/orders",
+            web::post().to(|body: String| async move { HttpResponse::Created().body(body) }),
+        ))
+        .await;
+        for _ in 0..2 {
+            let res = call_service(&app, post("10.0.0.1:1000", "k1")).await;
+            assert!(!res.headers().contains_key(REPLAYED));
+        }
+        let store = store.lock().unwrap();
+        assert!(store.records.is_empty());
+        assert_eq!(store.bytes, 0);
+    }
+
+    #[test]
+    fn forgets_keys_after_the_window() {
+        let mut store = Store::default();
+        let now = Instant::now();
+        let insert = |store: &mut Store, key: &str, secs: u64| {
+            let key = (String::new(), key.to_string());
+            let expires = now + Duration::from_secs(secs);
+            store.records.insert(
+                key.clone(),
+                Record {
+                    fingerprint: [0; 32],
+                    state: State::Pending,
+                    expires,
+                },
+            );
+            store.expiry.push_back((expires, key));
+        };
+        insert(&mut store, "a", 1);
+        insert(&mut store, "b", 2);
+
+        store.purge(now + Duration::from_secs(1));
+        assert_eq!(store.records.len(), 1);
+        assert!(store
+            .records
+            .contains_key(&(String::new(), String::from("b"))));
+
+        // "b" is dropped after a failure and stored again, its first entry
+        // must not expire the new record
+        store.records.clear();
+        insert(&mut store, "b", 5);
+        store.purge(now + Duration::from_secs(2));
+        assert_eq!(store.records.len(), 1);
+        store.purge(now + Duration::from_secs(5));
+        assert!(store.records.is_empty());
+    }
+}
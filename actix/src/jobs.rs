@@ -0,0 +1,594 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::config::JobsConfig;
+use crate::error::{ApiError, Problem};
+use crate::uploads::UploadStore;
+use crate::validate::{Rule, Valid, Validate, Validator};
+
+// How long an idle worker waits before checking again whether the queue is
+// still in use.
+const IDLE: Duration = Duration::from_secs(1);
+
+// Longest `sleep` job, in milliseconds.
+const MAX_SLEEP: i64 = 60_000;
+
+// Work accepted by `POST /jobs`, selected by `type`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Job {
+    // SHA-256 of a finished upload
+    Checksum { upload: String },
+    // waits `ms` milliseconds, handy to try the queue out
+    Sleep { ms: u64 },
+}
+
+impl Validate for Job {
+    fn rules(&self, v: &mut Validator) {
+        match self {
+            Job::Checksum { upload } => v.check(
+                "upload",
+                upload,
+                &[
+                    Rule::Required,
+                    Rule::Length(1, 255),
+                    Rule::Charset("letters, digits, '.', '_' and '-'", |c| {
+                        c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')
+                    }),
+                ],
+            ),
+            Job::Sleep { ms } => v.check("ms", ms, &[Rule::Range(0, MAX_SLEEP)]),
+        }
+    }
+}
+
+impl Job {
+    // Runs one attempt, reporting the percentage done through `progress`.
+    fn run(&self, uploads: &UploadStore, progress: &dyn Fn(u8)) -> Result<Value, String> {
+        match self {
+            Job::Checksum { upload } => {
+                checksum(uploads, upload, progress).map_err(|err| format!("{upload}: {err}"))
+            }
+            Job::Sleep { ms } => {
+                for step in 1..=10 {
+                    thread::sleep(Duration::from_millis(ms * step / 10 - ms * (step - 1) / 10));
+                    progress(step as u8 * 10);
+                }
+                Ok(json!({ "slept_ms": ms }))
+            }
+        }
+    }
+}
+
+fn checksum(uploads: &UploadStore, name: &str, progress: &dyn Fn(u8)) -> io::Result<Value> {
+    let mut file = uploads.open_file(name)?;
+    let size = file.metadata()?.len().max(1);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut read = 0;
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        read += n as u64;
+        progress((read * 100 / size).min(100) as u8);
+    }
+
+    Ok(json!({ "sha256": hex::encode(hasher.finalize()), "size": read }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    // waiting for a worker, or for its next retry
+    Queued,
+    Running,
+    Succeeded,
+    // every attempt failed
+    Failed,
+}
+
+impl Status {
+    fn is_finished(self) -> bool {
+        matches!(self, Status::Succeeded | Status::Failed)
+    }
+}
+
+// A job as reported by `GET /jobs/{id}`, also the format it is saved in.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct JobStatus {
+    pub id: String,
+    pub job: Job,
+    pub status: Status,
+    // attempts started so far
+    pub attempts: u32,
+    // percent done of the current attempt
+    pub progress: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub result: Option<Value>,
+    // why the last attempt failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // unix seconds
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+struct Entry {
+    status: JobStatus,
+    // earliest start of a retry
+    not_before: Option<Instant>,
+}
+
+struct State {
+    jobs: HashMap<String, Entry>,
+    // ids waiting to run, oldest first
+    queue: VecDeque<String>,
+    // ids of finished jobs, oldest first, at most `keep`
+    finished: VecDeque<String>,
+}
+
+struct Inner {
+    path: PathBuf,
+    max_attempts: u32,
+    max_queued: usize,
+    backoff: Duration,
+    keep: usize,
+    uploads: web::Data<UploadStore>,
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+// Jobs run in the background by a pool of worker threads, so handlers never
+// wait for them. Failed attempts are retried with exponential backoff. The
+// queue and the most recent finished jobs are saved to `path` on every
+// change, jobs that were running when the process stopped start over unless
+// that was their last attempt. At most `max_queued` jobs wait or run. One
+// queue created outside `HttpServer::new` is shared by every worker; its
+// threads exit once the last handle is dropped.
+#[derive(Clone)]
+pub struct JobQueue {
+    inner: Arc<Inner>,
+}
+
+impl JobQueue {
+    pub fn open(config: &JobsConfig, uploads: web::Data<UploadStore>) -> io::Result<Self> {
+        let saved: Vec<JobStatus> = match std::fs::read(&config.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        let mut state = State {
+            jobs: HashMap::new(),
+            queue: VecDeque::new(),
+            finished: VecDeque::new(),
+        };
+        for mut status in saved {
+            if !status.status.is_finished() && status.attempts >= config.max_attempts {
+                log::warn!("job {} was stopped on its last attempt", status.id);
+                status.status = Status::Failed;
+                status.progress = 0;
+                status.error = Some(String::from("stopped during the last attempt"));
+                status.updated_at = unix_now();
+            }
+            if status.status.is_finished() {
+                state.finished.push_back(status.id.clone());
+            } else {
+                status.status = Status::Queued;
+                status.progress = 0;
+                state.queue.push_back(status.id.clone());
+            }
+            let entry = Entry {
+                status,
+                not_before: None,
+            };
+            state.jobs.insert(entry.status.id.clone(), entry);
+        }
+        if !state.queue.is_empty() {
+            log::info!(
+                "{} pending jobs restored from {}",
+                state.queue.len(),
+                config.path.display()
+            );
+        }
+
+        let queue = JobQueue {
+            inner: Arc::new(Inner {
+                path: config.path.clone(),
+                max_attempts: config.max_attempts,
+                max_queued: config.max_queued,
+                backoff: Duration::from_millis(config.backoff),
+                keep: config.keep,
+                uploads,
+                state: Mutex::new(state),
+                ready: Condvar::new(),
+            }),
+        };
+        for n in 0..config.workers {
+            let inner = Arc::downgrade(&queue.inner);
+            thread::Builder::new()
+                .name(format!("job-worker-{n}"))
+                .spawn(move || work(inner))?;
+        }
+
+        Ok(queue)
+    }
+
+    // Saves the job before it is acknowledged.
+    pub fn enqueue(&self, job: Job) -> Result<JobStatus, ApiError> {
+        let now = unix_now();
+        let status = JobStatus {
+            id: uuid::Uuid::new_v4().to_string(),
+            job,
+            status: Status::Queued,
+            attempts: 0,
+            progress: 0,
+            result: None,
+            error: None,
+            created_at: now,
+            updated_at: now,
+        };
+
+        let mut state = self.inner.state.lock().unwrap();
+        if state.jobs.len() - state.finished.len() >= self.inner.max_queued {
+            return Err(ApiError::QueueFull(format!(
+                "at most {} jobs can wait or run at once",
+                self.inner.max_queued
+            )));
+        }
+        state.queue.push_back(status.id.clone());
+        state.jobs.insert(
+            status.id.clone(),
+            Entry {
+                status: status.clone(),
+                not_before: None,
+            },
+        );
+        if let Err(err) = self.inner.save(&state) {
+            state.queue.pop_back();
+            state.jobs.remove(&status.id);
+            return Err(err.into());
+        }
+        self.inner.ready.notify_one();
+
+        Ok(status)
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobStatus> {
+        let state = self.inner.state.lock().unwrap();
+        state.jobs.get(id).map(|entry| entry.status.clone())
+    }
+}
+
+fn work(inner: Weak<Inner>) {
+    while let Some(inner) = inner.upgrade() {
+        let Some((id, job)) = inner.next() else {
+            continue;
+        };
+
+        let outcome = job.run(&inner.uploads, &|percent| inner.progress(&id, percent));
+        inner.finish(&id, outcome);
+    }
+}
+
+impl Inner {
+    // Starts the oldest job that is due, or waits a while for one.
+    fn next(&self) -> Option<(String, Job)> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let due = state
+            .queue
+            .iter()
+            .position(|id| state.jobs[id].not_before.is_none_or(|at| at <= now));
+
+        let Some(due) = due else {
+            let wait = state
+                .queue
+                .iter()
+                .filter_map(|id| state.jobs[id].not_before)
+                .min()
+                .map_or(IDLE, |at| at.saturating_duration_since(now).min(IDLE));
+            drop(self.ready.wait_timeout(state, wait).unwrap());
+            return None;
+        };
+
+        let id = state.queue.remove(due)?;
+        let entry = state.jobs.get_mut(&id)?;
+        entry.status.status = Status::Running;
+        entry.status.attempts += 1;
+        entry.status.progress = 0;
+        entry.status.updated_at = unix_now();
+        let job = entry.status.job.clone();
+        if let Err(err) = self.save(&state) {
+            log::error!("could not save jobs to {}: {err}", self.path.display());
+        }
+
+        Some((id, job))
+    }
+
+    fn progress(&self, id: &str, percent: u8) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.jobs.get_mut(id) {
+            entry.status.progress = percent;
+        }
+    }
+
+    fn finish(&self, id: &str, outcome: Result<Value, String>) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let Some(entry) = state.jobs.get_mut(id) else {
+            return;
+        };
+        let status = &mut entry.status;
+        status.updated_at = unix_now();
+
+        match outcome {
+            Ok(result) => {
+                status.status = Status::Succeeded;
+                status.progress = 100;
+                status.result = Some(result);
+                status.error = None;
+            }
+            Err(error) if status.attempts < self.max_attempts => {
+                // 1x, 2x, 4x... the configured backoff
+                let delay = self.backoff * 2u32.pow((status.attempts - 1).min(16));
+                log::warn!("job {id} failed, retrying in {delay:?}: {error}");
+
+                status.status = Status::Queued;
+                status.error = Some(error);
+                entry.not_before = Some(Instant::now() + delay);
+                state.queue.push_back(id.to_string());
+                self.ready.notify_one();
+            }
+            Err(error) => {
+                log::warn!(
+                    "job {id} failed after {} attempts: {error}",
+                    status.attempts
+                );
+
+                status.status = Status::Failed;
+                status.error = Some(error);
+            }
+        }
+
+        if entry.status.status.is_finished() {
+            state.finished.push_back(id.to_string());
+            while state.finished.len() > self.keep {
+                if let Some(old) = state.finished.pop_front() {
+                    state.jobs.remove(&old);
+                }
+            }
+        }
+        if let Err(err) = self.save(state) {
+            log::error!("could not save jobs to {}: {err}", self.path.display());
+        }
+    }
+
+    // Writes every known job to a temp file and swaps it in, finished jobs
+    // first and queued ones last so they are restored in order.
+    fn save(&self, state: &State) -> io::Result<()> {
+        let running = state
+            .jobs
+            .values()
+            .map(|entry| &entry.status)
+            .filter(|status| status.status == Status::Running);
+        let jobs: Vec<&JobStatus> = state
+            .finished
+            .iter()
+            .map(|id| &state.jobs[id].status)
+            .chain(running)
+            .chain(state.queue.iter().map(|id| &state.jobs[id].status))
+            .collect();
+
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&serde_json::to_vec(&jobs)?)?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("").route(web::post().to(enqueue)))
+        .service(
+            web::resource("/{id}")
+                .name("job")
+                .route(web::get().to(get_job)),
+        );
+}
+
+// Queues a job and answers right away; poll the `Location` for its outcome.
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    request_body = Job,
+    responses(
+        (status = 202, body = JobStatus, headers(("location"))),
+        (status = 400, response = Problem),
+        (status = 422, response = Problem),
+        (status = 429, response = Problem),
+        (status = 500, response = Problem),
+        (status = 503, response = Problem),
+    )
+)]
+pub async fn enqueue(
+    req: HttpRequest,
+    queue: web::Data<JobQueue>,
+    job: Valid<web::Json<Job>>,
+) -> Result<HttpResponse, ApiError> {
+    let job = job.0.into_inner();
+    let status = web::block(move || queue.enqueue(job)).await??;
+    let location = req
+        .url_for("job", [&status.id])
+        .map_err(|err| ApiError::Internal(err.to_string()))?;
+
+    Ok(HttpResponse::Accepted()
+        .insert_header((header::LOCATION, location.path()))
+        .json(status))
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = String, Path)),
+    responses(
+        (status = 200, body = JobStatus),
+        (status = 404, response = Problem),
+    )
+)]
+pub async fn get_job(
+    queue: web::Data<JobQueue>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    match queue.get(&id) {
+        Some(status) => Ok(HttpResponse::Ok().json(status)),
+        None => Err(ApiError::NotFound(format!("no job with id {id}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UploadConfig;
+
+    fn open(dir: &std::path::Path, workers: usize) -> JobQueue {
+        open_with(dir, workers, JobsConfig::default().max_queued)
+    }
+
+    fn open_with(dir: &std::path::Path, workers: usize, max_queued: usize) -> JobQueue {
+        let uploads = UploadStore::open(&UploadConfig {
+            dir: dir.join("uploads"),
+            ..UploadConfig::default()
+        })
+        .unwrap();
+        let config = JobsConfig {
+            workers,
+            max_attempts: 2,
+            backoff: 10,
+            path: dir.join("jobs.json"),
+            max_queued,
+            ..JobsConfig::default()
+        };
+
+        JobQueue::open(&config, web::Data::new(uploads)).unwrap()
+    }
+
+    fn wait_until_finished(queue: &JobQueue, id: &str) -> JobStatus {
+        for _ in 0..200 {
+            let status = queue.get(id).unwrap();
+            if status.status.is_finished() {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("job {id} did not finish");
+    }
+
+    #[test]
+    fn runs_and_retries_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = open(dir.path(), 2);
+        std::fs::write(dir.path().join("uploads/a.txt"), b"abc").unwrap();
+
+        let ok = queue
+            .enqueue(Job::Checksum {
+                upload: String::from("a.txt"),
+            })
+            .unwrap();
+        let missing = queue
+            .enqueue(Job::Checksum {
+                upload: String::from("missing.txt"),
+            })
+            .unwrap();
+
+        let ok = wait_until_finished(&queue, &ok.id);
+        assert_eq!(ok.status, Status::Succeeded);
+        assert_eq!(ok.attempts, 1);
+        assert_eq!(ok.progress, 100);
+        assert_eq!(
+            ok.result.unwrap()["sha256"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let missing = wait_until_finished(&queue, &missing.id);
+        assert_eq!(missing.status, Status::Failed);
+        assert_eq!(missing.attempts, 2);
+        assert!(missing.error.unwrap().starts_with("missing.txt: "));
+    }
+
+    #[test]
+    fn pending_jobs_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = open(dir.path(), 0);
+        let job = first.enqueue(Job::Sleep { ms: 0 }).unwrap();
+        drop(first);
+
+        let second = open(dir.path(), 1);
+        assert_eq!(second.get(&job.id).unwrap().job, Job::Sleep { ms: 0 });
+
+        let done = wait_until_finished(&second, &job.id);
+        assert_eq!(done.status, Status::Succeeded);
+        assert_eq!(done.result.unwrap(), json!({ "slept_ms": 0 }));
+    }
+
+    #[test]
+    fn rejects_jobs_beyond_max_queued() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = open_with(dir.path(), 0, 2);
+        queue.enqueue(Job::Sleep { ms: 0 }).unwrap();
+        queue.enqueue(Job::Sleep { ms: 0 }).unwrap();
+
+        let err = queue.enqueue(Job::Sleep { ms: 0 }).unwrap_err();
+        assert!(matches!(err, ApiError::QueueFull(_)));
+    }
+
+    #[test]
+    fn jobs_stopped_on_their_last_attempt_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = open(dir.path(), 0);
+        let last = first.enqueue(Job::Sleep { ms: 0 }).unwrap();
+        let retried = first.enqueue(Job::Sleep { ms: 0 }).unwrap();
+        {
+            let mut state = first.inner.state.lock().unwrap();
+            for (id, attempts) in [(&last.id, 2), (&retried.id, 1)] {
+                let status = &mut state.jobs.get_mut(id).unwrap().status;
+                status.status = Status::Running;
+                status.attempts = attempts;
+            }
+            state.queue.clear();
+            first.inner.save(&state).unwrap();
+        }
+        drop(first);
+
+        let second = open(dir.path(), 0);
+        let last = second.get(&last.id).unwrap();
+        assert_eq!(last.status, Status::Failed);
+        assert_eq!(last.attempts, 2);
+        assert_eq!(second.get(&retried.id).unwrap().status, Status::Queued);
+    }
+}
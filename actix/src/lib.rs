@@ -0,0 +1,472 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::middleware::{self, Condition, Logger};
+use actix_web::{delete, get, HttpResponse};
+use actix_web::{post, web, App, Responder};
+use error::{ApiError, Problem};
+use serde::Serialize;
+use utoipa::ToSchema;
+pub mod audit;
+pub mod auth;
+pub mod cache;
+pub mod chat;
+pub mod config;
+pub mod cors;
+pub mod error;
+pub mod events;
+pub mod handlers;
+pub mod health;
+pub mod idempotency;
+pub mod jobs;
+pub mod metrics;
+pub mod middle_ware;
+pub mod negotiate;
+pub mod openapi;
+pub mod people;
+pub mod rate_limit;
+pub mod sessions;
+pub mod storage;
+pub mod tenants;
+pub mod tls;
+pub mod uploads;
+pub mod users;
+pub mod validate;
+pub mod versions;
+use futures_util::FutureExt;
+
+// This struct represents state
+struct AppState {
+    app_name: String,
+}
+
+// Mutable shared state, backed by a pluggable counter store
+struct AppStateWithCounter {
+    store: Box<dyn storage::CounterStore>,
+}
+
+// name of the counter behind the plain `/counter` endpoint
+const DEFAULT_COUNTER: &str = "default";
+
+#[utoipa::path(responses((status = 200, body = String)))]
+#[get("/")]
+async fn hello(data: web::Data<AppState>) -> String {
+    let app_name = &data.app_name;
+    format!("Hello {app_name}")
+}
+
+#[utoipa::path(responses(
+    (status = 200, body = String),
+    (status = 500, response = Problem),
+))]
+#[get("/counter")]
+async fn counter(
+    data: web::Data<AppStateWithCounter>,
+    bus: web::Data<events::EventBus>,
+) -> Result<String, ApiError> {
+    let counter = web::block(move || data.store.increment(DEFAULT_COUNTER)).await??;
+    bus.publish(
+        "counter",
+        &NamedCounter {
+            name: String::from(DEFAULT_COUNTER),
+            value: counter,
+        },
+    );
+
+    Ok(format!("Request number: {counter}"))
+}
+
+#[derive(Serialize, ToSchema)]
+struct NamedCounter {
+    name: String,
+    value: i64,
+}
+
+#[utoipa::path(responses(
+    (status = 200, body = NamedCounter),
+    (status = 404, response = Problem),
+))]
+#[get("/counter/{name}")]
+async fn get_counter(
+    data: web::Data<AppStateWithCounter>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let name = name.into_inner();
+    let key = name.clone();
+    let value = web::block(move || data.store.get(&key)).await??;
+
+    match value {
+        Some(value) => Ok(HttpResponse::Ok().json(NamedCounter { name, value })),
+        None => Err(ApiError::NotFound(String::from("counter not found"))),
+    }
+}
+
+#[utoipa::path(responses((status = 200, body = NamedCounter)))]
+#[post("/counter/{name}")]
+async fn increment_counter(
+    data: web::Data<AppStateWithCounter>,
+    bus: web::Data<events::EventBus>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let name = name.into_inner();
+    let key = name.clone();
+    let value = web::block(move || data.store.increment(&key)).await??;
+
+    let named = NamedCounter { name, value };
+    bus.publish("counter", &named);
+    Ok(HttpResponse::Ok().json(named))
+}
+
+#[utoipa::path(responses(
+    (status = 204),
+    (status = 404, response = Problem),
+))]
+#[delete("/counter/{name}")]
+async fn delete_counter(
+    data: web::Data<AppStateWithCounter>,
+    bus: web::Data<events::EventBus>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let name = name.into_inner();
+    let key = name.clone();
+    let deleted = web::block(move || data.store.delete(&key)).await??;
+
+    if deleted {
+        bus.publish("counter_deleted", &serde_json::json!({ "name": name }));
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ApiError::NotFound(String::from("counter not found")))
+    }
+}
+
+#[utoipa::path(
+    request_body(content = String, content_type = "text/plain"),
+    responses((status = 200, body = String))
+)]
+#[post("/echo")]
+async fn echo(req_body: String) -> impl Responder {
+    HttpResponse::Ok().body(req_body)
+}
+
+#[utoipa::path(get, path = "/hey", responses((status = 200, body = String)))]
+async fn manual_hello() -> impl Responder {
+    HttpResponse::Ok().body("Hey there!")
+}
+
+// Routes served to each tenant, minus the scopes it doesn't enable. Routes
+// added here or in `server_routes` must also be listed in `openapi::ApiDoc`,
+// whose test checks that every documented route is served.
+fn tenant_routes(
+    jwt_auth: auth::JwtAuth,
+    user_rate_limit: rate_limit::RateLimit,
+    scopes: &[config::Scope],
+) -> impl FnOnce(&mut web::ServiceConfig) + '_ {
+    use config::Scope;
+
+    move |cfg| {
+        cfg.configure(handlers::config)
+            .configure(versions::config)
+            .service(hello)
+            .service(echo)
+            .route("hey", web::get().to(manual_hello))
+            .service(people::list_people)
+            .service(handlers::person_auto)
+            .service(handlers::person_manual)
+            .service(handlers::form)
+            .service(sessions::login)
+            .service(sessions::logout)
+            .service(sessions::get_session)
+            .service(handlers::stream_request)
+            .service(handlers::json_response);
+
+        if scopes.contains(&Scope::Counters) {
+            cfg.service(counter)
+                .service(get_counter)
+                .service(increment_counter)
+                .service(delete_counter);
+        }
+        if scopes.contains(&Scope::Users) {
+            cfg.service(
+                web::scope("/users")
+                    .wrap(user_rate_limit)
+                    .wrap(jwt_auth.clone())
+                    .configure(users::config),
+            );
+        }
+        if scopes.contains(&Scope::Chat) {
+            cfg.service(
+                web::resource("/ws")
+                    .wrap(jwt_auth)
+                    .route(web::get().to(chat::connect)),
+            );
+        }
+        if scopes.contains(&Scope::Uploads) {
+            cfg.service(web::scope("/uploads").configure(uploads::config));
+        }
+        if scopes.contains(&Scope::Events) {
+            cfg.route("/events", web::get().to(events::events));
+        }
+        if scopes.contains(&Scope::Jobs) {
+            cfg.service(web::scope("/jobs").configure(jobs::config));
+        }
+    }
+}
+
+// Routes served on every host, whatever the tenant.
+fn server_routes(cfg: &mut web::ServiceConfig) {
+    cfg.configure(health::config)
+        .route("/metrics", web::get().to(metrics::metrics))
+        .configure(openapi::config);
+}
+
+// A virtual host: its own greeting and counters, served to the requests its
+// guard matches.
+#[derive(Clone)]
+struct Tenant {
+    guard: tenants::HostGuard,
+    scopes: Vec<config::Scope>,
+    state: web::Data<AppState>,
+    counters: web::Data<AppStateWithCounter>,
+    // so `/events` doesn't stream one tenant's changes to another
+    events: web::Data<events::EventBus>,
+}
+
+impl Tenant {
+    fn open(
+        config: &config::TenantConfig,
+        base_domain: Option<&str>,
+        events: &config::EventsConfig,
+    ) -> std::io::Result<Self> {
+        Ok(Tenant {
+            guard: tenants::HostGuard::new(config, base_domain),
+            scopes: config.scopes.clone(),
+            state: web::Data::new(AppState {
+                app_name: config.app_name.clone().unwrap_or(config.name.clone()),
+            }),
+            counters: web::Data::new(AppStateWithCounter {
+                store: storage::from_config(&config.counters)?,
+            }),
+            events: web::Data::new(events::EventBus::from_config(events)),
+        })
+    }
+}
+
+// State shared by every worker. Create it once, outside `HttpServer::new`,
+// and hand it to `build_app` in each worker.
+#[derive(Clone)]
+pub struct SharedState {
+    counters: web::Data<AppStateWithCounter>,
+    users: web::Data<users::UserStore>,
+    people: web::Data<people::PersonRepo>,
+    uploads: web::Data<uploads::UploadStore>,
+    jobs: web::Data<jobs::JobQueue>,
+    jwt_auth: auth::JwtAuth,
+    app_rate_limit: rate_limit::RateLimit,
+    user_rate_limit: rate_limit::RateLimit,
+    metrics: metrics::Metrics,
+    cache: cache::Cache,
+    idempotency: idempotency::Idempotency,
+    cors: cors::Cors,
+    audit: audit::Audit,
+    sessions: sessions::Sessions,
+    accounts: web::Data<sessions::Accounts>,
+    readiness: health::Readiness,
+    chat: web::Data<chat::ChatHub>,
+    events: web::Data<events::EventBus>,
+    tenants: Vec<Tenant>,
+}
+
+impl SharedState {
+    // Opens the counter, people, upload and session stores configured in
+    // `config`, tenants' counters included, and the audit log, and starts the
+    // job workers.
+    pub fn new(config: &config::Config) -> std::io::Result<Self> {
+        // limits under /users sit behind authentication so they can use the
+        // token subject, everything else is limited per IP at the app level
+        let (user_rules, app_rules) = match config.rate_limit.enabled {
+            true => config
+                .rate_limit
+                .rules
+                .iter()
+                .cloned()
+                .partition(|rule| rule.is_under("/users")),
+            false => (Vec::new(), Vec::new()),
+        };
+
+        let metrics = metrics::Metrics::new();
+        let jwt_auth = auth::JwtAuth::from_config(&config.auth)?;
+        let uploads = web::Data::new(uploads::UploadStore::open(&config.uploads)?);
+
+        Ok(SharedState {
+            counters: web::Data::new(AppStateWithCounter {
+                store: storage::from_config(&config.counters)?,
+            }),
+            users: web::Data::new(users::UserStore::new()),
+            people: web::Data::new(people::PersonRepo::from_config(&config.people)?),
+            jobs: web::Data::new(jobs::JobQueue::open(&config.jobs, uploads.clone())?),
+            uploads,
+            audit: audit::Audit::new(&config.audit, jwt_auth.clone())?,
+            sessions: sessions::Sessions::new(&config.sessions, config.tls.enabled())?,
+            accounts: web::Data::new(sessions::Accounts::new(&config.sessions.accounts)),
+            jwt_auth,
+            app_rate_limit: rate_limit::RateLimit::new(
+                app_rules,
+                config.rate_limit.trusted_proxies.clone(),
+            ),
+            user_rate_limit: rate_limit::RateLimit::new(
+                user_rules,
+                config.rate_limit.trusted_proxies.clone(),
+            ),
+            cache: cache::Cache::new(&config.cache, metrics.clone()),
+            metrics,
+            idempotency: idempotency::Idempotency::new(
+                &config.idempotency,
+                config.rate_limit.trusted_proxies.clone(),
+            ),
+            cors: cors::Cors::new(config.cors.policies.clone()),
+            readiness: health::Readiness::new(),
+            chat: web::Data::new(chat::ChatHub::new(chat::ChatSettings::from(&config.ws))),
+            events: web::Data::new(events::EventBus::from_config(&config.events)),
+            tenants: config
+                .tenants
+                .iter()
+                .map(|tenant| Tenant::open(tenant, config.base_domain.as_deref(), &config.events))
+                .collect::<std::io::Result<_>>()?,
+        })
+    }
+
+    // Flag behind `/readyz`, cleared when a shutdown starts.
+    pub fn readiness(&self) -> health::Readiness {
+        self.readiness.clone()
+    }
+}
+
+// Builds the application for one worker: middleware, extractor
+// configuration, state and every route. Each tenant gets a scope guarded by
+// its hosts, with its own state; requests for other hosts get the top-level
+// state or a 404, depending on `unknown_host`.
+pub fn build_app(
+    config: &config::Config,
+    state: &SharedState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let mut app = App::new()
+        // innermost, so it keeps uncompressed bodies that are compressed for
+        // whoever retries
+        .wrap(Condition::new(
+            config.idempotency.enabled,
+            state.idempotency.clone(),
+        ))
+        // NOTE: if you wrap() or wrap_fn() multiple times, the last occurrence will be
+        // executed first.
+        // add comperession middleware
+        .wrap(Condition::new(
+            config.middleware.compress,
+            middleware::Compress::default(),
+        ))
+        // outside compression, so its strong ETags differ between content
+        // codings; its 304s are still counted and logged
+        .wrap(Condition::new(config.cache.enabled, state.cache.clone()))
+        // outside the cache and idempotency, so replayed and cached responses
+        // still get the session's cookie
+        .wrap(state.sessions.clone())
+        .wrap(Condition::new(
+            config.middleware.logger,
+            Logger::new(&config.log.format),
+        ))
+        .wrap(Condition::new(
+            config.middleware.logger,
+            Logger::new(&config.log.agent_format),
+        ))
+        .wrap(state.app_rate_limit.clone())
+        // outside the rate limit, so preflights aren't counted against it and
+        // 429s still carry the CORS headers
+        .wrap(Condition::new(config.cors.enabled, state.cors.clone()))
+        // use wrap_fn to create a small middleware
+        .wrap_fn({
+            let trace = config.middleware.trace;
+            move |req, srv| {
+                if trace {
+                    println!("Hi from start. You requested: {}", req.path());
+                }
+
+                srv.call(req).map(move |res| {
+                    if trace {
+                        println!("Hi from response");
+                    }
+                    res
+                })
+            }
+        })
+        .wrap(Condition::new(
+            config.middleware.metrics,
+            state.metrics.clone(),
+        ))
+        // outside everything that can answer on its own, so rejected requests
+        // are recorded too
+        .wrap(state.audit.clone())
+        // registered last so it runs first and sees the final status and body size
+        .wrap(middle_ware::RequestLog::new(
+            config.middleware.json_log,
+            config.rate_limit.trusted_proxies.clone(),
+        ))
+        .configure(server_routes)
+        .app_data(web::Data::new(AppState {
+            app_name: config.app_name.clone(),
+        }))
+        .app_data(web::Data::new(config.limits.clone()))
+        .app_data(state.counters.clone())
+        .app_data(web::PayloadConfig::new(config.limits.payload))
+        .app_data(
+            web::JsonConfig::default()
+                .limit(config.limits.json)
+                .error_handler(error::json_error),
+        )
+        .app_data(
+            web::FormConfig::default()
+                .limit(config.limits.form)
+                .error_handler(error::form_error),
+        )
+        .app_data(web::QueryConfig::default().error_handler(error::query_error))
+        .app_data(web::PathConfig::default().error_handler(error::path_error))
+        .app_data(state.users.clone())
+        .app_data(state.people.clone())
+        .app_data(state.uploads.clone())
+        .app_data(state.jobs.clone())
+        .app_data(state.accounts.clone())
+        .app_data(web::Data::new(state.metrics.clone()))
+        .app_data(web::Data::new(state.readiness.clone()))
+        .app_data(state.chat.clone())
+        .app_data(state.events.clone())
+        .default_service(web::route().to(handlers::handle_404));
+
+    for tenant in &state.tenants {
+        app = app.service(
+            web::scope("")
+                .guard(tenant.guard.clone())
+                .app_data(tenant.state.clone())
+                .app_data(tenant.counters.clone())
+                .app_data(tenant.events.clone())
+                .configure(tenant_routes(
+                    state.jwt_auth.clone(),
+                    state.user_rate_limit.clone(),
+                    &tenant.scopes,
+                )),
+        );
+    }
+    if config.unknown_host == config::UnknownHost::Default {
+        app = app.configure(tenant_routes(
+            state.jwt_auth.clone(),
+            state.user_rate_limit.clone(),
+            &config::Scope::ALL,
+        ));
+    }
+
+    app
+}
@@ -1,107 +1,99 @@
-use actix_web::dev::Service;
-use actix_web::{get, middleware::Logger, HttpResponse};
-use actix_web::{guard::GuardContext, post, web, App, HttpServer, Responder};
-use std::sync::Mutex;
-mod handlers;
-mod middle_ware;
+use actix::{build_app, config, health, tls, SharedState};
+use actix_web::{rt, HttpServer};
 use env_logger::Env;
-use futures_util::FutureExt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
 
-// This struct represents state
-struct AppState {
-    app_name: String,
-}
-
-// Mutable shared state
-struct AppStateWithCounter {
-    counter: Mutex<i32>,
-}
-
-#[get("/")]
-async fn hello(data: web::Data<AppState>) -> String {
-    let app_name = &data.app_name;
-    format!("Hello {app_name}")
-}
-
-#[get("/counter")]
-async fn counter(data: web::Data<AppStateWithCounter>) -> String {
-    let mut counter = data.counter.lock().unwrap();
-
-    *counter += 1;
-    format!("Request number: {counter}")
-}
-
-#[post("/echo")]
-async fn echo(req_body: String) -> impl Responder {
-    HttpResponse::Ok().body(req_body)
-}
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
 
-#[get("/user1")]
-async fn user_by_id() -> impl Responder {
-    HttpResponse::Ok().body("user 1")
-}
+    env_logger::Builder::from_env(Env::default().default_filter_or(&config.log.level))
+        .format(|buf, record| {
+            // access log lines are already JSON, keep them machine readable
+            if record.target() == "access" {
+                return writeln!(buf, "{}", record.args());
+            }
+            writeln!(
+                buf,
+                "[{} {} {}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        })
+        .init();
 
-async fn manual_hello() -> impl Responder {
-    HttpResponse::Ok().body("Hey there!")
-}
+    let state = SharedState::new(&config)?;
+    let readiness = state.readiness();
 
-// custom guard
-fn verify_token(ctx: &GuardContext) -> bool {
-    let auth_header = ctx.head().headers().get("authorization");
+    let app_config = config.clone();
+    // signals are handled by `health::drain_on_signal` instead
+    let mut server = HttpServer::new(move || build_app(&app_config, &state))
+        .disable_signals()
+        .shutdown_timeout(config.shutdown.timeout);
 
-    if auth_header.is_none() {
-        HttpResponse::Unauthorized().body("access denied");
-        return false;
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
     }
-    return true;
-}
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let count = web::Data::new(AppStateWithCounter {
-        counter: Mutex::new(0),
-    });
 
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
+    let certs = tls::ReloadingCert::from_config(&config.tls)?;
+    let mut redirect = None;
+    match &certs {
+        Some(certs) => {
+            for addr in &config.tls.bind {
+                server = server.bind_rustls_0_23(addr, tls::server_config(certs.clone())?)?;
+            }
+            rt::spawn(tls::watch(
+                certs.clone(),
+                Duration::from_secs(config.tls.reload),
+            ));
 
-    HttpServer::new(move || {
-        let user_scope = web::scope("/users").guard(verify_token).service(user_by_id);
+            if config.tls.redirect {
+                // validated to be addresses
+                let port = config.tls.bind[0].parse::<SocketAddr>().unwrap().port();
+                let mut plain = HttpServer::new(move || tls::redirect_app(port))
+                    .workers(1)
+                    .disable_signals();
+                for addr in &config.bind {
+                    plain = plain.bind(addr)?;
+                }
+                redirect = Some(plain.run());
+            } else {
+                for addr in &config.bind {
+                    server = server.bind(addr)?;
+                }
+            }
+        }
+        None => {
+            for addr in &config.bind {
+                server = server.bind(addr)?;
+            }
+        }
+    }
 
-        App::new()
-            .wrap(Logger::default())
-            .wrap(Logger::new("%a %{User-Agent}i%D"))
-            // NOTE: if you wrap() or wrap_fn() multiple times, the last occurrence will be
-            // executed first.
-            // add comperession middleware
-            .wrap(actix_web::middleware::Compress::default())
-            // use wrap_fn to create a small middleware
-            .wrap_fn(|req, srv| {
-                println!("Hi from start. You requested: {}", req.path());
+    let server = server.run();
+    rt::spawn(health::drain_on_signal(
+        server.handle(),
+        readiness,
+        Duration::from_secs(config.shutdown.drain),
+    ));
 
-                srv.call(req).map(|res| {
-                    println!("Hi from response");
-                    return res;
-                })
-            })
-            .configure(handlers::config)
-            .service(web::scope("/api").configure(handlers::scoped_config))
-            .app_data(web::Data::new(AppState {
-                app_name: String::from("actix web"),
-            }))
-            .app_data(count.clone())
-            .service(counter)
-            .service(hello)
-            .service(echo)
-            .service(user_scope)
-            .route("hey", web::get().to(manual_hello))
-            .default_service(web::route().to(handlers::handle_unauthorized))
-            .default_service(web::route().to(handlers::handle_404))
-            .service(handlers::person_auto)
-            .service(handlers::person_manual)
-            .service(handlers::form)
-            .service(handlers::stream_request)
-            .service(handlers::json_response)
-    })
-    .bind(("0.0.0.0", 8081))?
-    .run()
-    .await
+    let Some(redirect) = redirect else {
+        return server.await;
+    };
+    // the redirect listener goes away together with the app
+    let redirect_handle = redirect.handle();
+    rt::spawn(redirect);
+    let result = server.await;
+    redirect_handle.stop(true).await;
+    result
 }
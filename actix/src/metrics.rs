@@ -0,0 +1,443 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    web::{self, Bytes},
+    Error, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+
+// upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// upper bounds of the payload size buckets, in bytes
+const SIZE_BUCKETS: &[f64] = &[
+    64.0,
+    256.0,
+    1024.0,
+    4096.0,
+    16384.0,
+    65536.0,
+    262144.0,
+    1048576.0,
+    16777216.0,
+    104857600.0,
+];
+
+// label used for requests that did not match any route, so scanners can't
+// create a new series per path
+const UNMATCHED: &str = "unmatched";
+
+struct Histogram {
+    bounds: &'static [f64],
+    // not cumulative, summed up when rendering
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct RouteKey {
+    method: String,
+    route: String,
+}
+
+impl RouteKey {
+    fn labels(&self) -> String {
+        format!(
+            "method=\"{}\",route=\"{}\"",
+            escape(&self.method),
+            escape(&self.route)
+        )
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+// One finished request.
+struct Observation {
+    key: RouteKey,
+    status: u16,
+    latency: f64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    requests: BTreeMap<(RouteKey, u16), u64>,
+    latency: BTreeMap<RouteKey, Histogram>,
+    request_size: BTreeMap<RouteKey, Histogram>,
+    response_size: BTreeMap<RouteKey, Histogram>,
+    // requests being handled right now, by method; the route is not known
+    // yet when a request comes in
+    in_flight: BTreeMap<String, i64>,
+    // response cache outcomes: hit, miss, store, evict, not_modified
+    cache: BTreeMap<&'static str, u64>,
+}
+
+impl Registry {
+    fn record(&mut self, obs: Observation) {
+        *self
+            .requests
+            .entry((obs.key.clone(), obs.status))
+            .or_insert(0) += 1;
+
+        for (histograms, bounds, value) in [
+            (&mut self.latency, LATENCY_BUCKETS, obs.latency),
+            (&mut self.request_size, SIZE_BUCKETS, obs.bytes_in as f64),
+            (&mut self.response_size, SIZE_BUCKETS, obs.bytes_out as f64),
+        ] {
+            histograms
+                .entry(obs.key.clone())
+                .or_insert_with(|| Histogram::new(bounds))
+                .observe(value);
+        }
+    }
+}
+
+// Prometheus metrics middleware and the registry it records into. Like
+// `RateLimit`, the state lives behind an `Arc`, so one instance created
+// outside `HttpServer::new` collects the traffic of every worker.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    // Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP http_requests_total Requests handled, by route and status.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        for ((key, status), count) in &registry.requests {
+            let labels = key.labels();
+            let _ = writeln!(
+                out,
+                "http_requests_total{{{labels},status=\"{status}\"}} {count}"
+            );
+        }
+
+        out.push_str("# HELP http_requests_in_flight Requests currently being handled.\n");
+        out.push_str("# TYPE http_requests_in_flight gauge\n");
+        for (method, count) in &registry.in_flight {
+            let method = escape(method);
+            let _ = writeln!(
+                out,
+                "http_requests_in_flight{{method=\"{method}\"}} {count}"
+            );
+        }
+
+        out.push_str("# HELP http_cache_events_total Response cache lookups and changes.\n");
+        out.push_str("# TYPE http_cache_events_total counter\n");
+        for (event, count) in &registry.cache {
+            let _ = writeln!(out, "http_cache_events_total{{event=\"{event}\"}} {count}");
+        }
+
+        for (name, help, histograms) in [
+            (
+                "http_request_duration_seconds",
+                "Time until the response body was sent.",
+                &registry.latency,
+            ),
+            (
+                "http_request_size_bytes",
+                "Request body size from Content-Length.",
+                &registry.request_size,
+            ),
+            (
+                "http_response_size_bytes",
+                "Response body bytes sent.",
+                &registry.response_size,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} histogram");
+            for (key, histogram) in histograms {
+                histogram.render(&mut out, name, &key.labels());
+            }
+        }
+
+        out
+    }
+
+    pub fn cache_event(&self, event: &'static str) {
+        *self
+            .registry
+            .lock()
+            .unwrap()
+            .cache
+            .entry(event)
+            .or_insert(0) += 1;
+    }
+
+    fn start(&self, method: &str) -> InFlight {
+        let mut registry = self.registry.lock().unwrap();
+        *registry.in_flight.entry(method.to_string()).or_insert(0) += 1;
+
+        InFlight {
+            metrics: self.clone(),
+            method: method.to_string(),
+        }
+    }
+}
+
+// Counts a request as in flight until it is dropped, even when the handler
+// fails or the client goes away.
+struct InFlight {
+    metrics: Metrics,
+    method: String,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut registry = self.metrics.registry.lock().unwrap();
+        if let Some(count) = registry.in_flight.get_mut(&self.method) {
+            *count -= 1;
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Metrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<MeteredBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MetricsMiddleware {
+            service,
+            metrics: self.clone(),
+        }))
+    }
+}
+
+pub struct MetricsMiddleware<S> {
+    service: S,
+    metrics: Metrics,
+}
+
+impl<S, B> Service<ServiceRequest> for MetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<MeteredBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let in_flight = self.metrics.start(req.method().as_str());
+        let bytes_in = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+
+            let observation = Observation {
+                key: RouteKey {
+                    method: res.request().method().to_string(),
+                    route: res
+                        .request()
+                        .match_pattern()
+                        .unwrap_or_else(|| String::from(UNMATCHED)),
+                },
+                status: res.status().as_u16(),
+                latency: 0.0,
+                bytes_in,
+                bytes_out: 0,
+            };
+
+            Ok(res.map_body(move |_, body| MeteredBody {
+                body: body.boxed(),
+                observation: Some(observation),
+                started,
+                in_flight,
+            }))
+        })
+    }
+}
+
+// Response body that counts the bytes sent and records the request when it
+// is dropped, the same way `middle_ware::LoggedBody` writes the access log.
+pub struct MeteredBody {
+    body: BoxBody,
+    observation: Option<Observation>,
+    started: Instant,
+    in_flight: InFlight,
+}
+
+impl MessageBody for MeteredBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.as_mut().get_mut();
+        let chunk = Pin::new(&mut this.body).poll_next(cx);
+
+        if let (Poll::Ready(Some(Ok(bytes))), Some(obs)) = (&chunk, this.observation.as_mut()) {
+            obs.bytes_out += bytes.len() as u64;
+        }
+
+        chunk
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        if let Some(mut obs) = self.observation.take() {
+            obs.latency = self.started.elapsed().as_secs_f64();
+            self.in_flight.metrics.registry.lock().unwrap().record(obs);
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses((status = 200, body = String, content_type = "text/plain; version=0.0.4"))
+)]
+pub async fn metrics(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, init_service, TestRequest};
+    use actix_web::App;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::new(&[1.0, 10.0]);
+        for value in [0.5, 5.0, 5.0, 50.0] {
+            histogram.observe(value);
+        }
+
+        let mut out = String::new();
+        histogram.render(&mut out, "size", "route=\"/\"");
+        assert_eq!(
+            out,
+            "size_bucket{route=\"/\",le=\"1\"} 1\n\
+             size_bucket{route=\"/\",le=\"10\"} 3\n\
+             size_bucket{route=\"/\",le=\"+Inf\"} 4\n\
+             size_sum{route=\"/\"} 60.5\n\
+             size_count{route=\"/\"} 4\n"
+        );
+    }
+
+    #[actix_web::test]
+    async fn records_requests_by_route() {
+        let metrics = Metrics::new();
+        let app = init_service(
+            App::new()
+                .wrap(metrics.clone())
+                .app_data(web::Data::new(metrics.clone()))
+                .route("/items/{id}", web::post().to(|| async { "created" }))
+                .route("/metrics", web::get().to(super::metrics)),
+        )
+        .await;
+
+        for id in 1..=2 {
+            let req = TestRequest::post()
+                .uri(&format!("/items/{id}"))
+                .set_payload("abc")
+                .to_request();
+            call_and_read_body(&app, req).await;
+        }
+        call_and_read_body(&app, TestRequest::get().uri("/nope").to_request()).await;
+
+        let req = TestRequest::get().uri("/metrics").to_request();
+        let body = call_and_read_body(&app, req).await;
+        let text = std::str::from_utf8(&body).unwrap();
+
+        let post = r#"method="POST",route="/items/{id}""#;
+        assert!(text.contains(&format!("http_requests_total{{{post},status=\"200\"}} 2")));
+        assert!(
+            text.contains(r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#)
+        );
+        assert!(text.contains(&format!(
+            "http_request_size_bytes_bucket{{{post},le=\"64\"}} 2"
+        )));
+        assert!(text.contains(&format!("http_response_size_bytes_sum{{{post}}} 14")));
+        assert!(text.contains(&format!("http_request_duration_seconds_count{{{post}}} 2")));
+        // the scrape itself is still running
+        assert!(text.contains(r#"http_requests_in_flight{method="GET"} 1"#));
+        assert!(text.contains(r#"http_requests_in_flight{method="POST"} 0"#));
+    }
+}
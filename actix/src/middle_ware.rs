@@ -1,63 +1,299 @@
+use std::cell::Cell;
 use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
 
 use actix_web::{
-    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error,
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{self, forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    web::Bytes,
+    Error, FromRequest, HttpMessage, HttpRequest,
 };
-use futures_util::future::LocalBoxFuture;
+use futures_util::{future::LocalBoxFuture, Stream, StreamExt};
+use serde::Serialize;
+
+use crate::error::ApiError;
+use crate::rate_limit;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+// The id of the current request, taken from the `X-Request-Id` header when the
+// client (or a proxy in front of us) sent a usable one, generated otherwise.
+// Handlers can take it as an extractor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn from_header(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let usable =
+            !value.is_empty() && value.len() <= 128 && value.bytes().all(|b| b.is_ascii_graphic());
+
+        usable.then(|| RequestId(value.to_string()))
+    }
+
+    fn generate() -> Self {
+        RequestId(uuid::Uuid::new_v4().to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<RequestId>().cloned().ok_or_else(|| {
+            ApiError::Internal(String::from("RequestLog middleware is not registered"))
+        }))
+    }
+}
 
 // There are two steps in middleware processing.
 // 1. Middleware initialization, middleware factory gets called with
 //    next service in chain as parameter.
 // 2. Middleware's call method gets called with normal request.
-pub struct SayHi;
+//
+// `RequestLog` assigns every request a `RequestId`, echoes it back in the
+// `X-Request-Id` response header and writes one JSON line per request to the
+// `access` log target once the response body has been sent. The client
+// address is resolved like `RateLimit` does, trusting `X-Forwarded-For` only
+// from `trusted_proxies`.
+pub struct RequestLog {
+    access_log: bool,
+    trusted_proxies: Arc<Vec<IpAddr>>,
+}
+
+impl RequestLog {
+    // `access_log: false` only assigns and echoes request ids.
+    pub fn new(access_log: bool, trusted_proxies: Vec<IpAddr>) -> Self {
+        RequestLog {
+            access_log,
+            trusted_proxies: Arc::new(trusted_proxies),
+        }
+    }
+}
 
 // Middleware factory is `Transform` trait
 // `S` - type of the next service
 // `B` - type of response's body
-impl<S, B> Transform<S, ServiceRequest> for SayHi
+impl<S, B> Transform<S, ServiceRequest> for RequestLog
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
     S::Future: 'static,
-    B: 'static,
+    B: MessageBody + 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<LoggedBody>;
     type Error = Error;
     type InitError = ();
-    type Transform = SayHiMiddleware<S>;
+    type Transform = RequestLogMiddleware<S>;
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(SayHiMiddleware { service }))
+        ready(Ok(RequestLogMiddleware {
+            service,
+            access_log: self.access_log,
+            trusted_proxies: Arc::clone(&self.trusted_proxies),
+        }))
     }
 }
 
-pub struct SayHiMiddleware<S> {
+pub struct RequestLogMiddleware<S> {
     service: S,
+    access_log: bool,
+    trusted_proxies: Arc<Vec<IpAddr>>,
 }
 
-impl<S, B> Service<ServiceRequest> for SayHiMiddleware<S>
+impl<S, B> Service<ServiceRequest> for RequestLogMiddleware<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
     S::Future: 'static,
-    B: 'static,
+    B: MessageBody + 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<LoggedBody>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     forward_ready!(service);
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        println!("Hi from start. You requested: {}", req.path());
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::generate);
+        req.extensions_mut().insert(request_id.clone());
+
+        // counted as the handler reads the body, so chunked ones are too
+        let bytes_in = Rc::new(Cell::new(0));
+        if self.access_log {
+            let payload = req.take_payload().map({
+                let bytes_in = Rc::clone(&bytes_in);
+                move |chunk| {
+                    if let Ok(bytes) = &chunk {
+                        bytes_in.set(bytes_in.get() + bytes.len() as u64);
+                    }
+                    chunk
+                }
+            });
+            let payload: Pin<Box<dyn Stream<Item = _>>> = Box::pin(payload);
+            req.set_payload(dev::Payload::from(payload));
+        }
+        let client_ip = rate_limit::client_ip(&req, &self.trusted_proxies).map(|ip| ip.to_string());
 
+        let access_log = self.access_log;
         let fut = self.service.call(req);
 
         Box::pin(async move {
-            let res = fut.await?;
+            let mut res = fut.await?;
+
+            if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
 
-            println!("Hi from response");
-            Ok(res)
+            let entry = access_log.then(|| AccessLog {
+                request_id: request_id.0,
+                method: res.request().method().to_string(),
+                path: res.request().path().to_string(),
+                route: res.request().match_pattern(),
+                status: res.status().as_u16(),
+                latency_ms: 0.0,
+                bytes_in: 0,
+                bytes_out: 0,
+                client_ip,
+            });
+
+            Ok(res.map_body(move |_, body| LoggedBody {
+                body: body.boxed(),
+                entry,
+                started,
+                bytes_in,
+            }))
         })
     }
 }
+
+#[derive(Serialize)]
+struct AccessLog {
+    request_id: String,
+    method: String,
+    path: String,
+    route: Option<String>,
+    status: u16,
+    latency_ms: f64,
+    bytes_in: u64,
+    bytes_out: u64,
+    client_ip: Option<String>,
+}
+
+// Response body that counts the bytes sent and writes the access log line
+// when it is dropped, i.e. after the last chunk went out or the client went
+// away.
+pub struct LoggedBody {
+    body: BoxBody,
+    entry: Option<AccessLog>,
+    started: Instant,
+    // request body bytes read so far
+    bytes_in: Rc<Cell<u64>>,
+}
+
+impl MessageBody for LoggedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.as_mut().get_mut();
+        let chunk = Pin::new(&mut this.body).poll_next(cx);
+
+        if let (Poll::Ready(Some(Ok(bytes))), Some(entry)) = (&chunk, this.entry.as_mut()) {
+            entry.bytes_out += bytes.len() as u64;
+        }
+
+        chunk
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.latency_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+            entry.bytes_in = self.bytes_in.get();
+
+            match serde_json::to_string(&entry) {
+                Ok(line) => log::info!(target: "access", "{line}"),
+                Err(err) => log::warn!("could not serialize access log entry: {err}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    async fn show_id(id: RequestId) -> HttpResponse {
+        HttpResponse::Ok().body(id.to_string())
+    }
+
+    #[actix_web::test]
+    async fn generates_and_echoes_request_id() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestLog::new(true, Vec::new()))
+                .route("/", web::get().to(show_id)),
+        )
+        .await;
+
+        let res = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        let header = res.headers().get(REQUEST_ID_HEADER).unwrap().clone();
+        let body = test::read_body(res).await;
+
+        assert_eq!(header.to_str().unwrap().len(), 36);
+        assert_eq!(header.as_bytes(), &body[..]);
+    }
+
+    #[actix_web::test]
+    async fn keeps_incoming_request_id() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestLog::new(true, Vec::new()))
+                .route("/", web::get().to(show_id)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .insert_header((REQUEST_ID_HEADER, "abc-123"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(&body[..], b"abc-123");
+
+        // ids with spaces or control characters are replaced
+        let req = test::TestRequest::get()
+            .insert_header((REQUEST_ID_HEADER, "two words"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_ne!(&body[..], b"two words");
+    }
+}
@@ -0,0 +1,282 @@
+use std::ops::Deref;
+
+use actix_web::{
+    body::BoxBody,
+    dev::Payload,
+    http::header::{self, Header},
+    mime::{self, Mime},
+    web, FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError,
+};
+use futures::{future::LocalBoxFuture, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::config::Limits;
+use crate::error::ApiError;
+
+// Body formats, in the order they are preferred when the client accepts
+// several equally.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Cbor,
+    MessagePack,
+    Xml,
+    Form,
+}
+
+const FORMATS: [Format; 5] = [
+    Format::Json,
+    Format::Cbor,
+    Format::MessagePack,
+    Format::Xml,
+    Format::Form,
+];
+
+impl Format {
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Cbor => "application/cbor",
+            Format::MessagePack => "application/msgpack",
+            Format::Xml => "application/xml",
+            Format::Form => "application/x-www-form-urlencoded",
+        }
+    }
+
+    fn from_mime(mime: &Mime) -> Option<Format> {
+        let format = match (mime.type_().as_str(), mime.subtype().as_str()) {
+            ("application", "json") => Format::Json,
+            ("application", "cbor") => Format::Cbor,
+            ("application", "msgpack" | "x-msgpack" | "vnd.msgpack") => Format::MessagePack,
+            ("application" | "text", "xml") => Format::Xml,
+            ("application", "x-www-form-urlencoded") => Format::Form,
+            _ => match mime.suffix()?.as_str() {
+                "json" => Format::Json,
+                "cbor" => Format::Cbor,
+                "xml" => Format::Xml,
+                _ => return None,
+            },
+        };
+
+        Some(format)
+    }
+
+    // Whether a media range from `Accept` covers this format.
+    fn matches(self, range: &Mime) -> bool {
+        if range.type_() == mime::STAR {
+            return true;
+        }
+        // every format is an `application/*` type, XML is also `text/xml`
+        if range.subtype() == mime::STAR {
+            return range.type_() == mime::APPLICATION
+                || (self == Format::Xml && range.type_() == mime::TEXT);
+        }
+
+        Format::from_mime(range) == Some(self)
+    }
+
+    pub fn encode(self, value: &impl Serialize) -> Result<Vec<u8>, ApiError> {
+        let encoded = match self {
+            Format::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            Format::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf)
+                    .map(|()| buf)
+                    .map_err(|err| err.to_string())
+            }
+            // as a map, so field names survive
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|err| err.to_string()),
+            Format::Xml => quick_xml::se::to_string(value)
+                .map(String::into_bytes)
+                .map_err(|err| err.to_string()),
+            Format::Form => serde_urlencoded::to_string(value)
+                .map(String::into_bytes)
+                .map_err(|err| err.to_string()),
+        };
+
+        encoded.map_err(|err| ApiError::Internal(format!("could not encode {self:?}: {err}")))
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, ApiError> {
+        match self {
+            Format::Json => Ok(serde_json::from_slice(body)?),
+            Format::Cbor => {
+                ciborium::from_reader(body).map_err(|err| ApiError::InvalidBody(err.to_string()))
+            }
+            Format::MessagePack => {
+                rmp_serde::from_slice(body).map_err(|err| ApiError::InvalidBody(err.to_string()))
+            }
+            Format::Xml => quick_xml::de::from_reader(body)
+                .map_err(|err| ApiError::InvalidBody(err.to_string())),
+            Format::Form => serde_urlencoded::from_bytes(body)
+                .map_err(|err| ApiError::InvalidForm(err.to_string())),
+        }
+    }
+
+    // The format of a request body, from its `Content-Type`.
+    pub fn of_request(req: &HttpRequest) -> Result<Format, ApiError> {
+        let unsupported = || {
+            let supported: Vec<_> = FORMATS.iter().map(|f| f.media_type()).collect();
+            ApiError::UnsupportedMediaType(format!("expected one of {}", supported.join(", ")))
+        };
+
+        match req.mime_type() {
+            Ok(Some(mime)) => Format::from_mime(&mime).ok_or_else(unsupported),
+            _ => Err(unsupported()),
+        }
+    }
+
+    // The format to answer in, from `Accept`. JSON when the client has no
+    // preference.
+    pub fn negotiate(req: &HttpRequest) -> Result<Format, ApiError> {
+        if !req.headers().contains_key(header::ACCEPT) {
+            return Ok(Format::Json);
+        }
+        let Ok(accept) = header::Accept::parse(req) else {
+            return Ok(Format::Json);
+        };
+
+        let ranges: Vec<_> = accept
+            .iter()
+            .filter(|range| range.quality > header::Quality::ZERO)
+            .cloned()
+            .collect();
+        header::Accept(ranges)
+            .ranked()
+            .iter()
+            .find_map(|range| FORMATS.into_iter().find(|f| f.matches(range)))
+            .ok_or_else(|| {
+                ApiError::NotAcceptable(format!(
+                    "{} is not available as {}",
+                    req.path(),
+                    req.headers()
+                        .get(header::ACCEPT)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default()
+                ))
+            })
+    }
+}
+
+// A body decoded from, or a value encoded to, whichever format the client
+// asked for: `Content-Type` picks the decoder and `Accept` the encoder.
+pub struct Negotiated<T>(pub T);
+
+impl<T> Deref for Negotiated<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Negotiated<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let format = Format::of_request(req);
+        let limits = req.app_data::<web::Data<Limits>>().cloned();
+        let mut payload = payload.take();
+
+        Box::pin(async move {
+            let format = format?;
+            let limit = match (format, limits) {
+                (Format::Form, Some(limits)) => limits.form,
+                (_, Some(limits)) => limits.json,
+                (_, None) => Limits::default().json,
+            };
+
+            let mut body = web::BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk.map_err(ApiError::from)?;
+                if body.len() + chunk.len() > limit {
+                    return Err(ApiError::PayloadTooLarge { limit }.into());
+                }
+                body.extend_from_slice(&chunk);
+            }
+
+            Ok(Negotiated(format.decode(&body)?))
+        })
+    }
+}
+
+impl<T: Serialize> Responder for Negotiated<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        let encoded =
+            Format::negotiate(req).and_then(|format| Ok((format, format.encode(&self.0)?)));
+
+        match encoded {
+            Ok((format, body)) => HttpResponse::Ok()
+                .content_type(format.media_type())
+                .insert_header((header::VARY, "accept"))
+                .body(body),
+            Err(err) => err.error_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Obj {
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        number: Option<i32>,
+    }
+
+    fn accept(value: &str) -> Result<Format, ApiError> {
+        let req = TestRequest::default()
+            .insert_header((header::ACCEPT, value))
+            .to_http_request();
+        Format::negotiate(&req)
+    }
+
+    #[test]
+    fn picks_the_preferred_format() {
+        assert_eq!(accept("application/cbor").unwrap(), Format::Cbor);
+        assert_eq!(accept("*/*").unwrap(), Format::Json);
+        assert_eq!(
+            accept("application/json;q=0.5, application/msgpack").unwrap(),
+            Format::MessagePack
+        );
+        assert_eq!(accept("text/*, application/json").unwrap(), Format::Json);
+        assert_eq!(accept("text/*").unwrap(), Format::Xml);
+        assert_eq!(accept("application/problem+json").unwrap(), Format::Json);
+        assert!(matches!(
+            accept("text/html, application/json;q=0"),
+            Err(ApiError::NotAcceptable(_))
+        ));
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(Format::negotiate(&req).unwrap(), Format::Json);
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        let values = [
+            Obj {
+                name: String::from("ferris"),
+                number: Some(7),
+            },
+            Obj {
+                name: String::from("crab"),
+                number: None,
+            },
+        ];
+
+        for format in FORMATS {
+            for value in &values {
+                let encoded = format.encode(value).unwrap();
+                let decoded: Obj = format.decode(&encoded).unwrap();
+                assert_eq!(&decoded, value, "{format:?}");
+            }
+        }
+    }
+}
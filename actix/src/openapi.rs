@@ -0,0 +1,184 @@
+use actix_web::web;
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::error::Problem;
+use crate::versions::VERSIONS;
+
+// OpenAPI 3.1 description of the routes in `tenant_routes` and
+// `server_routes`. Request and response schemas come from the
+// `#[utoipa::path]` attribute on each handler and the `ToSchema` derives on
+// the types they use.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "actix", description = "Example actix-web service"),
+    paths(
+        crate::hello,
+        crate::counter,
+        crate::get_counter,
+        crate::increment_counter,
+        crate::delete_counter,
+        crate::echo,
+        crate::manual_hello,
+        crate::versions::list_versions,
+        crate::handlers::app,
+        crate::handlers::app_head,
+        crate::people::list_people,
+        crate::handlers::person_auto,
+        crate::handlers::person_manual,
+        crate::handlers::form,
+        crate::sessions::login,
+        crate::sessions::logout,
+        crate::sessions::get_session,
+        crate::handlers::stream_request,
+        crate::handlers::json_response,
+        crate::users::list_users,
+        crate::users::create_user,
+        crate::users::get_user,
+        crate::users::update_user,
+        crate::users::delete_user,
+        crate::uploads::upload_multipart,
+        crate::uploads::upload,
+        crate::uploads::download,
+        crate::uploads::status,
+        crate::jobs::enqueue,
+        crate::jobs::get_job,
+        crate::health::healthz,
+        crate::health::readyz,
+        crate::metrics::metrics,
+        crate::chat::connect,
+        crate::events::events,
+    ),
+    components(schemas(Problem), responses(Problem)),
+    modifiers(&BearerAuth, &Versions)
+)]
+pub struct ApiDoc;
+
+// The JWT bearer scheme that `/users` and `/ws` refer to as `bearer`.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                Http::builder()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
+
+// The routes of every API version, see `versions::Version::openapi`.
+struct Versions;
+
+impl Modify for Versions {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for version in VERSIONS {
+            openapi.merge(version.openapi());
+        }
+    }
+}
+
+// Serves the document at `/openapi.json` and Swagger UI at `/docs/`. The UI
+// assets are compiled into the binary, so the page works without access to a
+// CDN.
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(SwaggerUi::new("/docs/{_:.*}").url("/openapi.json", ApiDoc::openapi()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::JwtAuth, config::Scope, rate_limit::RateLimit, server_routes, tenant_routes,
+    };
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    // The documented path with every `{param}` filled in.
+    fn concrete(path: &str) -> String {
+        let mut concrete = String::new();
+        let mut rest = path;
+        while let Some((before, after)) = rest.split_once('{') {
+            concrete.push_str(before);
+            concrete.push('1');
+            rest = after.split_once('}').map(|(_, after)| after).unwrap_or("");
+        }
+        concrete.push_str(rest);
+        concrete
+    }
+
+    // Sends a request for every documented operation. Without app data the
+    // handlers mostly fail with 500, but only an unregistered route gives a
+    // 404. Routes under `/api` are also tried with each version's header.
+    #[actix_web::test]
+    async fn every_documented_route_is_served() {
+        let app = init_service(App::new().configure(server_routes).configure(tenant_routes(
+            JwtAuth::new(b"0123456789abcdef", "actix"),
+            RateLimit::new(Vec::new(), Vec::new()),
+            &Scope::ALL,
+        )))
+        .await;
+
+        let spec = ApiDoc::openapi();
+        assert!(spec.paths.paths.contains_key("/users/{id}"));
+
+        let mut missing = Vec::new();
+        for (path, item) in &spec.paths.paths {
+            let operations = [
+                (Method::GET, &item.get),
+                (Method::PUT, &item.put),
+                (Method::POST, &item.post),
+                (Method::DELETE, &item.delete),
+                (Method::HEAD, &item.head),
+                (Method::PATCH, &item.patch),
+            ];
+            for (method, _) in operations.iter().filter(|(_, op)| op.is_some()) {
+                let versions = std::iter::once(None)
+                    .chain(VERSIONS.iter().map(|v| Some(v.number.to_string())));
+                let mut served = false;
+                for version in versions {
+                    let mut req = TestRequest::default()
+                        .method(method.clone())
+                        .uri(&concrete(path));
+                    if let Some(version) = version {
+                        req = req.insert_header(("accept-version", version));
+                    }
+                    if call_service(&app, req.to_request()).await.status() != StatusCode::NOT_FOUND
+                    {
+                        served = true;
+                        break;
+                    }
+                }
+                if !served {
+                    missing.push(format!("{method} {path}"));
+                }
+            }
+        }
+        assert!(
+            missing.is_empty(),
+            "documented routes that are not served: {missing:?}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn serves_document_and_docs_page() {
+        let app = init_service(App::new().configure(config)).await;
+
+        let req = TestRequest::get().uri("/openapi.json").to_request();
+        let doc: Value = read_body_json(call_service(&app, req).await).await;
+        assert!(doc["openapi"].as_str().unwrap().starts_with("3.1"));
+        assert!(doc["components"]["schemas"]["Info"].is_object());
+
+        let req = TestRequest::get().uri("/docs/").to_request();
+        let res = call_service(&app, req).await;
+        assert!(res.status().is_success());
+    }
+}
@@ -0,0 +1,517 @@
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{get, http::header, post, web, HttpRequest, HttpResponse};
+use rusqlite::{params_from_iter, types::Value, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::config::PeopleConfig;
+use crate::error::{ApiError, Problem};
+use crate::negotiate::Negotiated;
+use crate::validate::{Rule, Valid, Validate, Validator};
+
+// Schema changes, applied in order at startup. `PRAGMA user_version` holds
+// how many have run; only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE people (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        number INTEGER,
+        source TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    )",
+    // NOCASE so sorting and paging by name ignore case
+    "CREATE INDEX people_name ON people (name COLLATE NOCASE, id)",
+];
+
+fn storage(err: rusqlite::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+// The endpoint a person was posted to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Auto,
+    Manual,
+}
+
+impl Source {
+    fn as_str(self) -> &'static str {
+        match self {
+            Source::Auto => "auto",
+            Source::Manual => "manual",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Person {
+    id: i64,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    number: Option<i32>,
+    source: Source,
+    // unix seconds
+    created_at: i64,
+}
+
+impl Person {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
+        let source: String = row.get("source")?;
+
+        Ok(Person {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            number: row.get("number")?,
+            source: match source.as_str() {
+                "manual" => Source::Manual,
+                _ => Source::Auto,
+            },
+            created_at: row.get("created_at")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortKey {
+    // submission order
+    Created,
+    Name,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sort {
+    key: SortKey,
+    descending: bool,
+}
+
+impl Sort {
+    fn parse(value: &str) -> Result<Self, ApiError> {
+        let (descending, key) = match value.strip_prefix('-') {
+            Some(key) => (true, key),
+            None => (false, value),
+        };
+        let key = match key {
+            "created" => SortKey::Created,
+            "name" => SortKey::Name,
+            _ => {
+                return Err(ApiError::InvalidQuery(format!(
+                    "unknown sort {value:?}, expected created or name, optionally prefixed with -"
+                )))
+            }
+        };
+
+        Ok(Sort { key, descending })
+    }
+
+    fn as_str(self) -> &'static str {
+        match (self.key, self.descending) {
+            (SortKey::Created, false) => "created",
+            (SortKey::Created, true) => "-created",
+            (SortKey::Name, false) => "name",
+            (SortKey::Name, true) => "-name",
+        }
+    }
+}
+
+// Where a page ends: the sort it was made for and the sort key of its last
+// row. Handed to clients as opaque hex.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    name: Option<String>,
+    id: i64,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(value: &str, sort: Sort) -> Result<Self, ApiError> {
+        let invalid = || ApiError::InvalidQuery(String::from("invalid cursor"));
+        let bytes = hex::decode(value).map_err(|_| invalid())?;
+        let cursor: Cursor = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+
+        if cursor.sort != sort.as_str() || (sort.key == SortKey::Name) != cursor.name.is_some() {
+            return Err(ApiError::InvalidQuery(String::from(
+                "the cursor was made for another sort order",
+            )));
+        }
+        Ok(cursor)
+    }
+}
+
+// A page request, already checked.
+#[derive(Debug)]
+pub struct Filter {
+    name: Option<String>,
+    sort: Sort,
+    after: Option<Cursor>,
+    limit: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PersonPage {
+    items: Vec<Person>,
+    // cursor for the next page, absent on the last one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
+// Escapes `%`, `_` and the escape character itself for a LIKE pattern.
+fn like_pattern(needle: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in needle.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+// Every person posted to `/person/auto` and `/person/manual`, in an embedded
+// SQLite database. The single connection is shared by all workers; callers
+// run the methods on the blocking pool.
+pub struct PersonRepo {
+    conn: Mutex<Connection>,
+}
+
+impl PersonRepo {
+    // Opens (or creates) the database at `path` and brings its schema up to
+    // date. `:memory:` keeps everything in memory.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut conn = Connection::open(path).map_err(storage)?;
+        migrate(&mut conn).map_err(storage)?;
+
+        Ok(PersonRepo {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn from_config(config: &PeopleConfig) -> io::Result<Self> {
+        PersonRepo::open(&config.path)
+    }
+
+    pub fn insert(&self, name: &str, number: Option<i32>, source: Source) -> io::Result<Person> {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO people (name, number, source, created_at) VALUES (?1, ?2, ?3, ?4)",
+            (name, number, source.as_str(), created_at),
+        )
+        .map_err(storage)?;
+
+        Ok(Person {
+            id: conn.last_insert_rowid(),
+            name: name.to_string(),
+            number,
+            source,
+            created_at,
+        })
+    }
+
+    pub fn get(&self, id: i64) -> io::Result<Option<Person>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT * FROM people WHERE id = ?1", [id], Person::from_row)
+            .optional()
+            .map_err(storage)
+    }
+
+    // One page, using the sort key of the previous page's last row rather
+    // than an offset, so rows added meanwhile don't shift pages.
+    pub fn list(&self, filter: &Filter) -> io::Result<PersonPage> {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+
+        if let Some(name) = &filter.name {
+            conditions.push("name LIKE ? ESCAPE '\\'");
+            params.push(Value::Text(like_pattern(name)));
+        }
+        let (comparison, direction) = match filter.sort.descending {
+            true => ("<", "DESC"),
+            false => (">", "ASC"),
+        };
+        let (keyset, order) = match filter.sort.key {
+            SortKey::Created => (format!("id {comparison} ?"), format!("id {direction}")),
+            SortKey::Name => (
+                format!("(name COLLATE NOCASE, id) {comparison} (?, ?)"),
+                format!("name COLLATE NOCASE {direction}, id {direction}"),
+            ),
+        };
+        if let Some(cursor) = &filter.after {
+            conditions.push(&keyset);
+            if let Some(name) = &cursor.name {
+                params.push(Value::Text(name.clone()));
+            }
+            params.push(Value::Integer(cursor.id));
+        }
+
+        let mut sql = String::from("SELECT * FROM people");
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(&format!(" ORDER BY {order} LIMIT ?"));
+        // one more than asked, to know whether there is a next page
+        params.push(Value::Integer(filter.limit as i64 + 1));
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql).map_err(storage)?;
+        let mut items = stmt
+            .query_map(params_from_iter(params), Person::from_row)
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(storage)?;
+
+        let next = (items.len() > filter.limit).then(|| {
+            items.truncate(filter.limit);
+            let last = &items[items.len() - 1];
+            Cursor {
+                sort: filter.sort.as_str().to_string(),
+                name: (filter.sort.key == SortKey::Name).then(|| last.name.clone()),
+                id: last.id,
+            }
+            .encode()
+        });
+
+        Ok(PersonPage { items, next })
+    }
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let applied: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let applied = applied as usize;
+    if applied >= MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", version as i64 + 1)?;
+        log::info!("people database migrated to version {}", version + 1);
+    }
+    tx.commit()
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PersonQuery {
+    // case-insensitive substring match
+    name: Option<String>,
+    // `created` (the default) or `name`, prefixed with `-` for descending
+    sort: Option<String>,
+    limit: Option<usize>,
+    // `next` from the previous page
+    cursor: Option<String>,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+impl PersonQuery {
+    fn filter(self) -> Result<Filter, ApiError> {
+        let sort = Sort::parse(self.sort.as_deref().unwrap_or("created"))?;
+        let after = self
+            .cursor
+            .map(|cursor| Cursor::decode(&cursor, sort))
+            .transpose()?;
+
+        Ok(Filter {
+            name: self.name.filter(|name| !name.is_empty()),
+            sort,
+            after,
+            limit: self
+                .limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+        })
+    }
+}
+
+#[utoipa::path(
+    params(PersonQuery),
+    responses(
+        (status = 200, body = PersonPage),
+        (status = 400, response = Problem),
+        (status = 500, response = Problem),
+    )
+)]
+#[get("/person")]
+pub async fn list_people(
+    repo: web::Data<PersonRepo>,
+    query: web::Query<PersonQuery>,
+) -> Result<HttpResponse, ApiError> {
+    page(repo, query).await
+}
+
+async fn page(
+    repo: web::Data<PersonRepo>,
+    query: web::Query<PersonQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let filter = query.into_inner().filter()?;
+    let page = web::block(move || repo.list(&filter)).await??;
+
+    Ok(HttpResponse::Ok().json(page))
+}
+
+// Version 2 of the API treats people as a resource: listed and created at
+// `/people`, read back at `/people/{id}`.
+#[utoipa::path(
+    params(PersonQuery),
+    responses(
+        (status = 200, body = PersonPage),
+        (status = 400, response = Problem),
+        (status = 500, response = Problem),
+    )
+)]
+#[get("/people")]
+pub async fn get_people(
+    repo: web::Data<PersonRepo>,
+    query: web::Query<PersonQuery>,
+) -> Result<HttpResponse, ApiError> {
+    page(repo, query).await
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewPerson {
+    #[serde(default)]
+    name: String,
+    number: Option<i32>,
+}
+
+impl Validate for NewPerson {
+    fn rules(&self, v: &mut Validator) {
+        v.check("name", &self.name, &[Rule::Required, Rule::Length(1, 64)]);
+        v.check("number", &self.number, &[Rule::Range(0, 1000)]);
+    }
+}
+
+#[utoipa::path(
+    request_body(content(
+        (NewPerson = "application/json"),
+        (NewPerson = "application/cbor"),
+        (NewPerson = "application/msgpack"),
+        (NewPerson = "application/xml"),
+        (NewPerson = "application/x-www-form-urlencoded"),
+    )),
+    responses(
+        (status = 201, body = Person, headers(("location"))),
+        (status = 400, response = Problem),
+        (status = 413, response = Problem),
+        (status = 415, response = Problem),
+        (status = 422, response = Problem),
+        (status = 500, response = Problem),
+    )
+)]
+#[post("/people")]
+pub async fn create_person(
+    req: HttpRequest,
+    repo: web::Data<PersonRepo>,
+    person: Valid<Negotiated<NewPerson>>,
+) -> Result<HttpResponse, ApiError> {
+    let (name, number) = (person.name.clone(), person.number);
+    let person = web::block(move || repo.insert(&name, number, Source::Manual)).await??;
+    // relative to where the request was routed, `/api/v2/people` or `/api/people`
+    let location = format!("{}/{}", req.path().trim_end_matches('/'), person.id);
+
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, location))
+        .json(person))
+}
+
+#[utoipa::path(
+    params(("id" = i64, Path)),
+    responses(
+        (status = 200, body = Person),
+        (status = 404, response = Problem),
+        (status = 500, response = Problem),
+    )
+)]
+#[get("/people/{id}")]
+pub async fn get_person(
+    repo: web::Data<PersonRepo>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    match web::block(move || repo.get(id)).await?? {
+        Some(person) => Ok(HttpResponse::Ok().json(person)),
+        None => Err(ApiError::NotFound(format!("no person with id {id}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(repo: &PersonRepo, query: &str) -> PersonPage {
+        let query: PersonQuery = serde_urlencoded::from_str(query).unwrap();
+        repo.list(&query.filter().unwrap()).unwrap()
+    }
+
+    fn names(page: &PersonPage) -> Vec<&str> {
+        page.items.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn filters_sorts_and_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = PersonRepo::open(dir.path().join("people.db")).unwrap();
+        for name in ["carol", "alice", "Bob", "dave", "al_bundy"] {
+            repo.insert(name, None, Source::Auto).unwrap();
+        }
+        repo.insert("alfred", Some(7), Source::Manual).unwrap();
+
+        let first = page(&repo, "sort=name&limit=4");
+        assert_eq!(names(&first), ["al_bundy", "alfred", "alice", "Bob"]);
+        let next = first.next.unwrap();
+        let second = page(&repo, &format!("sort=name&limit=4&cursor={next}"));
+        assert_eq!(names(&second), ["carol", "dave"]);
+        assert!(second.next.is_none());
+
+        assert_eq!(
+            names(&page(&repo, "sort=-created&limit=2")),
+            ["alfred", "al_bundy"]
+        );
+        // `_` is not a wildcard
+        assert_eq!(names(&page(&repo, "name=L_&sort=-name")), ["al_bundy"]);
+        assert_eq!(
+            names(&page(&repo, "name=AL")),
+            ["alice", "al_bundy", "alfred"]
+        );
+
+        let query: PersonQuery =
+            serde_urlencoded::from_str(&format!("sort=created&cursor={next}")).unwrap();
+        assert!(matches!(query.filter(), Err(ApiError::InvalidQuery(_))));
+    }
+
+    #[test]
+    fn migrates_once_and_keeps_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("people.db");
+
+        PersonRepo::open(&path)
+            .unwrap()
+            .insert("ferris", Some(3), Source::Manual)
+            .unwrap();
+        let repo = PersonRepo::open(&path).unwrap();
+        let stored = &page(&repo, "").items[0];
+        assert_eq!((stored.name.as_str(), stored.number), ("ferris", Some(3)));
+        assert_eq!(stored.source, Source::Manual);
+
+        let conn = repo.conn.lock().unwrap();
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderMap, HeaderName, HeaderValue, X_FORWARDED_FOR},
+    Error, HttpMessage, ResponseError,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::Deserialize;
+
+use crate::auth::Claims;
+use crate::error::ApiError;
+
+// The peer address, or when the peer is a trusted proxy, the last address in
+// `X-Forwarded-For` that isn't one. Entries left of that were written by the
+// client and can be anything. The access log uses it too.
+pub fn client_ip(req: &ServiceRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<_> = req
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    let client = forwarded
+        .iter()
+        .rev()
+        .map(|addr| addr.trim().parse::<IpAddr>())
+        .find(|ip| !ip.as_ref().is_ok_and(|ip| trusted_proxies.contains(ip)));
+
+    match client {
+        Some(Ok(ip)) => Some(ip),
+        // garbage where the client should be, keep the proxy's address
+        Some(Err(_)) | None => Some(peer),
+    }
+}
+
+// Time source for the buckets, so tests can move time forward by hand.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// A rate limit for every path starting with `path`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub path: String,
+    // burst size, and the most tokens a bucket can hold
+    pub capacity: u32,
+    // tokens added back every second
+    pub per_second: f64,
+}
+
+impl Rule {
+    fn matches(&self, path: &str) -> bool {
+        within(path, &self.path)
+    }
+
+    // Whether the rule only covers paths under `scope`, e.g. `/users`.
+    pub fn is_under(&self, scope: &str) -> bool {
+        within(&self.path, scope)
+    }
+}
+
+// `path` is `prefix` or below it, whole segments only.
+fn within(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix.trim_end_matches('/')) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Outcome of taking a token, used to fill in the `X-RateLimit-*` headers.
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // seconds until the bucket is full again
+    pub reset: u64,
+    // seconds until the next token, only set when the request was rejected
+    pub retry_after: Option<u64>,
+}
+
+// how often full buckets are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Buckets {
+    clients: HashMap<String, Bucket>,
+    swept: Instant,
+}
+
+struct Limiter {
+    rule: Rule,
+    buckets: Mutex<Buckets>,
+}
+
+impl Limiter {
+    fn new(rule: Rule, now: Instant) -> Self {
+        Limiter {
+            rule,
+            buckets: Mutex::new(Buckets {
+                clients: HashMap::new(),
+                swept: now,
+            }),
+        }
+    }
+
+    fn take(&self, key: &str, now: Instant) -> Decision {
+        let capacity = f64::from(self.rule.capacity);
+        let rate = self.rule.per_second;
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if now.saturating_duration_since(buckets.swept) >= SWEEP_INTERVAL {
+            // a bucket that has refilled completely carries no state
+            buckets
+                .clients
+                .retain(|_, b| b.tokens + elapsed(b.updated, now) * rate < capacity);
+            buckets.swept = now;
+        }
+
+        let bucket = buckets.clients.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens + elapsed(bucket.updated, now) * rate).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        Decision {
+            allowed,
+            limit: self.rule.capacity,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((capacity - bucket.tokens) / rate).ceil() as u64,
+            retry_after: (!allowed).then(|| ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64),
+        }
+    }
+}
+
+fn elapsed(since: Instant, now: Instant) -> f64 {
+    now.saturating_duration_since(since).as_secs_f64()
+}
+
+// Token-bucket rate limiter middleware. Clients are identified by the subject
+// of their bearer token when it sits behind `JwtAuth`, by IP address
+// otherwise. The state lives behind an `Arc`, so one instance created outside
+// `HttpServer::new` is shared by every worker.
+#[derive(Clone)]
+pub struct RateLimit {
+    limiters: Arc<Vec<Limiter>>,
+    // peers whose `X-Forwarded-For` is believed
+    trusted_proxies: Arc<Vec<IpAddr>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimit {
+    pub fn new(rules: Vec<Rule>, trusted_proxies: Vec<IpAddr>) -> Self {
+        RateLimit::with_clock(rules, trusted_proxies, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(
+        rules: Vec<Rule>,
+        trusted_proxies: Vec<IpAddr>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let now = clock.now();
+        let limiters = rules
+            .into_iter()
+            .map(|rule| Limiter::new(rule, now))
+            .collect();
+
+        RateLimit {
+            limiters: Arc::new(limiters),
+            trusted_proxies: Arc::new(trusted_proxies),
+            clock,
+        }
+    }
+
+    // The first rule matching the path wins, unmatched paths are not limited.
+    fn check(&self, req: &ServiceRequest) -> Option<Decision> {
+        let limiter = self.limiters.iter().find(|l| l.rule.matches(req.path()))?;
+
+        let subject = req.extensions().get::<Claims>().map(|c| c.sub.clone());
+        let key = match subject {
+            Some(sub) => format!("sub:{sub}"),
+            None => match client_ip(req, &self.trusted_proxies) {
+                Some(ip) => format!("ip:{ip}"),
+                None => String::from("ip:-"),
+            },
+        };
+
+        Some(limiter.take(&key, self.clock.now()))
+    }
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &Decision) {
+    for (name, value) in [
+        ("x-ratelimit-limit", u64::from(decision.limit)),
+        ("x-ratelimit-remaining", u64::from(decision.remaining)),
+        ("x-ratelimit-reset", decision.reset),
+    ] {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service,
+            limit: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limit: RateLimit,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let Some(decision) = self.limit.check(&req) else {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        };
+
+        if let Some(retry_after) = decision.retry_after {
+            let mut res = ApiError::TooManyRequests { retry_after }.error_response();
+            insert_headers(res.headers_mut(), &decision);
+
+            let res = req.into_response(res).map_into_right_body();
+            return Box::pin(async move { Ok(res) });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            insert_headers(res.headers_mut(), &decision);
+
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test::TestRequest, web, App, HttpResponse};
+
+    // Clock that only moves when told to.
+    struct ManualClock(Mutex<Instant>);
+
+    impl ManualClock {
+        fn new() -> Self {
+            ManualClock(Mutex::new(Instant::now()))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn rule(path: &str) -> Rule {
+        Rule {
+            path: path.to_string(),
+            capacity: 2,
+            per_second: 0.5,
+        }
+    }
+
+    #[test]
+    fn bucket_refills_with_time() {
+        let clock = ManualClock::new();
+        let limiter = Limiter::new(rule("/"), clock.now());
+
+        assert!(limiter.take("a", clock.now()).allowed);
+        assert!(limiter.take("a", clock.now()).allowed);
+
+        let rejected = limiter.take("a", clock.now());
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.retry_after, Some(2));
+        assert_eq!(rejected.reset, 4);
+
+        // other clients have their own bucket
+        assert!(limiter.take("b", clock.now()).allowed);
+
+        clock.advance(Duration::from_secs(2));
+        assert!(limiter.take("a", clock.now()).allowed);
+        assert!(!limiter.take("a", clock.now()).allowed);
+    }
+
+    #[test]
+    fn full_buckets_are_swept_on_an_interval() {
+        let clock = ManualClock::new();
+        // a token takes 50s to come back
+        let rule = Rule {
+            per_second: 0.02,
+            ..rule("/")
+        };
+        let limiter = Limiter::new(rule, clock.now());
+
+        limiter.take("a", clock.now());
+        clock.advance(Duration::from_secs(30));
+        limiter.take("b", clock.now());
+        assert_eq!(limiter.buckets.lock().unwrap().clients.len(), 2);
+
+        // "a" has refilled by now, "b" hasn't
+        clock.advance(Duration::from_secs(30));
+        limiter.take("c", clock.now());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.clients.contains_key("a"));
+        assert_eq!(buckets.clients.len(), 2);
+    }
+
+    #[test]
+    fn rules_match_whole_segments() {
+        let rule = rule("/person/manual");
+        assert!(rule.matches("/person/manual"));
+        assert!(rule.matches("/person/manual/x"));
+        assert!(!rule.matches("/person/manually"));
+        assert!(!rule.matches("/echo"));
+
+        assert!(self::rule("/users").is_under("/users"));
+        assert!(self::rule("/users/me").is_under("/users"));
+        assert!(!self::rule("/usersettings").is_under("/users"));
+        assert!(!self::rule("/").is_under("/users"));
+    }
+
+    #[actix_web::test]
+    async fn rejects_with_429_and_headers() {
+        let clock = Arc::new(ManualClock::new());
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(RateLimit::with_clock(
+                    vec![rule("/echo")],
+                    vec!["10.0.0.9".parse().unwrap()],
+                    clock.clone(),
+                ))
+                .route("/echo", web::post().to(HttpResponse::Ok))
+                .route("/free", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let call = |path: &'static str| {
+            TestRequest::post()
+                .uri(path)
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .to_request()
+        };
+        // through the trusted proxy, for the client it forwarded for
+        let proxied = |forwarded_for: &str| {
+            TestRequest::post()
+                .uri("/echo")
+                .peer_addr("10.0.0.9:80".parse().unwrap())
+                .insert_header(("x-forwarded-for", forwarded_for))
+                .to_request()
+        };
+
+        let res = actix_web::test::call_service(&app, call("/echo")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+
+        actix_web::test::call_service(&app, call("/echo")).await;
+        let res = actix_web::test::call_service(&app, call("/echo")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("retry-after").unwrap(), "2");
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "2");
+
+        // a forwarding header from anyone else doesn't buy a new bucket
+        let req = TestRequest::post()
+            .uri("/echo")
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("x-forwarded-for", "192.0.2.1"))
+            .to_request();
+        let res = actix_web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // nor does a spoofed entry left of the one the proxy added
+        let res = actix_web::test::call_service(&app, proxied("10.0.0.1")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = actix_web::test::call_service(&app, proxied("192.0.2.1, 10.0.0.1")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = actix_web::test::call_service(&app, proxied("192.0.2.7")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // unmatched paths are not limited
+        let res = actix_web::test::call_service(&app, call("/free")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key("x-ratelimit-limit"));
+
+        clock.advance(Duration::from_secs(2));
+        let res = actix_web::test::call_service(&app, call("/echo")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
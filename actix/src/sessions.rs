@@ -0,0 +1,874 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::future::{ready, Ready};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    cookie::{time, Cookie, CookieJar, Key, SameSite},
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    get,
+    http::header,
+    post, web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use futures_util::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::config::{SessionConfig, StoreKind};
+use crate::error::{ApiError, Problem};
+use crate::negotiate::Negotiated;
+use crate::validate::{Rule, Valid, Validate, Validator};
+
+// Header carrying the CSRF token of requests that don't send it as a form
+// field.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+// Sessions kept in the store, keyed by their id.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionData {
+    pub user: Option<String>,
+    pub csrf_token: String,
+    // seconds since the epoch
+    pub created_at: u64,
+    pub last_seen: u64,
+    pub rotated_at: u64,
+}
+
+impl SessionData {
+    fn new(now: u64) -> Self {
+        SessionData {
+            user: None,
+            csrf_token: random_token(),
+            created_at: now,
+            last_seen: now,
+            rotated_at: now,
+        }
+    }
+}
+
+// Storage for sessions. Like `CounterStore`, a single store is shared by all
+// workers, and it is only called from blocking threads.
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>>;
+
+    fn save(&self, id: &str, data: &SessionData) -> io::Result<()>;
+
+    fn delete(&self, id: &str) -> io::Result<()>;
+
+    // Deletes every session `expired` returns true for, and returns how many.
+    fn purge(&self, expired: &dyn Fn(&SessionData) -> bool) -> io::Result<usize>;
+
+    // Sessions in the store, expired ones included until they are purged.
+    fn count(&self) -> io::Result<usize>;
+}
+
+// Sessions that live only as long as the process.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, SessionData>>,
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        Ok(self.sessions.lock().unwrap().get(id).cloned())
+    }
+
+    fn save(&self, id: &str, data: &SessionData) -> io::Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(id.to_string(), data.clone());
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn purge(&self, expired: &dyn Fn(&SessionData) -> bool) -> io::Result<usize> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, data| !expired(data));
+        Ok(before - sessions.len())
+    }
+
+    fn count(&self) -> io::Result<usize> {
+        Ok(self.sessions.lock().unwrap().len())
+    }
+}
+
+// One JSON file per session in `dir`, so sessions survive restarts and can be
+// shared by several processes.
+pub struct FileSessionStore {
+    dir: PathBuf,
+}
+
+impl FileSessionStore {
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileSessionStore { dir })
+    }
+
+    fn path(&self, id: &str) -> io::Result<PathBuf> {
+        // ids come from cookies, they must not name another file
+        if !is_id(id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid session id",
+            ));
+        }
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        match fs::read(self.path(id)?) {
+            Ok(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(data) => Ok(Some(data)),
+                Err(err) => {
+                    log::warn!("ignoring corrupt session {id}: {err}");
+                    Ok(None)
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save(&self, id: &str, data: &SessionData) -> io::Result<()> {
+        // write a temp file and swap it in, so readers never see half a session
+        let path = self.path(id)?;
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&serde_json::to_vec(data)?)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &path)
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(id)?) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn purge(&self, expired: &dyn Fn(&SessionData) -> bool) -> io::Result<usize> {
+        let mut purged = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let Some(id) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".json"))
+                .filter(|id| is_id(id))
+            else {
+                continue;
+            };
+            if self.load(id)?.is_none_or(|data| expired(&data)) {
+                self.delete(id)?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    fn count(&self) -> io::Result<usize> {
+        let mut count = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+// Opens the store selected in the configuration.
+pub fn from_config(config: &SessionConfig) -> io::Result<Box<dyn SessionStore>> {
+    match config.store {
+        StoreKind::Memory => Ok(Box::new(MemorySessionStore::default())),
+        StoreKind::File => {
+            let store = FileSessionStore::open(&config.dir)?;
+            log::info!("sessions are persisted to {}", config.dir.display());
+
+            Ok(Box::new(store))
+        }
+    }
+}
+
+// 256 random bits, hex encoded.
+fn random_token() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+fn is_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Compares in time independent of where the first difference is.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Session lifetimes, in seconds. `rotate: 0` keeps the id for the whole
+// session.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub idle: u64,
+    pub max_age: u64,
+    pub rotate: u64,
+}
+
+// What a request does to the session it came with.
+#[derive(Debug, PartialEq, Eq)]
+enum Fate {
+    Expired,
+    // same data under a new id
+    Rotate,
+    // `last_seen` is saved now and then, not on every request
+    Touch,
+    Keep,
+}
+
+impl Timeouts {
+    fn fate(&self, data: &SessionData, now: u64) -> Fate {
+        if now.saturating_sub(data.last_seen) >= self.idle
+            || now.saturating_sub(data.created_at) >= self.max_age
+        {
+            Fate::Expired
+        } else if self.rotate > 0 && now.saturating_sub(data.rotated_at) >= self.rotate {
+            Fate::Rotate
+        } else if now.saturating_sub(data.last_seen) >= (self.idle / 2).min(60) {
+            Fate::Touch
+        } else {
+            Fate::Keep
+        }
+    }
+
+    // When the session ends if it is used again right now.
+    fn expires_at(&self, data: &SessionData, now: u64) -> u64 {
+        (now + self.idle).min(data.created_at + self.max_age)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    None,
+    Modified,
+    // saved under a new id, the old one is dropped
+    Renewed,
+    Deleted,
+}
+
+struct State {
+    data: Option<SessionData>,
+    change: Change,
+    timeouts: Timeouts,
+}
+
+// The session of the current request, created on first use. Handlers get it
+// as an extractor; it is only available behind the `Sessions` middleware.
+#[derive(Clone)]
+pub struct Session(Rc<RefCell<State>>);
+
+impl Session {
+    pub fn user(&self) -> Option<String> {
+        self.0.borrow().data.as_ref()?.user.clone()
+    }
+
+    // Starts a session if there is none yet.
+    pub fn csrf_token(&self) -> String {
+        let mut state = self.0.borrow_mut();
+        if state.data.is_none() {
+            state.data = Some(SessionData::new(now()));
+            state.change = Change::Modified;
+        }
+        state.data.as_ref().unwrap().csrf_token.clone()
+    }
+
+    // Replaces the session with a fresh one for `user`, under a new id and
+    // with a new CSRF token, so ids and tokens seen before logging in are
+    // worthless.
+    pub fn login(&self, user: &str) {
+        let mut state = self.0.borrow_mut();
+        let mut data = SessionData::new(now());
+        data.user = Some(user.to_string());
+        state.data = Some(data);
+        state.change = Change::Renewed;
+    }
+
+    pub fn logout(&self) {
+        let mut state = self.0.borrow_mut();
+        state.data = None;
+        state.change = Change::Deleted;
+    }
+
+    // Identifies the session without starting one: it is kept when the id is
+    // rotated and replaced on login.
+    pub fn scope(&self) -> Option<String> {
+        let state = self.0.borrow();
+        state.data.as_ref().map(|data| data.csrf_token.clone())
+    }
+
+    // Whether `token` is the CSRF token of this session. Requests without a
+    // session have no valid token.
+    pub fn check_csrf(&self, token: Option<&str>) -> bool {
+        let state = self.0.borrow();
+        match (&state.data, token) {
+            (Some(data), Some(token)) => {
+                constant_time_eq(data.csrf_token.as_bytes(), token.as_bytes())
+            }
+            _ => false,
+        }
+    }
+
+    fn info(&self) -> SessionInfo {
+        let csrf_token = self.csrf_token();
+        let state = self.0.borrow();
+        let data = state.data.as_ref().unwrap();
+
+        SessionInfo {
+            user: data.user.clone(),
+            csrf_token,
+            expires_at: state.timeouts.expires_at(data, now()),
+        }
+    }
+}
+
+impl FromRequest for Session {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<Session>().cloned().ok_or_else(|| {
+            ApiError::Internal(String::from("sessions are not set up for this route"))
+        }))
+    }
+}
+
+struct Inner {
+    store: Box<dyn SessionStore>,
+    key: Key,
+    // private (encrypted) cookies rather than signed ones
+    encrypt: bool,
+    cookie_name: String,
+    secure: bool,
+    timeouts: Timeouts,
+    // sessions in the store before new anonymous ones are refused
+    max_sessions: usize,
+    // when expired sessions were last purged from the store
+    last_purge: Mutex<u64>,
+}
+
+impl Inner {
+    // The session id in the request's cookie, if it has a valid signature.
+    fn id(&self, req: &ServiceRequest) -> Option<String> {
+        let mut jar = CookieJar::new();
+        jar.add_original(req.cookie(&self.cookie_name)?);
+        let cookie = match self.encrypt {
+            true => jar.private(&self.key).get(&self.cookie_name),
+            false => jar.signed(&self.key).get(&self.cookie_name),
+        }?;
+        Some(cookie.value().to_string()).filter(|id| is_id(id))
+    }
+
+    fn cookie(&self, id: &str, data: &SessionData, now: u64) -> Cookie<'static> {
+        let remaining = (data.created_at + self.timeouts.max_age).saturating_sub(now);
+        let cookie = Cookie::build(self.cookie_name.clone(), id.to_string())
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(remaining as i64))
+            .finish();
+
+        let mut jar = CookieJar::new();
+        match self.encrypt {
+            true => jar.private_mut(&self.key).add(cookie),
+            false => jar.signed_mut(&self.key).add(cookie),
+        }
+        jar.get(&self.cookie_name).unwrap().clone()
+    }
+
+    fn removal(&self) -> Cookie<'static> {
+        let mut cookie = Cookie::build(self.cookie_name.clone(), "")
+            .path("/")
+            .finish();
+        cookie.make_removal();
+        cookie
+    }
+
+    // Purges expired sessions at most once per idle timeout.
+    fn purge(&self, now: u64) {
+        {
+            let mut last = self.last_purge.lock().unwrap();
+            if now.saturating_sub(*last) < self.timeouts.idle {
+                return;
+            }
+            *last = now;
+        }
+
+        let expired = |data: &SessionData| self.timeouts.fate(data, now) == Fate::Expired;
+        match self.store.purge(&expired) {
+            Ok(0) => {}
+            Ok(purged) => log::debug!("purged {purged} expired sessions"),
+            Err(err) => log::warn!("could not purge expired sessions: {err}"),
+        }
+    }
+
+    // Whether another session may be started without logging in. Logins are
+    // never refused, so a full store can't lock users out.
+    fn has_room(&self) -> io::Result<bool> {
+        Ok(self.store.count()? < self.max_sessions)
+    }
+}
+
+// Cookie session middleware factory. The cookie only holds a random id,
+// signed (HMAC-SHA256) or encrypted (AES-256-GCM) with the configured
+// secret; the session itself is kept in the store. Once it holds
+// `max_sessions`, requests that would start an anonymous session get a 507
+// until some expire. Like `Idempotency`, one instance is shared by every
+// worker.
+#[derive(Clone)]
+pub struct Sessions {
+    inner: Arc<Inner>,
+}
+
+impl Sessions {
+    // `secure` is the default of `config.secure`, for when it is not set.
+    pub fn new(config: &SessionConfig, secure: bool) -> io::Result<Self> {
+        let key = match &config.secret {
+            Some(secret) => Key::derive_from(secret.as_bytes()),
+            None => {
+                log::warn!("sessions.secret is not set, sessions end when the server restarts");
+                Key::generate()
+            }
+        };
+
+        Ok(Sessions {
+            inner: Arc::new(Inner {
+                store: from_config(config)?,
+                key,
+                encrypt: config.encrypt,
+                cookie_name: config.cookie_name.clone(),
+                secure: config.secure.unwrap_or(secure),
+                timeouts: Timeouts {
+                    idle: config.idle_timeout,
+                    max_age: config.max_age,
+                    rotate: config.rotate,
+                },
+                max_sessions: config.max_sessions,
+                last_purge: Mutex::new(now()),
+            }),
+        })
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Sessions
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SessionsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SessionsMiddleware {
+            service: Rc::new(service),
+            inner: Arc::clone(&self.inner),
+        }))
+    }
+}
+
+pub struct SessionsMiddleware<S> {
+    // shared with the request future, which calls it once the session is loaded
+    service: Rc<S>,
+    inner: Arc<Inner>,
+}
+
+impl<S, B> Service<ServiceRequest> for SessionsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let inner = Arc::clone(&self.inner);
+
+        Box::pin(async move {
+            let presented = req.cookie(&inner.cookie_name).is_some();
+            let now = now();
+
+            // expired sessions are dropped before the handler can see them
+            let loaded = match inner.id(&req) {
+                Some(id) => {
+                    let loaded = blocking(&inner, move |inner| {
+                        let Some(data) = inner.store.load(&id)? else {
+                            return Ok(None);
+                        };
+                        match inner.timeouts.fate(&data, now) {
+                            Fate::Expired => {
+                                inner.store.delete(&id)?;
+                                Ok(None)
+                            }
+                            fate => Ok(Some((id, data, fate))),
+                        }
+                    })
+                    .await;
+                    match loaded {
+                        Ok(loaded) => loaded,
+                        Err(err) => {
+                            let res = ApiError::Storage(err).error_response();
+                            return Ok(req.into_response(res).map_into_right_body());
+                        }
+                    }
+                }
+                None => None,
+            };
+            let (id, data, fate) = match loaded {
+                Some((id, data, fate)) => (Some(id), Some(data), fate),
+                None => (None, None, Fate::Keep),
+            };
+
+            let state = Rc::new(RefCell::new(State {
+                data,
+                change: Change::None,
+                timeouts: inner.timeouts,
+            }));
+            req.extensions_mut().insert(Session(Rc::clone(&state)));
+
+            let mut res = service.call(req).await?;
+
+            let (data, change) = {
+                let state = state.borrow();
+                (state.data.clone(), state.change)
+            };
+            let alive = data.is_some();
+            // the id to drop and the id and data to save
+            let (old, new) = match (change, data) {
+                (_, None) => (id.clone(), None),
+                (Change::Renewed, Some(data)) => (id.clone(), Some((random_token(), data))),
+                (_, Some(mut data)) if fate == Fate::Rotate => {
+                    data.rotated_at = now;
+                    (id.clone(), Some((random_token(), data)))
+                }
+                (Change::Modified, Some(data)) => {
+                    let new = id.clone().unwrap_or_else(random_token);
+                    (None, Some((new, data)))
+                }
+                (_, Some(data)) if fate == Fate::Touch => (None, id.clone().zip(Some(data))),
+                (_, Some(_)) => (None, None),
+            };
+            // changes made by the handler must be saved, routine touches and
+            // rotations can fail without failing the request
+            let required = change != Change::None;
+
+            let Some((new_id, mut new_data)) = new else {
+                if let Some(old) = old {
+                    if let Err(err) = blocking(&inner, move |inner| inner.store.delete(&old)).await
+                    {
+                        log::warn!("could not delete a session: {err}");
+                    }
+                }
+                // ended, expired, or never valid
+                if presented && !alive {
+                    res.response_mut().add_removal_cookie(&inner.removal())?;
+                }
+                return Ok(res.map_into_left_body());
+            };
+
+            new_data.last_seen = now;
+            let anonymous = id.is_none() && new_data.user.is_none();
+            let cookie =
+                (Some(&new_id) != id.as_ref()).then(|| inner.cookie(&new_id, &new_data, now));
+            let saved = blocking(&inner, move |inner| {
+                inner.purge(now);
+                if anonymous && !inner.has_room()? {
+                    return Ok(false);
+                }
+                inner.store.save(&new_id, &new_data)?;
+                if let Some(old) = old {
+                    inner.store.delete(&old)?;
+                }
+                Ok(true)
+            })
+            .await;
+
+            match saved {
+                Ok(true) => {
+                    if let Some(cookie) = cookie {
+                        res.response_mut().add_cookie(&cookie)?;
+                    }
+                    Ok(res.map_into_left_body())
+                }
+                Ok(false) => {
+                    let (req, _) = res.into_parts();
+                    let res = ApiError::QuotaExceeded(String::from(
+                        "too many sessions, try again later or log in",
+                    ))
+                    .error_response();
+                    Ok(ServiceResponse::new(req, res).map_into_right_body())
+                }
+                Err(err) if required => {
+                    let (req, _) = res.into_parts();
+                    let res = ApiError::Storage(err).error_response();
+                    Ok(ServiceResponse::new(req, res).map_into_right_body())
+                }
+                Err(err) => {
+                    log::warn!("could not save a session: {err}");
+                    Ok(res.map_into_left_body())
+                }
+            }
+        })
+    }
+}
+
+// Runs `f` on a blocking thread, stores are never called from async code.
+async fn blocking<T: Send + 'static>(
+    inner: &Arc<Inner>,
+    f: impl FnOnce(&Inner) -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    let inner = Arc::clone(inner);
+    web::block(move || f(&inner))
+        .await
+        .unwrap_or_else(|err| Err(io::Error::other(err.to_string())))
+}
+
+// Users who can log in, with their argon2 password hashes in PHC format,
+// e.g. from `cargo run --bin hash-password`.
+pub struct Accounts {
+    hashes: HashMap<String, String>,
+}
+
+impl Accounts {
+    pub fn new(accounts: &[Account]) -> Self {
+        Accounts {
+            hashes: accounts
+                .iter()
+                .map(|account| (account.username.clone(), account.password_hash.clone()))
+                .collect(),
+        }
+    }
+
+    // Slow on purpose. Unknown users are checked against another user's
+    // hash, so they take as long as a wrong password.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        let known = self.hashes.contains_key(username);
+        let Some(hash) = self.hashes.get(username).or(self.hashes.values().next()) else {
+            return false;
+        };
+        // validated with the configuration
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return false;
+        };
+
+        let verified = Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok();
+        known && verified
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Account {
+    pub username: String,
+    pub password_hash: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct Credentials {
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: String,
+}
+
+impl Validate for Credentials {
+    fn rules(&self, v: &mut Validator) {
+        v.check(
+            "username",
+            &self.username,
+            &[Rule::Required, Rule::Length(1, 64)],
+        );
+        // hashing is slow enough without huge inputs
+        v.check(
+            "password",
+            &self.password,
+            &[Rule::Required, Rule::Length(1, 1024)],
+        );
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SessionInfo {
+    user: Option<String>,
+    // to send back with forms, or as the `X-CSRF-Token` header
+    csrf_token: String,
+    // seconds since the epoch, later when the session is used again
+    expires_at: u64,
+}
+
+#[utoipa::path(
+    request_body(content(
+        (Credentials = "application/json"),
+        (Credentials = "application/x-www-form-urlencoded"),
+    )),
+    responses(
+        (status = 200, body = SessionInfo, headers(("set-cookie"))),
+        (status = 401, response = Problem),
+        (status = 422, response = Problem),
+        (status = 500, response = Problem),
+    )
+)]
+#[post("/login")]
+pub async fn login(
+    session: Session,
+    accounts: web::Data<Accounts>,
+    credentials: Valid<Negotiated<Credentials>>,
+) -> Result<HttpResponse, ApiError> {
+    let Credentials { username, password } = credentials.0 .0;
+    let verified = web::block({
+        let username = username.clone();
+        move || accounts.verify(&username, &password)
+    })
+    .await?;
+    if !verified {
+        return Err(ApiError::Unauthorized {
+            detail: String::from("unknown username or wrong password"),
+            challenge: None,
+        });
+    }
+
+    session.login(&username);
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(session.info()))
+}
+
+#[utoipa::path(responses((status = 204)))]
+#[post("/logout")]
+pub async fn logout(session: Session) -> HttpResponse {
+    session.logout();
+    HttpResponse::NoContent().finish()
+}
+
+// The current session, started if needed so browsers can get a CSRF token
+// before logging in.
+#[utoipa::path(responses(
+    (status = 200, body = SessionInfo),
+    (status = 507, response = Problem),
+))]
+#[get("/session")]
+pub async fn get_session(session: Session) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(session.info())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store_keeps_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileSessionStore::open(dir.path()).unwrap();
+        let (a, b) = (random_token(), random_token());
+
+        let mut data = SessionData::new(100);
+        data.user = Some(String::from("alice"));
+        store.save(&a, &data).unwrap();
+        store.save(&b, &SessionData::new(0)).unwrap();
+        assert_eq!(store.load(&a).unwrap(), Some(data));
+        assert!(store.load("../../etc/passwd").is_err());
+        assert_eq!(store.count().unwrap(), 2);
+
+        let purged = store.purge(&|data| data.created_at < 50).unwrap();
+        assert_eq!(purged, 1);
+        assert_eq!(store.load(&b).unwrap(), None);
+
+        store.delete(&a).unwrap();
+        store.delete(&a).unwrap();
+        assert_eq!(store.load(&a).unwrap(), None);
+    }
+
+    #[test]
+    fn sessions_expire_and_rotate() {
+        let timeouts = Timeouts {
+            idle: 600,
+            max_age: 3600,
+            rotate: 900,
+        };
+        let mut data = SessionData::new(1000);
+
+        assert_eq!(timeouts.fate(&data, 1010), Fate::Keep);
+        assert_eq!(timeouts.fate(&data, 1060), Fate::Touch);
+        assert_eq!(timeouts.fate(&data, 1600), Fate::Expired);
+        data.last_seen = 1800;
+        assert_eq!(timeouts.fate(&data, 1900), Fate::Rotate);
+        data.rotated_at = 1800;
+        assert_eq!(timeouts.fate(&data, 1810), Fate::Keep);
+        data.last_seen = 4500;
+        assert_eq!(timeouts.fate(&data, 4600), Fate::Expired);
+        assert_eq!(timeouts.expires_at(&data, 4000), 4600);
+    }
+
+    #[test]
+    fn checks_csrf_tokens() {
+        let session = Session(Rc::new(RefCell::new(State {
+            data: None,
+            change: Change::None,
+            timeouts: Timeouts {
+                idle: 60,
+                max_age: 60,
+                rotate: 0,
+            },
+        })));
+        assert!(!session.check_csrf(None));
+
+        let token = session.csrf_token();
+        assert_eq!(session.0.borrow().change, Change::Modified);
+        assert!(session.check_csrf(Some(&token)));
+        assert!(!session.check_csrf(Some(&token[1..])));
+        assert!(!session.check_csrf(None));
+
+        session.login("alice");
+        assert!(!session.check_csrf(Some(&token)));
+        assert_eq!(session.user().as_deref(), Some("alice"));
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{CounterConfig, StoreKind};
+
+// Storage for named counters. Every method takes `&self` so a single store
+// can be shared by all workers; implementations do their own locking so that
+// read-modify-write operations never lose updates.
+pub trait CounterStore: Send + Sync {
+    fn get(&self, name: &str) -> io::Result<Option<i64>>;
+
+    // Increments the counter (creating it at 0 first) and returns the new value.
+    fn increment(&self, name: &str) -> io::Result<i64>;
+
+    // Returns false if the counter did not exist.
+    fn delete(&self, name: &str) -> io::Result<bool>;
+}
+
+// Counters that live only as long as the process.
+#[derive(Default)]
+pub struct MemoryStore {
+    counters: Mutex<HashMap<String, i64>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl CounterStore for MemoryStore {
+    fn get(&self, name: &str) -> io::Result<Option<i64>> {
+        Ok(self.counters.lock().unwrap().get(name).copied())
+    }
+
+    fn increment(&self, name: &str) -> io::Result<i64> {
+        let mut counters = self.counters.lock().unwrap();
+        let value = counters.entry(name.to_string()).or_insert(0);
+
+        *value += 1;
+        Ok(*value)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<bool> {
+        Ok(self.counters.lock().unwrap().remove(name).is_some())
+    }
+}
+
+// One line of the append log. `value: None` records a delete.
+#[derive(Serialize, Deserialize)]
+struct LogEntry {
+    name: String,
+    value: Option<i64>,
+}
+
+struct FileState {
+    counters: HashMap<String, i64>,
+    log: File,
+    // length of the log up to the last complete entry
+    len: u64,
+}
+
+impl FileState {
+    // Appends an entry and syncs it. A failed write is cut off again, so the
+    // log never keeps half an entry that later ones would be appended to.
+    fn append(&mut self, name: &str, value: Option<i64>) -> io::Result<()> {
+        let line = entry_line(name, value)?;
+        let written = self
+            .log
+            .write_all(&line)
+            .and_then(|()| self.log.sync_data());
+
+        match written {
+            Ok(()) => {
+                self.len += line.len() as u64;
+                Ok(())
+            }
+            Err(err) => {
+                if let Err(truncate) = self.log.set_len(self.len) {
+                    log::error!("could not cut a failed write off the counter log: {truncate}");
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+// Counters persisted to an append-only log of JSON lines. The log is replayed
+// and compacted when the store is opened, and every change is synced to disk
+// before it is applied in memory and acknowledged.
+pub struct FileStore {
+    path: PathBuf,
+    state: Mutex<FileState>,
+}
+
+impl FileStore {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let counters = match File::open(&path) {
+            Ok(file) => replay(file)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+
+        // compact: write the current snapshot to a temp file and swap it in
+        let tmp = path.with_extension("compact");
+        {
+            let mut file = File::create(&tmp)?;
+            for (name, value) in &counters {
+                file.write_all(&entry_line(name, Some(*value))?)?;
+            }
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp, &path)?;
+        // the rename itself is only durable once the directory is synced
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+
+        let log = OpenOptions::new().append(true).open(&path)?;
+        let len = log.metadata()?.len();
+
+        Ok(FileStore {
+            path,
+            state: Mutex::new(FileState { counters, log, len }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn replay(file: File) -> io::Result<HashMap<String, i64>> {
+    let mut counters = HashMap::new();
+
+    let mut lines = BufReader::new(file).lines().peekable();
+    while let Some(line) = lines.next() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // a torn final line from a crash is expected, anything else means the
+        // log is damaged and guessing would silently lose counts
+        let entry: LogEntry = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(err) if lines.peek().is_none() => {
+                log::warn!("skipping torn last counter log entry: {err}");
+                continue;
+            }
+            Err(err) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt counter log entry: {err}"),
+                ))
+            }
+        };
+
+        match entry.value {
+            Some(value) => counters.insert(entry.name, value),
+            None => counters.remove(&entry.name),
+        };
+    }
+
+    Ok(counters)
+}
+
+fn entry_line(name: &str, value: Option<i64>) -> io::Result<Vec<u8>> {
+    let entry = LogEntry {
+        name: name.to_string(),
+        value,
+    };
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+
+    Ok(line)
+}
+
+impl CounterStore for FileStore {
+    fn get(&self, name: &str) -> io::Result<Option<i64>> {
+        Ok(self.state.lock().unwrap().counters.get(name).copied())
+    }
+
+    fn increment(&self, name: &str) -> io::Result<i64> {
+        let mut state = self.state.lock().unwrap();
+        let value = state.counters.get(name).copied().unwrap_or(0) + 1;
+
+        state.append(name, Some(value))?;
+        state.counters.insert(name.to_string(), value);
+
+        Ok(value)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        if !state.counters.contains_key(name) {
+            return Ok(false);
+        }
+
+        state.append(name, None)?;
+        state.counters.remove(name);
+
+        Ok(true)
+    }
+}
+
+// Opens the store selected in the configuration.
+pub fn from_config(config: &CounterConfig) -> io::Result<Box<dyn CounterStore>> {
+    match config.store {
+        StoreKind::Memory => Ok(Box::new(MemoryStore::new())),
+        StoreKind::File => {
+            let store = FileStore::open(&config.path)?;
+            log::info!("counters are persisted to {}", store.path().display());
+
+            Ok(Box::new(store))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn hammer(store: Arc<dyn CounterStore>) {
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        store.increment("hits").unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn memory_store_does_not_lose_updates() {
+        let store: Arc<dyn CounterStore> = Arc::new(MemoryStore::new());
+        hammer(Arc::clone(&store));
+
+        assert_eq!(store.get("hits").unwrap(), Some(400));
+        assert!(store.delete("hits").unwrap());
+        assert!(!store.delete("hits").unwrap());
+    }
+
+    #[test]
+    fn file_store_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counters.log");
+
+        let store: Arc<dyn CounterStore> = Arc::new(FileStore::open(&path).unwrap());
+        hammer(Arc::clone(&store));
+        store.increment("gone").unwrap();
+        store.delete("gone").unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get("hits").unwrap(), Some(400));
+        assert_eq!(store.get("gone").unwrap(), None);
+        assert_eq!(store.increment("hits").unwrap(), 401);
+    }
+
+    #[test]
+    fn only_a_torn_last_line_is_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counters.log");
+
+        std::fs::write(&path, "{\"name\":\"a\",\"value\":3}\n{\"name\":\"a\",\"va").unwrap();
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(3));
+        assert_eq!(store.increment("a").unwrap(), 4);
+        drop(store);
+        assert_eq!(FileStore::open(&path).unwrap().get("a").unwrap(), Some(4));
+
+        std::fs::write(
+            &path,
+            "{\"name\":\"a\",\"va\n{\"name\":\"a\",\"value\":3}\n",
+        )
+        .unwrap();
+        let err = FileStore::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
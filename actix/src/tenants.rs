@@ -0,0 +1,100 @@
+use actix_web::{
+    dev::RequestHead,
+    guard::{Guard, GuardContext},
+    http::{header, uri::Authority},
+};
+
+use crate::config::TenantConfig;
+
+// The host a request was sent to, lowercased and without the port. Taken from
+// `Host`, or from the URI authority for HTTP/2 requests that only carry
+// `:authority`. Forwarding headers are ignored, they are up to the client.
+pub fn host(head: &RequestHead) -> Option<String> {
+    let authority = match head.headers().get(header::HOST) {
+        Some(value) => value.to_str().ok()?.parse::<Authority>().ok()?,
+        None => head.uri.authority()?.clone(),
+    };
+    let host = authority.host().trim_end_matches('.');
+
+    Some(host.to_ascii_lowercase())
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// Matches the requests of one tenant: an exact host, or exactly
+// `<subdomain>.<base_domain>`.
+#[derive(Debug, Clone)]
+pub struct HostGuard {
+    hosts: Vec<String>,
+    // the full host of the subdomain
+    subdomain: Option<String>,
+}
+
+impl HostGuard {
+    pub fn new(config: &TenantConfig, base_domain: Option<&str>) -> Self {
+        HostGuard {
+            hosts: config.hosts.iter().map(|host| normalize(host)).collect(),
+            // validation makes sure both are set together
+            subdomain: config
+                .subdomain
+                .as_ref()
+                .zip(base_domain)
+                .map(|(subdomain, base)| normalize(&format!("{subdomain}.{base}"))),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        self.hosts.iter().chain(&self.subdomain).any(|h| h == host)
+    }
+}
+
+impl Guard for HostGuard {
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        host(ctx.head()).is_some_and(|host| self.matches(&host))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn matches_hosts_and_subdomains() {
+        let guard = HostGuard::new(
+            &TenantConfig {
+                name: String::from("acme"),
+                hosts: vec![String::from("Acme.test")],
+                subdomain: Some(String::from("acme")),
+                ..TenantConfig::default()
+            },
+            Some("Example.com."),
+        );
+        let check = |host: &str| {
+            let req = TestRequest::default()
+                .insert_header((header::HOST, host))
+                .to_srv_request();
+            guard.check(&req.guard_ctx())
+        };
+
+        assert!(check("acme.test"));
+        assert!(check("ACME.test:8081"));
+        assert!(check("acme.test."));
+        assert!(check("acme.example.com"));
+        assert!(!check("acme"));
+        assert!(!check("www.acme.test"));
+        assert!(!check("globex.example.com"));
+        // only the configured base domain
+        assert!(!check("acme.evil.com"));
+        assert!(!check("acme.anything"));
+        assert!(!check("acme.example.com.evil.com"));
+        assert!(!check("x.acme.example.com"));
+
+        let req = TestRequest::default()
+            .uri("https://acme.example.com/")
+            .to_srv_request();
+        assert!(guard.check(&req.guard_ctx()));
+    }
+}
@@ -0,0 +1,301 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    http::header,
+    rt, web, App, HttpRequest, HttpResponse,
+};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+
+use crate::config::TlsConfig;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn invalid(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {err}", path.display()),
+    )
+}
+
+fn load_key(cert: &Path, key: &Path) -> io::Result<CertifiedKey> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| invalid(cert, err))?;
+    if chain.is_empty() {
+        return Err(invalid(cert, "no certificate found"));
+    }
+    let private = PrivateKeyDer::from_pem_file(key).map_err(|err| invalid(key, err))?;
+    let signing = provider()
+        .key_provider
+        .load_private_key(private)
+        .map_err(|err| invalid(key, err))?;
+
+    // a renewal caught between writing the certificate and the key
+    let certified = CertifiedKey::new(chain, signing);
+    certified.keys_match().map_err(|err| invalid(key, err))?;
+
+    Ok(certified)
+}
+
+fn modified(path: &Path) -> io::Result<SystemTime> {
+    fs::metadata(path)?.modified()
+}
+
+// The server certificate, read again from disk when its files change so a
+// renewed certificate is picked up without a restart. Handshakes in progress
+// keep the one they started with.
+#[derive(Debug)]
+pub struct ReloadingCert {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    // modification times of the files `current` was read from
+    loaded: Mutex<(SystemTime, SystemTime)>,
+}
+
+impl ReloadingCert {
+    pub fn load(cert: &Path, key: &Path) -> io::Result<Arc<Self>> {
+        let loaded = (modified(cert)?, modified(key)?);
+
+        Ok(Arc::new(ReloadingCert {
+            current: RwLock::new(Arc::new(load_key(cert, key)?)),
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+            loaded: Mutex::new(loaded),
+        }))
+    }
+
+    pub fn from_config(config: &TlsConfig) -> io::Result<Option<Arc<Self>>> {
+        match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => ReloadingCert::load(cert, key).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    // Reads the files again if either changed since the last load. A broken
+    // pair, e.g. one caught halfway through a renewal, keeps the current
+    // certificate and is retried next time.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let seen = (modified(&self.cert)?, modified(&self.key)?);
+        let mut loaded = self.loaded.lock().unwrap();
+        if *loaded == seen {
+            return Ok(false);
+        }
+
+        let key = load_key(&self.cert, &self.key)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        *loaded = seen;
+
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().unwrap()))
+    }
+}
+
+// rustls settings for the HTTPS listeners. `bind_rustls_0_23` adds the ALPN
+// protocols, so HTTP/2 is used by every client that offers it.
+pub fn server_config(certs: Arc<ReloadingCert>) -> io::Result<ServerConfig> {
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_cert_resolver(certs);
+
+    Ok(config)
+}
+
+// Checks for a renewed certificate every `every`.
+pub async fn watch(certs: Arc<ReloadingCert>, every: Duration) {
+    let mut interval = rt::time::interval(every);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        match certs.reload_if_changed() {
+            Ok(true) => log::info!("reloaded TLS certificate {}", certs.cert.display()),
+            Ok(false) => {}
+            Err(err) => log::warn!("keeping the current TLS certificate: {err}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct HttpsPort(u16);
+
+async fn redirect(req: HttpRequest, port: web::Data<HttpsPort>) -> HttpResponse {
+    let info = req.connection_info();
+    let authority = info.host();
+    // drop the plain HTTP port, keeping IPv6 literals whole
+    let host = match authority.rfind(':') {
+        Some(at) if !authority[at..].contains(']') => &authority[..at],
+        _ => authority,
+    };
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+
+    let location = match port.0 {
+        443 => format!("https://{host}{path}"),
+        port => format!("https://{host}:{port}{path}"),
+    };
+
+    // 308 so clients repeat the method and body
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+// App for the plain HTTP listeners when HTTPS is on: everything is sent to
+// the same URL on `https_port`.
+pub fn redirect_app(
+    https_port: u16,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .app_data(web::Data::new(HttpsPort(https_port)))
+        .default_service(web::to(redirect))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{StatusCode, Version};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::HttpServer;
+    use rustls::{ClientConfig, RootCertStore};
+
+    struct Generated {
+        cert: String,
+        key: String,
+        der: CertificateDer<'static>,
+    }
+
+    fn generate() -> Generated {
+        let rcgen::CertifiedKey { cert, signing_key } =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+
+        Generated {
+            cert: cert.pem(),
+            key: signing_key.serialize_pem(),
+            der: cert.der().clone(),
+        }
+    }
+
+    fn client(trusted: &Generated) -> awc::Client {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.der.clone()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        awc::Client::builder()
+            .connector(awc::Connector::new().rustls_0_23(Arc::new(config)))
+            .finish()
+    }
+
+    #[actix_web::test]
+    async fn serves_http2_and_reloads_the_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        let first = generate();
+        fs::write(&cert_path, &first.cert).unwrap();
+        fs::write(&key_path, &first.key).unwrap();
+
+        let certs = ReloadingCert::load(&cert_path, &key_path).unwrap();
+        let server =
+            HttpServer::new(|| App::new().route("/", web::get().to(|| async { "secure" })))
+                .workers(1)
+                .disable_signals()
+                .bind_rustls_0_23("127.0.0.1:0", server_config(certs.clone()).unwrap())
+                .unwrap();
+        let url = format!("https://localhost:{}/", server.addrs()[0].port());
+        let server = server.run();
+        let handle = server.handle();
+        rt::spawn(server);
+
+        let mut res = client(&first).get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.version(), Version::HTTP_2);
+        assert_eq!(res.body().await.unwrap(), "secure");
+
+        assert!(!certs.reload_if_changed().unwrap());
+        let second = generate();
+        fs::write(&cert_path, &second.cert).unwrap();
+        fs::write(&key_path, &second.key).unwrap();
+        assert!(certs.reload_if_changed().unwrap());
+
+        // new connections get the renewed certificate
+        let res = client(&second).get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(client(&first).get(&url).send().await.is_err());
+
+        // a broken renewal keeps the certificate in use
+        fs::write(&key_path, "not a key").unwrap();
+        assert!(certs.reload_if_changed().is_err());
+        let res = client(&second).get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // and so does a new certificate whose key isn't there yet
+        let third = generate();
+        fs::write(&cert_path, &third.cert).unwrap();
+        fs::write(&key_path, &second.key).unwrap();
+        assert!(certs.reload_if_changed().is_err());
+        let res = client(&second).get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        fs::write(&key_path, &third.key).unwrap();
+        assert!(certs.reload_if_changed().unwrap());
+        let res = client(&third).get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn redirects_to_https() {
+        let app = init_service(redirect_app(8443)).await;
+
+        let cases = [
+            (
+                "example.com:8081",
+                "https://example.com:8443/person/auto?x=1",
+            ),
+            ("[::1]:8081", "https://[::1]:8443/person/auto?x=1"),
+            ("example.com", "https://example.com:8443/person/auto?x=1"),
+        ];
+        for (host, location) in cases {
+            let req = TestRequest::post()
+                .uri("/person/auto?x=1")
+                .insert_header((header::HOST, host))
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+            assert_eq!(res.headers().get(header::LOCATION).unwrap(), location);
+        }
+    }
+}
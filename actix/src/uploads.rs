@@ -0,0 +1,737 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_web::{
+    http::header::{self, ContentDisposition, DispositionParam, DispositionType, HeaderValue},
+    mime, web, HttpRequest, HttpResponse, Responder,
+};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use utoipa::ToSchema;
+
+use crate::config::UploadConfig;
+use crate::error::{ApiError, Problem};
+use crate::events::EventBus;
+
+const CHECKSUM_HEADER: &str = "x-checksum-sha256";
+
+// Files uploaded through `/uploads`. Finished files live directly in `dir`,
+// uploads in progress in `dir/.partial` and the SHA-256 of every finished
+// file in `dir/.sha256`.
+pub struct UploadStore {
+    dir: PathBuf,
+    max_file_size: u64,
+    max_total_size: u64,
+    state: Mutex<State>,
+}
+
+struct State {
+    // bytes on disk, finished and partial files together
+    used: u64,
+    // names currently receiving data
+    active: HashSet<String>,
+    // running checksums of partial uploads, kept between resumable chunks
+    hashers: HashMap<String, Sha256>,
+}
+
+impl UploadStore {
+    pub fn open(config: &UploadConfig) -> io::Result<Self> {
+        let dir = config.dir.clone();
+        std::fs::create_dir_all(dir.join(".partial"))?;
+        std::fs::create_dir_all(dir.join(".sha256"))?;
+
+        let used = dir_size(&dir)? + dir_size(&dir.join(".partial"))?;
+
+        Ok(UploadStore {
+            dir,
+            max_file_size: config.max_file_size,
+            max_total_size: config.max_total_size,
+            state: Mutex::new(State {
+                used,
+                active: HashSet::new(),
+                hashers: HashMap::new(),
+            }),
+        })
+    }
+
+    // Opens a finished upload for reading, e.g. from a background job.
+    pub fn open_file(&self, name: &str) -> io::Result<std::fs::File> {
+        check_name(name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        std::fs::File::open(self.final_path(name))
+    }
+
+    fn final_path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn partial_path(&self, name: &str) -> PathBuf {
+        self.dir.join(".partial").join(name)
+    }
+
+    fn checksum_path(&self, name: &str) -> PathBuf {
+        self.dir.join(".sha256").join(name)
+    }
+
+    // Marks `name` as receiving data, only one request may write to it at once.
+    fn begin(&self, name: &str) -> Result<ActiveUpload<'_>, ApiError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.active.insert(name.to_string()) {
+            return Err(ApiError::Conflict(format!(
+                "{name} is already being uploaded"
+            )));
+        }
+
+        Ok(ActiveUpload {
+            store: self,
+            name: name.to_string(),
+        })
+    }
+
+    fn reserve(&self, bytes: u64) -> Result<(), ApiError> {
+        let mut state = self.state.lock().unwrap();
+        if state.used + bytes > self.max_total_size {
+            return Err(ApiError::QuotaExceeded(format!(
+                "uploads are limited to {} bytes in total",
+                self.max_total_size
+            )));
+        }
+
+        state.used += bytes;
+        Ok(())
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.used = state.used.saturating_sub(bytes);
+    }
+}
+
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+struct ActiveUpload<'a> {
+    store: &'a UploadStore,
+    name: String,
+}
+
+impl Drop for ActiveUpload<'_> {
+    fn drop(&mut self) {
+        let mut state = self.store.state.lock().unwrap();
+        state.active.remove(&self.name);
+    }
+}
+
+// Upload names become file names, so only a conservative set of characters
+// is allowed and hidden files (our bookkeeping directories) are off limits.
+fn check_name(name: &str) -> Result<(), ApiError> {
+    let valid = !name.is_empty()
+        && name.len() <= 255
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiError::InvalidPath(format!(
+            "{name:?} is not a valid upload name"
+        )))
+    }
+}
+
+// `Content-Range: bytes <start>-<end>/<total>` of a resumable upload chunk.
+#[derive(Debug, PartialEq)]
+struct ContentRange {
+    start: u64,
+    end: u64,
+    total: u64,
+}
+
+impl ContentRange {
+    fn parse(value: &HeaderValue) -> Result<Self, ApiError> {
+        let invalid = || {
+            ApiError::BadRequest(String::from(
+                "Content-Range must look like `bytes <start>-<end>/<total>`",
+            ))
+        };
+
+        let value = value.to_str().map_err(|_| invalid())?;
+        let (range, total) = value
+            .strip_prefix("bytes ")
+            .and_then(|v| v.split_once('/'))
+            .ok_or_else(invalid)?;
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+
+        let range = ContentRange {
+            start: start.trim().parse().map_err(|_| invalid())?,
+            end: end.trim().parse().map_err(|_| invalid())?,
+            total: total.trim().parse().map_err(|_| invalid())?,
+        };
+
+        if range.start > range.end || range.end >= range.total {
+            return Err(invalid());
+        }
+        Ok(range)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum Received {
+    Complete {
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    Partial {
+        name: String,
+        received: u64,
+    },
+}
+
+async fn hash_file(path: &Path) -> io::Result<Sha256> {
+    let mut hasher = Sha256::new();
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(hasher);
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+// Streams `body` into the partial file of `name`, hashing it on the way. With
+// a `range` the data is appended to an earlier partial upload, otherwise the
+// upload starts from scratch. The file is moved into place once all bytes
+// have arrived.
+async fn receive<S, E>(
+    store: &UploadStore,
+    name: &str,
+    range: Option<ContentRange>,
+    mut body: S,
+) -> Result<Received, ApiError>
+where
+    S: Stream<Item = Result<web::Bytes, E>> + Unpin,
+    E: Display,
+{
+    let _active = store.begin(name)?;
+    let partial = store.partial_path(name);
+    let current = match tokio::fs::metadata(&partial).await {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err.into()),
+    };
+
+    let (mut hasher, mut offset) = match &range {
+        None => {
+            if current > 0 {
+                tokio::fs::remove_file(&partial).await?;
+                store.release(current);
+            }
+            (Sha256::new(), 0)
+        }
+        Some(range) => {
+            if range.start != current {
+                return Err(ApiError::Conflict(format!(
+                    "{name} has {current} bytes, the next chunk must start there"
+                )));
+            }
+            if range.total > store.max_file_size {
+                return Err(ApiError::PayloadTooLarge {
+                    limit: store.max_file_size as usize,
+                });
+            }
+
+            let kept = store.state.lock().unwrap().hashers.remove(name);
+            let hasher = match kept {
+                Some(hasher) => hasher,
+                // the server restarted since the last chunk
+                None if current > 0 => hash_file(&partial).await?,
+                None => Sha256::new(),
+            };
+            (hasher, current)
+        }
+    };
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&partial)
+        .await?;
+
+    let written = async {
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|err| ApiError::BadRequest(err.to_string()))?;
+            let len = chunk.len() as u64;
+
+            if offset + len > store.max_file_size {
+                return Err(ApiError::PayloadTooLarge {
+                    limit: store.max_file_size as usize,
+                });
+            }
+            if let Some(range) = &range {
+                if offset + len > range.end + 1 {
+                    return Err(ApiError::BadRequest(String::from(
+                        "body is longer than the Content-Range",
+                    )));
+                }
+            }
+
+            store.reserve(len)?;
+            if let Err(err) = file.write_all(&chunk).await {
+                store.release(len);
+                return Err(err.into());
+            }
+            hasher.update(&chunk);
+            offset += len;
+        }
+
+        file.sync_all().await?;
+        Ok(())
+    }
+    .await;
+
+    let complete = match &range {
+        None => true,
+        Some(range) => offset == range.total,
+    };
+
+    if written.is_err() || !complete {
+        // keep what we have so the client can resume from `offset`
+        store
+            .state
+            .lock()
+            .unwrap()
+            .hashers
+            .insert(name.to_string(), hasher);
+        written?;
+
+        return Ok(Received::Partial {
+            name: name.to_string(),
+            received: offset,
+        });
+    }
+
+    let sha256 = hex::encode(hasher.finalize());
+    let target = store.final_path(name);
+    if let Ok(old) = tokio::fs::metadata(&target).await {
+        store.release(old.len());
+    }
+    tokio::fs::rename(&partial, &target).await?;
+    tokio::fs::write(store.checksum_path(name), &sha256).await?;
+
+    Ok(Received::Complete {
+        name: name.to_string(),
+        size: offset,
+        sha256,
+    })
+}
+
+fn declared_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn received_response(req: &HttpRequest, received: Received) -> Result<HttpResponse, ApiError> {
+    match &received {
+        Received::Complete { name, .. } => {
+            let location = req
+                .url_for("upload", [name])
+                .map_err(|err| ApiError::Internal(err.to_string()))?;
+
+            Ok(HttpResponse::Created()
+                .insert_header((header::LOCATION, location.path()))
+                .json(received))
+        }
+        Received::Partial { received: 0, .. } => Ok(HttpResponse::Accepted().json(received)),
+        Received::Partial { received: n, .. } => Ok(HttpResponse::Accepted()
+            .insert_header((header::RANGE, format!("bytes=0-{}", n - 1)))
+            .json(received)),
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("").route(web::post().to(upload_multipart)))
+        .service(
+            web::resource("/{name}")
+                .name("upload")
+                .route(web::put().to(upload))
+                .route(web::get().to(download)),
+        )
+        .service(web::resource("/{name}/status").route(web::get().to(status)));
+}
+
+// PUT the raw file as the body. Large files can be sent in several requests,
+// each carrying a `Content-Range` that starts where the previous one ended.
+#[utoipa::path(
+    put,
+    path = "/uploads/{name}",
+    params(("name" = String, Path), ("content-range" = Option<String>, Header)),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 201, description = "Upload complete", body = Received, headers(("location"))),
+        (status = 202, description = "More ranges expected", body = Received, headers(("range"))),
+        (status = 400, response = Problem),
+        (status = 413, response = Problem),
+        (status = 507, response = Problem),
+    )
+)]
+pub async fn upload(
+    req: HttpRequest,
+    store: web::Data<UploadStore>,
+    bus: web::Data<EventBus>,
+    name: web::Path<String>,
+    body: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    check_name(&name)?;
+
+    let range = match req.headers().get(header::CONTENT_RANGE) {
+        Some(value) => Some(ContentRange::parse(value)?),
+        None => None,
+    };
+    if range.is_none() && declared_length(&req).is_some_and(|len| len > store.max_file_size) {
+        return Err(ApiError::PayloadTooLarge {
+            limit: store.max_file_size as usize,
+        });
+    }
+
+    let received = receive(&store, &name, range, body).await?;
+    if let Received::Complete { .. } = received {
+        bus.publish("upload", &received);
+    }
+    received_response(&req, received)
+}
+
+// `multipart/form-data` upload, every part with a file name is stored.
+#[utoipa::path(
+    post,
+    path = "/uploads",
+    request_body(content = Vec<u8>, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, body = Vec<Received>),
+        (status = 400, response = Problem),
+        (status = 413, response = Problem),
+        (status = 507, response = Problem),
+    )
+)]
+pub async fn upload_multipart(
+    req: HttpRequest,
+    store: web::Data<UploadStore>,
+    bus: web::Data<EventBus>,
+    mut form: Multipart,
+) -> Result<HttpResponse, ApiError> {
+    if declared_length(&req).is_some_and(|len| len > store.max_total_size) {
+        return Err(ApiError::PayloadTooLarge {
+            limit: store.max_total_size as usize,
+        });
+    }
+
+    let mut stored = Vec::new();
+    while let Some(field) = form.next().await {
+        let mut field = field.map_err(|err| ApiError::BadRequest(err.to_string()))?;
+        let file_name = field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename())
+            // browsers may send a full path
+            .and_then(|name| name.rsplit(['/', '\\']).next())
+            .map(str::to_string);
+
+        let Some(name) = file_name else {
+            // drain plain form fields
+            while field.next().await.is_some() {}
+            continue;
+        };
+        check_name(&name)?;
+
+        let received = receive(&store, &name, None, field).await?;
+        bus.publish("upload", &received);
+        stored.push(received);
+    }
+
+    if stored.is_empty() {
+        return Err(ApiError::BadRequest(String::from(
+            "the form did not contain any files",
+        )));
+    }
+    Ok(HttpResponse::Created().json(stored))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UploadStatus {
+    name: String,
+    received: u64,
+    complete: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/uploads/{name}/status",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = UploadStatus),
+        (status = 404, response = Problem),
+    )
+)]
+pub async fn status(
+    store: web::Data<UploadStore>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    check_name(&name)?;
+
+    let partial = tokio::fs::metadata(store.partial_path(&name)).await.ok();
+    let finished = tokio::fs::metadata(store.final_path(&name)).await.ok();
+
+    let status = match (partial, finished) {
+        (Some(partial), _) => UploadStatus {
+            name: name.into_inner(),
+            received: partial.len(),
+            complete: false,
+        },
+        (None, Some(finished)) => UploadStatus {
+            name: name.into_inner(),
+            received: finished.len(),
+            complete: true,
+        },
+        (None, None) => return Err(ApiError::NotFound(format!("no upload named {name}"))),
+    };
+
+    Ok(HttpResponse::Ok().json(status))
+}
+
+// Serves a finished upload, with support for `Range` requests and the
+// checksum computed while it was received. Uploads are never rendered by the
+// browser: an uploaded page or SVG would run its scripts on our origin, next
+// to the session cookies.
+#[utoipa::path(
+    get,
+    path = "/uploads/{name}",
+    params(("name" = String, Path), ("range" = Option<String>, Header)),
+    responses(
+        (status = 200, body = Vec<u8>, content_type = "application/octet-stream", headers(("x-checksum-sha256"))),
+        (status = 206, body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 404, response = Problem),
+    )
+)]
+pub async fn download(
+    req: HttpRequest,
+    store: web::Data<UploadStore>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    check_name(&name)?;
+
+    let file = NamedFile::open_async(store.final_path(&name))
+        .await
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => ApiError::NotFound(format!("no upload named {name}")),
+            _ => err.into(),
+        })?;
+    let checksum = tokio::fs::read_to_string(store.checksum_path(&name))
+        .await
+        .ok();
+
+    let mut res = file
+        .set_content_type(mime::APPLICATION_OCTET_STREAM)
+        .set_content_disposition(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(name.to_string())],
+        })
+        .use_etag(true)
+        .use_last_modified(true)
+        .respond_to(&req)
+        .map_into_boxed_body();
+    res.headers_mut().insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    if let Some(value) = checksum.and_then(|c| HeaderValue::from_str(&c).ok()) {
+        res.headers_mut()
+            .insert(header::HeaderName::from_static(CHECKSUM_HEADER), value);
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::{http::StatusCode, App};
+    use serde_json::Value;
+    use std::time::Duration;
+
+    fn store(dir: &Path, max_file_size: u64, max_total_size: u64) -> UploadStore {
+        UploadStore::open(&UploadConfig {
+            dir: dir.to_path_buf(),
+            max_file_size,
+            max_total_size,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_content_range() {
+        let value = HeaderValue::from_static("bytes 0-9/20");
+        assert_eq!(
+            ContentRange::parse(&value).unwrap(),
+            ContentRange {
+                start: 0,
+                end: 9,
+                total: 20
+            }
+        );
+
+        for bad in [
+            "bytes 0-9/*",
+            "bytes 9-0/20",
+            "bytes 0-20/20",
+            "items 0-1/2",
+        ] {
+            assert!(ContentRange::parse(&HeaderValue::from_static(bad)).is_err());
+        }
+    }
+
+    #[actix_web::test]
+    async fn resumable_upload_and_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(store(dir.path(), 1024, 4096)))
+                .app_data(web::Data::new(EventBus::new(16, Duration::from_secs(15))))
+                .service(web::scope("/uploads").configure(config)),
+        )
+        .await;
+
+        let req = TestRequest::put()
+            .uri("/uploads/hello.txt")
+            .insert_header((header::CONTENT_RANGE, "bytes 0-5/11"))
+            .set_payload("hello ")
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(res.headers().get(header::RANGE).unwrap(), "bytes=0-5");
+
+        // a chunk that doesn't continue where the last one ended
+        let req = TestRequest::put()
+            .uri("/uploads/hello.txt")
+            .insert_header((header::CONTENT_RANGE, "bytes 3-10/11"))
+            .set_payload("lo world")
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let req = TestRequest::put()
+            .uri("/uploads/hello.txt")
+            .insert_header((header::CONTENT_RANGE, "bytes 6-10/11"))
+            .set_payload("world")
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body: Value = read_body_json(res).await;
+        assert_eq!(
+            body["sha256"],
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+
+        let req = TestRequest::get().uri("/uploads/hello.txt").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CHECKSUM_HEADER).unwrap(),
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        assert_eq!(&read_body(res).await[..], b"hello world");
+    }
+
+    #[actix_web::test]
+    async fn enforces_quotas_and_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(store(dir.path(), 8, 12)))
+                .app_data(web::Data::new(EventBus::new(16, Duration::from_secs(15))))
+                .service(web::scope("/uploads").configure(config)),
+        )
+        .await;
+
+        let put = |name: &str, body: &'static str| {
+            TestRequest::put()
+                .uri(&format!("/uploads/{name}"))
+                .set_payload(body)
+                .to_request()
+        };
+
+        let res = call_service(&app, put("big", "123456789")).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let res = call_service(&app, put("a", "12345678")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = call_service(&app, put("b", "12345678")).await;
+        assert_eq!(res.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+        // replacing a file frees the space of the old one
+        let res = call_service(&app, put("a", "1234")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = call_service(&app, put(".partial", "x")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn multipart_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(store(dir.path(), 1024, 4096)))
+                .app_data(web::Data::new(EventBus::new(16, Duration::from_secs(15))))
+                .service(web::scope("/uploads").configure(config)),
+        )
+        .await;
+
+        let body = "--XX\r\n\
+            Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+            ignored\r\n\
+            --XX\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"docs/a.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            abc\r\n\
+            --XX--\r\n";
+        let req = TestRequest::post()
+            .uri("/uploads")
+            .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=XX"))
+            .set_payload(body)
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body[0]["name"], "a.txt");
+        assert_eq!(body[0]["size"], 3);
+        assert_eq!(std::fs::read(dir.path().join("a.txt")).unwrap(), b"abc");
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use actix_web::{
+    http::header::{self, EntityTag, Header, IfMatch},
+    web, HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::error::{ApiError, Problem};
+use crate::handlers::{Info, USERNAME};
+use crate::validate::{Valid, Validate, Validator};
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct User {
+    id: u64,
+    username: String,
+    // bumped on every update, used as the ETag
+    #[serde(skip)]
+    version: u64,
+}
+
+impl User {
+    fn etag(&self) -> EntityTag {
+        EntityTag::new_strong(format!("{}-{}", self.id, self.version))
+    }
+}
+
+#[derive(Default)]
+struct UserTable {
+    next_id: u64,
+    users: BTreeMap<u64, User>,
+}
+
+// In-memory user repository shared by all workers.
+#[derive(Default)]
+pub struct UserStore {
+    table: Mutex<UserTable>,
+}
+
+impl UserStore {
+    pub fn new() -> Self {
+        UserStore::default()
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("")
+            .route(web::get().to(list_users))
+            .route(web::post().to(create_user)),
+    )
+    .service(
+        web::resource("/{id}")
+            .name("user")
+            .route(web::get().to(get_user))
+            .route(web::patch().to(update_user))
+            .route(web::delete().to(delete_user)),
+    );
+}
+
+// Checks the request's `If-Match` header against the current version.
+// A missing header always matches.
+fn if_match(req: &HttpRequest, user: &User) -> bool {
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return true;
+    }
+
+    match IfMatch::parse(req) {
+        Ok(IfMatch::Any) => true,
+        Ok(IfMatch::Items(tags)) => tags.iter().any(|tag| tag.strong_eq(&user.etag())),
+        Err(_) => false,
+    }
+}
+
+fn with_etag(mut res: actix_web::HttpResponseBuilder, user: &User) -> HttpResponse {
+    res.insert_header(header::ETag(user.etag())).json(user)
+}
+
+#[utoipa::path(
+    post,
+    path = "/users",
+    request_body = Info,
+    security(("bearer" = [])),
+    responses(
+        (status = 201, body = User, headers(("location"), ("etag"))),
+        (status = 400, response = Problem),
+        (status = 401, response = Problem),
+        (status = 422, response = Problem),
+    )
+)]
+pub async fn create_user(
+    req: HttpRequest,
+    store: web::Data<UserStore>,
+    info: Valid<web::Json<Info>>,
+) -> Result<HttpResponse, ApiError> {
+    let user = {
+        let mut table = store.table.lock().unwrap();
+        table.next_id += 1;
+
+        let user = User {
+            id: table.next_id,
+            username: info.0.into_inner().username,
+            version: 1,
+        };
+        table.users.insert(user.id, user.clone());
+        user
+    };
+
+    let location = req
+        .url_for("user", [user.id.to_string()])
+        .map_err(|err| ApiError::Internal(err.to_string()))?;
+    let mut res = HttpResponse::Created();
+    res.insert_header((header::LOCATION, location.path()));
+
+    Ok(with_etag(res, &user))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    // case-insensitive substring match on the username
+    username: Option<String>,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Serialize, ToSchema)]
+pub struct UserPage {
+    items: Vec<User>,
+    total: usize,
+    offset: usize,
+    limit: usize,
+}
+
+#[utoipa::path(
+    get,
+    path = "/users",
+    params(ListQuery),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = UserPage),
+        (status = 401, response = Problem),
+    )
+)]
+pub async fn list_users(store: web::Data<UserStore>, query: web::Query<ListQuery>) -> HttpResponse {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let needle = query.username.as_deref().map(str::to_lowercase);
+
+    let table = store.table.lock().unwrap();
+    let matching: Vec<&User> = table
+        .users
+        .values()
+        .filter(|user| match &needle {
+            Some(needle) => user.username.to_lowercase().contains(needle),
+            None => true,
+        })
+        .collect();
+
+    let page = UserPage {
+        total: matching.len(),
+        items: matching
+            .into_iter()
+            .skip(query.offset)
+            .take(limit)
+            .cloned()
+            .collect(),
+        offset: query.offset,
+        limit,
+    };
+
+    HttpResponse::Ok().json(page)
+}
+
+fn not_found() -> ApiError {
+    ApiError::NotFound(String::from("user not found"))
+}
+
+fn modified() -> ApiError {
+    ApiError::PreconditionFailed(String::from("user was modified"))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{id}",
+    params(("id" = u64, Path)),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = User, headers(("etag"))),
+        (status = 401, response = Problem),
+        (status = 404, response = Problem),
+    )
+)]
+pub async fn get_user(
+    store: web::Data<UserStore>,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, ApiError> {
+    let table = store.table.lock().unwrap();
+    let user = table.users.get(&id).ok_or_else(not_found)?;
+
+    Ok(with_etag(HttpResponse::Ok(), user))
+}
+
+// partial update, every field is optional
+#[derive(Deserialize, ToSchema)]
+pub struct UserPatch {
+    username: Option<String>,
+}
+
+// the fields that are sent follow the same rules as on create
+impl Validate for UserPatch {
+    fn rules(&self, v: &mut Validator) {
+        if let Some(username) = &self.username {
+            v.check("username", username, USERNAME);
+        }
+    }
+}
+
+#[utoipa::path(
+    patch,
+    path = "/users/{id}",
+    params(("id" = u64, Path), ("if-match" = Option<String>, Header)),
+    request_body = UserPatch,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, body = User, headers(("etag"))),
+        (status = 401, response = Problem),
+        (status = 404, response = Problem),
+        (status = 412, response = Problem),
+        (status = 422, response = Problem),
+    )
+)]
+pub async fn update_user(
+    req: HttpRequest,
+    store: web::Data<UserStore>,
+    id: web::Path<u64>,
+    patch: Valid<web::Json<UserPatch>>,
+) -> Result<HttpResponse, ApiError> {
+    let mut table = store.table.lock().unwrap();
+    let user = table.users.get_mut(&id).ok_or_else(not_found)?;
+
+    if !if_match(&req, user) {
+        return Err(modified());
+    }
+
+    if let Some(username) = patch.0.into_inner().username {
+        user.username = username;
+    }
+    user.version += 1;
+
+    Ok(with_etag(HttpResponse::Ok(), user))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{id}",
+    params(("id" = u64, Path), ("if-match" = Option<String>, Header)),
+    security(("bearer" = [])),
+    responses(
+        (status = 204),
+        (status = 401, response = Problem),
+        (status = 404, response = Problem),
+        (status = 412, response = Problem),
+    )
+)]
+pub async fn delete_user(
+    req: HttpRequest,
+    store: web::Data<UserStore>,
+    id: web::Path<u64>,
+) -> Result<HttpResponse, ApiError> {
+    let mut table = store.table.lock().unwrap();
+    let user = table.users.get(&id).ok_or_else(not_found)?;
+
+    if !if_match(&req, user) {
+        return Err(modified());
+    }
+
+    table.users.remove(&id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App};
+    use serde_json::{json, Value};
+
+    #[actix_web::test]
+    async fn crud_with_optimistic_concurrency() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(UserStore::new()))
+                .service(web::scope("/users").configure(config)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(json!({ "username": "alice" }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers().get(header::LOCATION).unwrap(), "/users/1");
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+
+        for username in ["", "two words"] {
+            let req = test::TestRequest::patch()
+                .uri("/users/1")
+                .set_json(json!({ "username": username }))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+
+        let req = test::TestRequest::patch()
+            .uri("/users/1")
+            .insert_header((header::IF_MATCH, etag.clone()))
+            .set_json(json!({ "username": "alicia" }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_ne!(res.headers().get(header::ETAG), Some(&etag));
+
+        // the old etag is now stale
+        let req = test::TestRequest::patch()
+            .uri("/users/1")
+            .insert_header((header::IF_MATCH, etag))
+            .set_json(json!({ "username": "mallory" }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        let req = test::TestRequest::get().uri("/users/1").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!({ "id": 1, "username": "alicia" }));
+
+        let req = test::TestRequest::delete().uri("/users/1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get().uri("/users/1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn list_filters_and_paginates() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(UserStore::new()))
+                .service(web::scope("/users").configure(config)),
+        )
+        .await;
+
+        for name in ["ann", "bob", "anna", "joanne"] {
+            let req = test::TestRequest::post()
+                .uri("/users")
+                .set_json(json!({ "username": name }))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/users?username=ANN&offset=1&limit=1")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["total"], 3);
+        assert_eq!(body["items"], json!([{ "id": 3, "username": "anna" }]));
+    }
+}
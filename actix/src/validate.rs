@@ -0,0 +1,267 @@
+use std::ops::Deref;
+
+use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use futures::future::LocalBoxFuture;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::error::ApiError;
+
+// A constraint on one field of a request body.
+pub enum Rule {
+    // present and, for text, not empty
+    Required,
+    // length in characters, inclusive
+    Length(usize, usize),
+    // every character passes the check; the text describes the allowed ones
+    Charset(&'static str, fn(char) -> bool),
+    // inclusive
+    Range(i64, i64),
+}
+
+// Field types rules can be checked against. Missing values are only an error
+// for `Rule::Required`, the other rules skip them.
+pub trait Field {
+    fn is_missing(&self) -> bool;
+
+    fn text(&self) -> Option<&str> {
+        None
+    }
+
+    fn number(&self) -> Option<i64> {
+        None
+    }
+}
+
+impl Field for String {
+    fn is_missing(&self) -> bool {
+        self.is_empty()
+    }
+
+    fn text(&self) -> Option<&str> {
+        Some(self)
+    }
+}
+
+impl Field for i32 {
+    fn is_missing(&self) -> bool {
+        false
+    }
+
+    fn number(&self) -> Option<i64> {
+        Some(i64::from(*self))
+    }
+}
+
+impl Field for u64 {
+    fn is_missing(&self) -> bool {
+        false
+    }
+
+    fn number(&self) -> Option<i64> {
+        Some(i64::try_from(*self).unwrap_or(i64::MAX))
+    }
+}
+
+impl<T: Field> Field for Option<T> {
+    fn is_missing(&self) -> bool {
+        self.as_ref().is_none_or(Field::is_missing)
+    }
+
+    fn text(&self) -> Option<&str> {
+        self.as_ref().and_then(Field::text)
+    }
+
+    fn number(&self) -> Option<i64> {
+        self.as_ref().and_then(Field::number)
+    }
+}
+
+// One failed constraint, listed in the `errors` of a 422 problem.
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: &'static str,
+    // `required`, `length`, `charset` or `range`
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &'static str, code: &'static str, message: String) -> Self {
+        FieldError {
+            field,
+            code,
+            message,
+        }
+    }
+}
+
+// Collects the failures of every field, reporting the first broken rule of
+// each.
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn check(&mut self, name: &'static str, value: &impl Field, rules: &[Rule]) {
+        if value.is_missing() {
+            if rules.iter().any(|rule| matches!(rule, Rule::Required)) {
+                self.errors.push(FieldError::new(
+                    name,
+                    "required",
+                    String::from("is required"),
+                ));
+            }
+            return;
+        }
+
+        for rule in rules {
+            if let Some(error) = broken(name, value, rule) {
+                self.errors.push(error);
+                return;
+            }
+        }
+    }
+}
+
+fn broken(name: &'static str, value: &impl Field, rule: &Rule) -> Option<FieldError> {
+    match *rule {
+        Rule::Required => None,
+        Rule::Length(min, max) => {
+            let len = value.text()?.chars().count();
+            (len < min || len > max).then(|| {
+                FieldError::new(
+                    name,
+                    "length",
+                    format!("must be {min} to {max} characters long"),
+                )
+            })
+        }
+        Rule::Charset(allowed, check) => {
+            let ok = value.text()?.chars().all(check);
+            (!ok).then(|| FieldError::new(name, "charset", format!("may only contain {allowed}")))
+        }
+        Rule::Range(min, max) => {
+            let number = value.number()?;
+            (number < min || number > max)
+                .then(|| FieldError::new(name, "range", format!("must be between {min} and {max}")))
+        }
+    }
+}
+
+// Request types declare their constraints by checking each field.
+pub trait Validate {
+    fn rules(&self, v: &mut Validator);
+
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut v = Validator::default();
+        self.rules(&mut v);
+
+        if v.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::Validation(v.errors))
+        }
+    }
+}
+
+// Extracts `E`, then validates what it derefs to, e.g.
+// `Valid<web::Form<FormData>>`. The handler only runs for valid input.
+pub struct Valid<E>(pub E);
+
+impl<E> Deref for Valid<E> {
+    type Target = E;
+
+    fn deref(&self) -> &E {
+        &self.0
+    }
+}
+
+impl<E> FromRequest for Valid<E>
+where
+    E: FromRequest + Deref + 'static,
+    E::Target: Validate,
+    E::Error: Into<actix_web::Error>,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let extracted = E::from_request(req, payload);
+
+        Box::pin(async move {
+            let value = extracted.await.map_err(Into::into)?;
+            value.validate()?;
+            Ok(Valid(value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Signup {
+        name: String,
+        age: Option<i32>,
+        nickname: Option<String>,
+    }
+
+    impl Validate for Signup {
+        fn rules(&self, v: &mut Validator) {
+            v.check(
+                "name",
+                &self.name,
+                &[
+                    Rule::Required,
+                    Rule::Length(2, 5),
+                    Rule::Charset("lowercase letters", |c| c.is_ascii_lowercase()),
+                ],
+            );
+            v.check("age", &self.age, &[Rule::Required, Rule::Range(0, 150)]);
+            v.check("nickname", &self.nickname, &[Rule::Length(1, 3)]);
+        }
+    }
+
+    fn errors(signup: Signup) -> Vec<(&'static str, &'static str)> {
+        match signup.validate() {
+            Ok(()) => Vec::new(),
+            Err(ApiError::Validation(errors)) => {
+                errors.into_iter().map(|e| (e.field, e.code)).collect()
+            }
+            Err(err) => panic!("unexpected {err:?}"),
+        }
+    }
+
+    #[test]
+    fn reports_the_first_broken_rule_of_each_field() {
+        let valid = Signup {
+            name: String::from("ann"),
+            age: Some(30),
+            nickname: None,
+        };
+        assert_eq!(errors(valid), []);
+
+        let invalid = Signup {
+            name: String::from("Ann-Marie"),
+            age: None,
+            nickname: Some(String::from("annie")),
+        };
+        assert_eq!(
+            errors(invalid),
+            [
+                ("name", "length"),
+                ("age", "required"),
+                ("nickname", "length")
+            ]
+        );
+
+        let invalid = Signup {
+            name: String::from("An"),
+            age: Some(200),
+            nickname: Some(String::new()),
+        };
+        assert_eq!(errors(invalid), [("name", "charset"), ("age", "range")]);
+    }
+}
@@ -0,0 +1,380 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    dev::{forward_ready, RequestHead, Service, ServiceRequest, ServiceResponse, Transform},
+    guard::{Guard, GuardContext},
+    http::header::{self, HeaderName, HeaderValue, HttpDate},
+    web, Error, HttpRequest, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::Serialize;
+use utoipa::openapi::Deprecated;
+use utoipa::{OpenApi, ToSchema};
+
+use crate::error::ApiError;
+use crate::{handlers, people};
+
+const ACCEPT_VERSION: &str = "accept-version";
+
+// `application/vnd.actix.v2+json` asks for version 2 through `Accept`.
+const MEDIA_TYPE_PREFIX: &str = "application/vnd.actix.v";
+
+// Served to `/api` requests that don't ask for a version, which is what
+// clients that predate versioning expect.
+const DEFAULT_VERSION: u32 = 1;
+
+// One version of the API under `/api`. Its routes are served at
+// `/api/v{number}`, and at `/api` to requests that ask for it with
+// `Accept-Version` or the versioned media type.
+pub struct Version {
+    pub number: u32,
+    configure: fn(&mut web::ServiceConfig),
+    // the routes, documented relative to the version root
+    doc: fn() -> utoipa::openapi::OpenApi,
+    // HTTP dates, set once a newer version replaces this one
+    deprecated: Option<&'static str>,
+    sunset: Option<&'static str>,
+}
+
+pub const VERSIONS: &[Version] = &[
+    Version {
+        number: 1,
+        configure: v1,
+        doc: V1Doc::openapi,
+        deprecated: Some("Thu, 01 Oct 2026 00:00:00 GMT"),
+        sunset: Some("Fri, 01 Oct 2027 00:00:00 GMT"),
+    },
+    Version {
+        number: 2,
+        configure: v2,
+        doc: V2Doc::openapi,
+        deprecated: None,
+        sunset: None,
+    },
+];
+
+// The routes as they were before versioning, also still served unprefixed.
+fn v1(cfg: &mut web::ServiceConfig) {
+    cfg.service(people::list_people)
+        .service(handlers::person_auto)
+        .service(handlers::person_manual)
+        .service(handlers::json_response)
+        .configure(handlers::scoped_config);
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    crate::people::list_people,
+    crate::handlers::person_auto,
+    crate::handlers::person_manual,
+    crate::handlers::json_response,
+    crate::handlers::scoped_test,
+))]
+struct V1Doc;
+
+fn v2(cfg: &mut web::ServiceConfig) {
+    cfg.service(people::get_people)
+        .service(people::create_person)
+        .service(people::get_person);
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    crate::people::get_people,
+    crate::people::create_person,
+    crate::people::get_person,
+))]
+struct V2Doc;
+
+impl Version {
+    fn path(&self) -> String {
+        format!("/api/v{}", self.number)
+    }
+
+    fn media_type(&self) -> String {
+        format!("{MEDIA_TYPE_PREFIX}{}+json", self.number)
+    }
+
+    // The documentation of every route of this version, at both places it is
+    // served. Operations are flagged as deprecated along with the version.
+    pub fn openapi(&self) -> utoipa::openapi::OpenApi {
+        let mut doc = (self.doc)();
+        let relative = std::mem::take(&mut doc.paths.paths);
+
+        for (path, mut item) in relative {
+            if self.deprecated.is_some() {
+                let operations = [
+                    &mut item.get,
+                    &mut item.put,
+                    &mut item.post,
+                    &mut item.delete,
+                    &mut item.options,
+                    &mut item.head,
+                    &mut item.patch,
+                    &mut item.trace,
+                ];
+                for operation in operations.into_iter().flatten() {
+                    operation.deprecated = Some(Deprecated::True);
+                }
+            }
+            doc.paths
+                .paths
+                .insert(format!("{}{path}", self.path()), item.clone());
+            doc.paths.paths.insert(format!("/api{path}"), item);
+        }
+
+        doc
+    }
+
+    fn headers(&self, vary: bool) -> VersionHeaders {
+        let mut headers = Vec::new();
+
+        if let Some(deprecated) = self.deprecated {
+            // validated by the `versions_are_consistent` test
+            let since = SystemTime::from(HttpDate::from_str(deprecated).unwrap());
+            let since = since.duration_since(UNIX_EPOCH).unwrap_or_default();
+            headers.push((
+                HeaderName::from_static("deprecation"),
+                HeaderValue::from_str(&format!("@{}", since.as_secs())).unwrap(),
+            ));
+            if let Some(successor) = VERSIONS.last().filter(|v| v.number != self.number) {
+                headers.push((
+                    header::LINK,
+                    HeaderValue::from_str(&format!(
+                        "<{}>; rel=\"successor-version\"",
+                        successor.path()
+                    ))
+                    .unwrap(),
+                ));
+            }
+        }
+        if let Some(sunset) = self.sunset {
+            headers.push((
+                HeaderName::from_static("sunset"),
+                HeaderValue::from_static(sunset),
+            ));
+        }
+        // the same path answers differently depending on the requested version
+        if vary {
+            headers.push((
+                header::VARY,
+                HeaderValue::from_static("accept-version, accept"),
+            ));
+        }
+
+        VersionHeaders {
+            headers: Rc::new(headers),
+        }
+    }
+}
+
+// The version a request asks for with `Accept-Version` (`2` or `v2`) or an
+// `Accept` of `application/vnd.actix.v2+json`, unparsed.
+fn requested(head: &RequestHead) -> Option<String> {
+    if let Some(value) = head.headers().get(ACCEPT_VERSION) {
+        let value = value.to_str().unwrap_or_default().trim();
+        return Some(value.strip_prefix(['v', 'V']).unwrap_or(value).to_string());
+    }
+
+    head.headers()
+        .get_all(header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|range| {
+            let essence = range.split(';').next()?.trim().to_ascii_lowercase();
+            let version = essence
+                .strip_prefix(MEDIA_TYPE_PREFIX)?
+                .strip_suffix("+json")?;
+            Some(version.to_string())
+        })
+}
+
+// Matches requests that ask for `number`, or for no version when it is the
+// default one.
+struct VersionGuard {
+    number: u32,
+}
+
+impl Guard for VersionGuard {
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        match requested(ctx.head()) {
+            Some(version) => version == self.number.to_string(),
+            None => self.number == DEFAULT_VERSION,
+        }
+    }
+}
+
+// Registers `/api`: the list of versions, each version under its path, then
+// each version again for requests that pick it by header. A path version wins
+// over a header.
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/api").route(web::get().to(list_versions)));
+
+    for version in VERSIONS {
+        cfg.service(
+            web::scope(&version.path())
+                .wrap(version.headers(false))
+                .configure(version.configure),
+        );
+    }
+    for version in VERSIONS {
+        cfg.service(
+            web::scope("/api")
+                .guard(VersionGuard {
+                    number: version.number,
+                })
+                .wrap(version.headers(true))
+                .configure(version.configure),
+        );
+    }
+    cfg.service(web::scope("/api").default_service(web::to(unknown_version)));
+}
+
+async fn unknown_version(req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    match requested(req.head()) {
+        Some(version) if !VERSIONS.iter().any(|v| v.number.to_string() == version) => {
+            let known: Vec<_> = VERSIONS.iter().map(|v| v.number.to_string()).collect();
+            Err(ApiError::NotAcceptable(format!(
+                "unknown API version {version:?}, expected one of {}",
+                known.join(", ")
+            )))
+        }
+        _ => Err(ApiError::NotFound(String::from("Not found"))),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct VersionInfo {
+    version: u32,
+    path: String,
+    media_type: String,
+    // HTTP dates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deprecated: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sunset: Option<&'static str>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct VersionList {
+    // served to requests that don't ask for a version
+    default: u32,
+    versions: Vec<VersionInfo>,
+}
+
+#[utoipa::path(get, path = "/api", responses((status = 200, body = VersionList)))]
+pub async fn list_versions() -> HttpResponse {
+    HttpResponse::Ok().json(VersionList {
+        default: DEFAULT_VERSION,
+        versions: VERSIONS
+            .iter()
+            .map(|version| VersionInfo {
+                version: version.number,
+                path: version.path(),
+                media_type: version.media_type(),
+                deprecated: version.deprecated,
+                sunset: version.sunset,
+            })
+            .collect(),
+    })
+}
+
+// Adds a version's `Deprecation`, `Sunset`, `Link` and `Vary` headers to its
+// responses.
+#[derive(Clone)]
+struct VersionHeaders {
+    headers: Rc<Vec<(HeaderName, HeaderValue)>>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for VersionHeaders
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = VersionHeadersMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(VersionHeadersMiddleware {
+            service,
+            headers: self.headers.clone(),
+        }))
+    }
+}
+
+struct VersionHeadersMiddleware<S> {
+    service: S,
+    headers: Rc<Vec<(HeaderName, HeaderValue)>>,
+}
+
+impl<S, B> Service<ServiceRequest> for VersionHeadersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let fut = self.service.call(req);
+        let headers = self.headers.clone();
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            for (name, value) in headers.iter() {
+                res.headers_mut().append(name.clone(), value.clone());
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn versions_are_consistent() {
+        for (i, version) in VERSIONS.iter().enumerate() {
+            assert_eq!(version.number, i as u32 + 1);
+            for date in [version.deprecated, version.sunset].into_iter().flatten() {
+                assert!(
+                    HttpDate::from_str(date).is_ok(),
+                    "{date:?} is not an HTTP date"
+                );
+            }
+        }
+        assert!(VERSIONS.iter().any(|v| v.number == DEFAULT_VERSION));
+        assert!(VERSIONS.last().unwrap().deprecated.is_none());
+    }
+
+    #[test]
+    fn reads_the_requested_version() {
+        let requested = |name: &str, value: &str| {
+            let req = TestRequest::default()
+                .insert_header((name, value))
+                .to_http_request();
+            requested(req.head())
+        };
+
+        assert_eq!(requested("accept-version", "2").as_deref(), Some("2"));
+        assert_eq!(requested("accept-version", " v1 ").as_deref(), Some("1"));
+        assert_eq!(
+            requested("accept", "text/html, application/vnd.actix.v2+json; q=0.9").as_deref(),
+            Some("2")
+        );
+        assert_eq!(requested("accept", "application/json"), None);
+    }
+}
@@ -0,0 +1,1318 @@
+// End-to-end tests against the full application as built by `build_app`,
+// with every middleware, extractor config and default handler in place.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix::auth::{Audience, Claims};
+use actix::config::{Config, RateLimitConfig, UnknownHost};
+use actix::rate_limit::Rule;
+use actix::sessions::Account;
+use actix::{build_app, SharedState};
+use actix_http::Request;
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::{Argon2, Params};
+use futures::{stream, StreamExt};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+const SECRET: &str = "integration-test-secret-of-32-bytes";
+
+fn test_config(dir: &TempDir) -> Config {
+    let mut config = Config::default();
+    config.middleware.trace = false;
+    config.middleware.json_log = false;
+    config.limits.payload = 64;
+    config.limits.json = 64;
+    config.limits.form = 64;
+    config.auth.secret = Some(String::from(SECRET));
+    config.rate_limit.rules = vec![Rule {
+        path: String::from("/echo"),
+        capacity: 3,
+        per_second: 0.001,
+    }];
+    config.uploads.dir = dir.path().join("uploads");
+    config.people.path = dir.path().join("people.db");
+    config.jobs.path = dir.path().join("jobs.json");
+    config.audit.path = dir.path().join("audit.jsonl");
+    config.sessions.dir = dir.path().join("sessions");
+    config.sessions.accounts = vec![Account {
+        username: String::from("alice"),
+        password_hash: password_hash("wonderland"),
+    }];
+    config
+}
+
+// cheap parameters, verifying reads them from the hash
+fn password_hash(password: &str) -> String {
+    let params = Params::new(8, 1, 1, None).unwrap();
+    let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+    let salt = SaltString::encode_b64(b"integration-salt").unwrap();
+    argon2
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+async fn start(
+    config: &Config,
+) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+    let state = SharedState::new(config).unwrap();
+    init_service(build_app(config, &state)).await
+}
+
+fn token() -> String {
+    let exp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 60;
+    let claims = Claims {
+        sub: String::from("alice"),
+        aud: Audience::from("actix"),
+        exp,
+        nbf: None,
+    };
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(SECRET.as_bytes()),
+    )
+    .unwrap()
+}
+
+// Starts a session, returning its cookie and CSRF token.
+async fn session<S, B>(app: &S, cookie: Option<Cookie<'static>>) -> (Cookie<'static>, Value)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let mut req = TestRequest::get().uri("/session");
+    if let Some(cookie) = cookie.clone() {
+        req = req.cookie(cookie);
+    }
+    let res = call_service(app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let set = res
+        .response()
+        .cookies()
+        .find(|c| c.name() == "session")
+        .map(Cookie::into_owned);
+    let info: Value = read_body_json(res).await;
+
+    (set.or(cookie).unwrap(), info)
+}
+
+// Sends `req` and returns the status, the problem `code` for errors or the
+// raw body otherwise.
+async fn send<S, B>(app: &S, req: TestRequest) -> (StatusCode, String)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let res = call_service(app, req.to_request()).await;
+    let status = res.status();
+    let is_problem = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v == "application/problem+json");
+
+    let body = read_body(res).await;
+    if is_problem {
+        let problem: Value = serde_json::from_slice(&body).unwrap();
+        return (status, problem["code"].as_str().unwrap().to_string());
+    }
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+#[actix_web::test]
+async fn plain_routes() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    let cases = [
+        (TestRequest::get().uri("/"), "Hello actix web"),
+        (TestRequest::get().uri("/hey"), "Hey there!"),
+        (TestRequest::get().uri("/app"), "app"),
+        (TestRequest::get().uri("/api/test"), "test"),
+        (
+            TestRequest::get().uri("/json/response/bob"),
+            r#"{"name":"bob"}"#,
+        ),
+        (TestRequest::get().uri("/stream").set_payload("chunk"), ""),
+        (TestRequest::post().uri("/echo").set_payload("ping"), "ping"),
+    ];
+    for (req, expected) in cases {
+        assert_eq!(
+            send(&app, req).await,
+            (StatusCode::OK, expected.to_string())
+        );
+    }
+}
+
+#[actix_web::test]
+async fn method_guards_and_default_handler() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    let req = TestRequest::default().method(Method::HEAD).uri("/app");
+    assert_eq!(
+        send(&app, req).await,
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            String::from("method_not_allowed")
+        )
+    );
+
+    // unknown paths and known paths with the wrong method both end up in the
+    // single default handler
+    for req in [
+        TestRequest::get().uri("/nope"),
+        TestRequest::get().uri("/echo"),
+        TestRequest::delete().uri("/person/auto"),
+        TestRequest::get().uri("/api/nope"),
+        TestRequest::post().uri("/counter"),
+    ] {
+        assert_eq!(
+            send(&app, req).await,
+            (StatusCode::NOT_FOUND, String::from("not_found"))
+        );
+    }
+}
+
+#[actix_web::test]
+async fn counters() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    for n in 1..=2 {
+        let req = TestRequest::get().uri("/counter");
+        assert_eq!(
+            send(&app, req).await,
+            (StatusCode::OK, format!("Request number: {n}"))
+        );
+    }
+
+    let req = TestRequest::get().uri("/counter/hits");
+    assert_eq!(send(&app, req).await.0, StatusCode::NOT_FOUND);
+
+    let req = TestRequest::post().uri("/counter/hits");
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::OK, String::from(r#"{"name":"hits","value":1}"#))
+    );
+
+    let req = TestRequest::get().uri("/counter/hits");
+    let res = call_service(&app, req.to_request()).await;
+    let body: Value = read_body_json(res).await;
+    assert_eq!(body, json!({"name": "hits", "value": 1}));
+
+    let req = TestRequest::delete().uri("/counter/hits");
+    assert_eq!(send(&app, req).await.0, StatusCode::NO_CONTENT);
+    let req = TestRequest::delete().uri("/counter/hits");
+    assert_eq!(send(&app, req).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn json_payloads_and_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    let req = TestRequest::post()
+        .uri("/person/auto")
+        .set_json(json!({"username": "alice"}));
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::OK, String::from("welcome alice!"))
+    );
+
+    let cases = [
+        (r#"{"username": 1}"#, "application/json", "invalid_json"),
+        ("{", "application/json", "invalid_json"),
+        (
+            r#"{"username": "alice"}"#,
+            "text/plain",
+            "unsupported_media_type",
+        ),
+    ];
+    for (body, content_type, code) in cases {
+        let req = TestRequest::post()
+            .uri("/person/auto")
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .set_payload(body);
+        assert_eq!(send(&app, req).await.1, code);
+    }
+
+    let req = TestRequest::post()
+        .uri("/person/auto")
+        .set_json(json!({"username": "a".repeat(100)}));
+    assert_eq!(
+        send(&app, req).await,
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            String::from("payload_too_large")
+        )
+    );
+}
+
+#[actix_web::test]
+async fn manual_payload_and_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    let req = TestRequest::post()
+        .uri("/person/manual")
+        .set_payload(r#"{"name":"bob","number":3}"#);
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::OK, String::from(r#"{"name":"bob","number":3}"#))
+    );
+
+    let req = TestRequest::post()
+        .uri("/person/manual")
+        .set_payload("not json");
+    assert_eq!(send(&app, req).await.1, "invalid_json");
+
+    let req = TestRequest::post()
+        .uri("/person/manual")
+        .set_payload(format!(r#"{{"name":"{}"}}"#, "b".repeat(100)));
+    assert_eq!(
+        send(&app, req).await,
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            String::from("payload_too_large")
+        )
+    );
+}
+
+#[actix_web::test]
+async fn negotiated_formats() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    let mut cbor = Vec::new();
+    ciborium::into_writer(&json!({"username": "carol"}), &mut cbor).unwrap();
+    let req = TestRequest::post()
+        .uri("/person/auto")
+        .insert_header((header::CONTENT_TYPE, "application/cbor"))
+        .set_payload(cbor);
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::OK, String::from("welcome carol!"))
+    );
+
+    let req = TestRequest::post()
+        .uri("/person/manual")
+        .insert_header((header::CONTENT_TYPE, "application/x-www-form-urlencoded"))
+        .insert_header((header::ACCEPT, "text/html, application/xml;q=0.9"))
+        .set_payload("name=bob&number=3");
+    let res = call_service(&app, req.to_request()).await;
+    assert_eq!(
+        res.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/xml"
+    );
+    assert_eq!(
+        read_body(res).await,
+        "<Obj><name>bob</name><number>3</number></Obj>"
+    );
+
+    let req = TestRequest::get()
+        .uri("/json/response/bob")
+        .insert_header((header::ACCEPT, "application/msgpack"));
+    let res = call_service(&app, req.to_request()).await;
+    let body: Value = rmp_serde::from_slice(&read_body(res).await).unwrap();
+    assert_eq!(body, json!({"name": "bob"}));
+
+    let req = TestRequest::get()
+        .uri("/json/response/bob")
+        .insert_header((header::ACCEPT, "text/html"));
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::NOT_ACCEPTABLE, String::from("not_acceptable"))
+    );
+
+    let req = TestRequest::post()
+        .uri("/person/manual")
+        .insert_header((header::CONTENT_TYPE, "text/plain"))
+        .set_payload("bob");
+    assert_eq!(
+        send(&app, req).await,
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            String::from("unsupported_media_type")
+        )
+    );
+}
+
+#[actix_web::test]
+async fn conditional_and_cached_responses() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    let req = TestRequest::get().uri("/json/response/bob").to_request();
+    let res = call_service(&app, req).await;
+    assert_eq!(
+        res.headers().get(header::CACHE_CONTROL).unwrap(),
+        "public, max-age=60"
+    );
+    let etag = res.headers().get(header::ETAG).unwrap().clone();
+
+    let req = TestRequest::get()
+        .uri("/json/response/bob")
+        .insert_header((header::IF_NONE_MATCH, etag.clone()));
+    let res = call_service(&app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    assert!(read_body(res).await.is_empty());
+
+    // another representation is cached separately and has its own tag
+    let req = TestRequest::get()
+        .uri("/json/response/bob")
+        .insert_header((header::ACCEPT, "application/xml"))
+        .insert_header((header::IF_NONE_MATCH, etag));
+    let (status, body) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "<JsonResp><name>bob</name></JsonResp>");
+
+    let req = TestRequest::get().uri("/metrics");
+    let (_, body) = send(&app, req).await;
+    assert!(body.contains(r#"http_cache_events_total{event="hit"} 1"#));
+    assert!(body.contains(r#"http_cache_events_total{event="store"} 2"#));
+}
+
+#[actix_web::test]
+async fn forms_and_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+    let (cookie, info) = session(&app, None).await;
+
+    let req = TestRequest::post()
+        .uri("/form")
+        .cookie(cookie)
+        .insert_header(("x-csrf-token", info["csrf_token"].as_str().unwrap()))
+        .set_form([("username", "carol"), ("number", "7")]);
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::OK, String::from("Username: carol, Number: 7"))
+    );
+
+    let req = TestRequest::post().uri("/form").set_form([("number", "x")]);
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::BAD_REQUEST, String::from("invalid_form"))
+    );
+
+    let long = "c".repeat(100);
+    let req = TestRequest::post()
+        .uri("/form")
+        .set_form([("username", long.as_str())]);
+    assert_eq!(
+        send(&app, req).await,
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            String::from("payload_too_large")
+        )
+    );
+}
+
+#[actix_web::test]
+async fn invalid_fields_are_rejected_with_details() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    let errors = |res: ServiceResponse<_>| async move {
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let problem: Value = read_body_json(res).await;
+        assert_eq!(problem["code"], "validation_failed");
+        problem["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                format!(
+                    "{} {}",
+                    e["field"].as_str().unwrap(),
+                    e["code"].as_str().unwrap()
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // a missing number is no longer taken as 0
+    let req = TestRequest::post()
+        .uri("/form")
+        .set_form([("username", "bad name!")]);
+    let res = call_service(&app, req.to_request()).await;
+    assert_eq!(errors(res).await, ["username charset", "number required"]);
+
+    let req = TestRequest::post().uri("/person/auto").set_json(json!({}));
+    let res = call_service(&app, req.to_request()).await;
+    assert_eq!(errors(res).await, ["username required"]);
+
+    let req = TestRequest::post()
+        .uri("/person/manual")
+        .set_payload(r#"{"name":"bob","number":-1}"#);
+    let res = call_service(&app, req.to_request()).await;
+    assert_eq!(errors(res).await, ["number range"]);
+}
+
+#[actix_web::test]
+async fn retried_posts_are_replayed() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+    let (cookie, info) = session(&app, None).await;
+    let token = info["csrf_token"].as_str().unwrap();
+
+    let form = |number: &str| {
+        TestRequest::post()
+            .uri("/form")
+            .cookie(cookie.clone())
+            .insert_header(("x-csrf-token", token))
+            .insert_header(("idempotency-key", "retry-1"))
+            .set_form([("username", "dave"), ("number", number)])
+    };
+
+    let first = send(&app, form("1")).await;
+    assert_eq!(
+        first,
+        (StatusCode::OK, String::from("Username: dave, Number: 1"))
+    );
+    let res = call_service(&app, form("1").to_request()).await;
+    assert_eq!(res.headers().get("idempotent-replayed").unwrap(), "true");
+
+    assert_eq!(
+        send(&app, form("2")).await,
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            String::from("idempotency_key_reused")
+        )
+    );
+}
+
+#[actix_web::test]
+async fn users_need_a_token() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+    let bearer = (header::AUTHORIZATION, format!("Bearer {}", token()));
+
+    let req = TestRequest::get().uri("/users");
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::UNAUTHORIZED, String::from("unauthorized"))
+    );
+
+    let req = TestRequest::post()
+        .uri("/users")
+        .insert_header(bearer.clone())
+        .set_json(json!({"username": "dave"}));
+    let res = call_service(&app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(res.headers().get(header::LOCATION).unwrap(), "/users/1");
+
+    let req = TestRequest::get()
+        .uri("/users/1")
+        .insert_header(bearer.clone());
+    let user: Value = read_body_json(call_service(&app, req.to_request()).await).await;
+    assert_eq!(user, json!({"id": 1, "username": "dave"}));
+
+    let req = TestRequest::get()
+        .uri("/users/abc")
+        .insert_header(bearer.clone());
+    assert_eq!(send(&app, req).await.1, "invalid_path");
+
+    let req = TestRequest::get()
+        .uri("/users?limit=x")
+        .insert_header(bearer.clone());
+    assert_eq!(send(&app, req).await.1, "invalid_query");
+
+    let req = TestRequest::delete().uri("/users/1").insert_header(bearer);
+    assert_eq!(send(&app, req).await.0, StatusCode::NO_CONTENT);
+}
+
+#[actix_web::test]
+async fn uploads_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    let req = TestRequest::put()
+        .uri("/uploads/notes.txt")
+        .set_payload("hello uploads");
+    assert_eq!(send(&app, req).await.0, StatusCode::CREATED);
+
+    let req = TestRequest::get().uri("/uploads/notes.txt");
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::OK, String::from("hello uploads"))
+    );
+
+    // never rendered, whatever the extension
+    let req = TestRequest::put()
+        .uri("/uploads/page.html")
+        .set_payload("<script>alert(1)</script>");
+    assert_eq!(send(&app, req).await.0, StatusCode::CREATED);
+    let req = TestRequest::get().uri("/uploads/page.html").to_request();
+    let res = call_service(&app, req).await;
+    let headers = res.headers();
+    assert_eq!(
+        headers.get(header::CONTENT_TYPE).unwrap(),
+        "application/octet-stream"
+    );
+    assert_eq!(
+        headers.get(header::CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"page.html\""
+    );
+    assert_eq!(
+        headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+        "nosniff"
+    );
+
+    let req = TestRequest::get().uri("/uploads/notes.txt/status");
+    let status: Value = read_body_json(call_service(&app, req.to_request()).await).await;
+    assert_eq!(status["complete"], true);
+
+    let req = TestRequest::get().uri("/uploads/missing.txt");
+    assert_eq!(send(&app, req).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn jobs_run_in_the_background() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    let req = TestRequest::put()
+        .uri("/uploads/abc.txt")
+        .set_payload("abc");
+    assert_eq!(send(&app, req).await.0, StatusCode::CREATED);
+
+    let req = TestRequest::post()
+        .uri("/jobs")
+        .set_json(json!({ "type": "checksum", "upload": "abc.txt" }))
+        .to_request();
+    let res = call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let location = res
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let job: Value = read_body_json(res).await;
+    assert_eq!(location, format!("/jobs/{}", job["id"].as_str().unwrap()));
+
+    let mut job = job;
+    for _ in 0..200 {
+        if job["status"] == "succeeded" {
+            break;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(10)).await;
+        let req = TestRequest::get().uri(&location).to_request();
+        job = read_body_json(call_service(&app, req).await).await;
+    }
+    assert_eq!(job["status"], "succeeded");
+    assert_eq!(job["progress"], 100);
+    assert_eq!(
+        job["result"]["sha256"],
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+
+    for (body, code) in [
+        (
+            json!({ "type": "sleep", "ms": 600_000 }),
+            "validation_failed",
+        ),
+        (
+            json!({ "type": "checksum", "upload": "../etc" }),
+            "validation_failed",
+        ),
+        (json!({ "type": "compile" }), "invalid_json"),
+    ] {
+        let req = TestRequest::post().uri("/jobs").set_json(body);
+        assert_eq!(send(&app, req).await.1, code);
+    }
+
+    let req = TestRequest::get().uri("/jobs/missing");
+    assert_eq!(send(&app, req).await.0, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn api_versions_by_path_and_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    let req = TestRequest::get().uri("/api").to_request();
+    let list: Value = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(list["default"], 1);
+    assert_eq!(list["versions"][1]["path"], "/api/v2");
+    assert_eq!(
+        list["versions"][1]["media_type"],
+        "application/vnd.actix.v2+json"
+    );
+
+    // v1 is deprecated, with a pointer to its successor
+    let req = TestRequest::get().uri("/api/v1/test").to_request();
+    let res = call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res
+        .headers()
+        .get("deprecation")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .starts_with('@'));
+    assert!(res.headers().contains_key("sunset"));
+    assert_eq!(
+        res.headers().get(header::LINK).unwrap(),
+        "</api/v2>; rel=\"successor-version\""
+    );
+
+    let req = TestRequest::post()
+        .uri("/api/v2/people")
+        .set_json(json!({ "name": "Dana", "number": 3 }))
+        .to_request();
+    let res = call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert!(!res.headers().contains_key("deprecation"));
+    let location = res
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let created: Value = read_body_json(res).await;
+    assert_eq!(location, format!("/api/v2/people/{}", created["id"]));
+
+    let req = TestRequest::get().uri(&location).to_request();
+    let person: Value = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(person["name"], "Dana");
+
+    // unversioned paths pick the version from headers, v1 by default
+    let req = TestRequest::get().uri("/api/test");
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::OK, String::from("test"))
+    );
+    for req in [
+        TestRequest::get()
+            .uri("/api/people")
+            .insert_header(("accept-version", "2")),
+        TestRequest::get()
+            .uri("/api/people")
+            .insert_header((header::ACCEPT, "application/vnd.actix.v2+json")),
+    ] {
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::VARY).unwrap(),
+            "accept-version, accept"
+        );
+        let page: Value = read_body_json(res).await;
+        assert_eq!(page["items"][0]["name"], "Dana");
+    }
+
+    let req = TestRequest::get().uri("/api/people");
+    assert_eq!(send(&app, req).await.0, StatusCode::NOT_FOUND);
+    let req = TestRequest::get()
+        .uri("/api/test")
+        .insert_header(("accept-version", "9"));
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::NOT_ACCEPTABLE, String::from("not_acceptable"))
+    );
+}
+
+#[actix_web::test]
+async fn mutating_requests_are_audited() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(&dir);
+    config.audit.body = true;
+    let app = start(&config).await;
+
+    let req = TestRequest::post()
+        .uri("/echo")
+        .insert_header((header::CONTENT_TYPE, "text/plain"))
+        .set_payload("hello");
+    assert_eq!(send(&app, req).await.0, StatusCode::OK);
+    let req = TestRequest::post()
+        .uri("/person/auto")
+        .insert_header((header::AUTHORIZATION, format!("Bearer {}", token())))
+        .set_json(json!({ "username": "ann", "token": "s3cr3t" }));
+    assert_eq!(send(&app, req).await.0, StatusCode::OK);
+    // reads are not recorded, rejected writes are
+    let req = TestRequest::get().uri("/echo");
+    send(&app, req).await;
+    let req = TestRequest::post()
+        .uri("/form")
+        .set_form([("username", "ann")]);
+    assert_eq!(send(&app, req).await.0, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let text = std::fs::read_to_string(&config.audit.path).unwrap();
+    let records: Vec<Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 3);
+
+    assert_eq!(records[0]["route"], "/echo");
+    assert_eq!(records[0]["body"], "hello");
+    assert_eq!(
+        records[0]["body_sha256"],
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+    );
+    assert!(records[0].get("principal").is_none());
+
+    assert_eq!(records[1]["principal"], "alice");
+    assert_eq!(
+        records[1]["body"],
+        r#"{"token":"[redacted]","username":"ann"}"#
+    );
+    assert_eq!(records[1]["prev"], records[0]["hash"]);
+    assert_eq!(records[2]["status"], 422);
+
+    let verified = actix::audit::verify(text.as_bytes(), None).unwrap();
+    assert_eq!(verified.records, 3);
+}
+
+#[actix_web::test]
+async fn middleware_runs_on_every_route() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    // request ids are added to responses from handlers and the default
+    // handler alike
+    for uri in ["/", "/nope"] {
+        let res = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+        assert!(res.headers().contains_key("x-request-id"));
+    }
+
+    for _ in 0..3 {
+        let req = TestRequest::post().uri("/echo").set_payload("x");
+        assert_eq!(send(&app, req).await.0, StatusCode::OK);
+    }
+    let req = TestRequest::post().uri("/echo").set_payload("x");
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::TOO_MANY_REQUESTS, String::from("rate_limited"))
+    );
+}
+
+#[actix_web::test]
+async fn login_attempts_are_limited() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(&dir);
+    config.rate_limit.rules = RateLimitConfig::default().rules;
+    let app = start(&config).await;
+
+    let login = |peer: &str| {
+        TestRequest::post()
+            .uri("/login")
+            .peer_addr(peer.parse().unwrap())
+            .set_json(json!({ "username": "alice", "password": "rabbit" }))
+    };
+    for _ in 0..5 {
+        assert_eq!(
+            send(&app, login("10.0.0.1:1000")).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+    assert_eq!(
+        send(&app, login("10.0.0.1:1000")).await,
+        (StatusCode::TOO_MANY_REQUESTS, String::from("rate_limited"))
+    );
+    assert_eq!(
+        send(&app, login("10.0.0.2:1000")).await.0,
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[actix_web::test]
+async fn serves_openapi_document() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    let req = TestRequest::get().uri("/openapi.json");
+    let doc: Value = read_body_json(call_service(&app, req.to_request()).await).await;
+    assert!(doc["paths"]["/users/{id}"].is_object());
+}
+
+#[actix_web::test]
+async fn health_and_metrics() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = test_config(&dir);
+    let state = SharedState::new(&config).unwrap();
+    let app = init_service(build_app(&config, &state)).await;
+
+    let req = TestRequest::get().uri("/healthz");
+    assert_eq!(send(&app, req).await.0, StatusCode::OK);
+    let req = TestRequest::get().uri("/readyz");
+    assert_eq!(send(&app, req).await.0, StatusCode::OK);
+
+    state.readiness().set_ready(false);
+    let req = TestRequest::get().uri("/readyz");
+    assert_eq!(send(&app, req).await.0, StatusCode::SERVICE_UNAVAILABLE);
+
+    let req = TestRequest::get().uri("/metrics");
+    let (status, body) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#"http_requests_total{method="GET",route="/readyz",status="503"} 1"#));
+    assert!(
+        body.contains(r#"http_request_duration_seconds_count{method="GET",route="/healthz"} 1"#)
+    );
+}
+
+#[actix_web::test]
+async fn counter_changes_are_streamed() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    for _ in 0..2 {
+        let req = TestRequest::post().uri("/counter/visits");
+        assert_eq!(send(&app, req).await.0, StatusCode::OK);
+    }
+
+    // resume after the first event
+    let req = TestRequest::get()
+        .uri("/events")
+        .insert_header(("last-event-id", "1"))
+        .to_request();
+    let res = call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .is_none_or(|v| v == "identity"));
+
+    let mut body = Box::pin(res.into_body());
+    let mut body = stream::poll_fn(move |cx| body.as_mut().poll_next(cx));
+    let Some(Ok(head)) = body.next().await else {
+        panic!("no events");
+    };
+    assert_eq!(
+        head,
+        "retry: 3000\n\nid: 2\nevent: counter\ndata: {\"name\":\"visits\",\"value\":2}\n\n"
+    );
+}
+
+#[actix_web::test]
+async fn tenants_are_routed_by_host() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config: Config = toml::from_str(
+        r#"
+        base_domain = "example.com"
+
+        [[tenants]]
+        name = "acme"
+        hosts = ["acme.test"]
+        app_name = "Acme"
+
+        [[tenants]]
+        name = "globex"
+        subdomain = "globex"
+        scopes = ["users"]
+        "#,
+    )
+    .unwrap();
+    config.middleware.trace = false;
+    config.middleware.json_log = false;
+    config.auth.secret = Some(String::from(SECRET));
+    config.uploads.dir = dir.path().join("uploads");
+    config.people.path = dir.path().join("people.db");
+    config.jobs.path = dir.path().join("jobs.json");
+    config.audit.path = dir.path().join("audit.jsonl");
+    let app = start(&config).await;
+
+    let get = |host: &str, uri: &str| {
+        TestRequest::get()
+            .uri(uri)
+            .insert_header((header::HOST, host))
+    };
+    for (host, greeting) in [
+        ("acme.test:8081", "Hello Acme"),
+        ("globex.example.com", "Hello globex"),
+        ("localhost", "Hello actix web"),
+    ] {
+        assert_eq!(
+            send(&app, get(host, "/")).await,
+            (StatusCode::OK, greeting.to_string())
+        );
+    }
+
+    // counters are kept per tenant
+    for _ in 0..2 {
+        send(&app, get("acme.test", "/counter")).await;
+    }
+    assert_eq!(
+        send(&app, get("localhost", "/counter")).await.1,
+        "Request number: 1"
+    );
+    assert_eq!(
+        send(&app, get("acme.test", "/counter")).await.1,
+        "Request number: 3"
+    );
+    // and so are their events: acme's third is its third event, not the
+    // fourth on a bus shared with localhost
+    let req = get("acme.test", "/events")
+        .insert_header(("last-event-id", "2"))
+        .to_request();
+    let mut body = Box::pin(call_service(&app, req).await.into_body());
+    let mut body = stream::poll_fn(move |cx| body.as_mut().poll_next(cx));
+    let Some(Ok(head)) = body.next().await else {
+        panic!("no events");
+    };
+    assert_eq!(
+        head,
+        "retry: 3000\n\nid: 3\nevent: counter\ndata: {\"name\":\"default\",\"value\":3}\n\n"
+    );
+
+    // globex only has the users scope on top of the basic routes
+    assert_eq!(
+        send(&app, get("globex.example.com", "/counter")).await,
+        (StatusCode::NOT_FOUND, String::from("not_found"))
+    );
+    assert_eq!(
+        send(&app, get("globex.example.com", "/users")).await.0,
+        StatusCode::UNAUTHORIZED
+    );
+    // probes are served on every host
+    assert_eq!(
+        send(&app, get("globex.example.com", "/healthz")).await.0,
+        StatusCode::OK
+    );
+
+    config.unknown_host = UnknownHost::Reject;
+    let app = start(&config).await;
+    assert_eq!(
+        send(&app, get("localhost", "/")).await,
+        (StatusCode::NOT_FOUND, String::from("not_found"))
+    );
+    assert_eq!(send(&app, get("acme.test", "/")).await.1, "Hello Acme");
+}
+
+#[actix_web::test]
+async fn cors_policies_per_scope() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(&dir);
+    config.cors.policies = toml::from_str::<Config>(
+        r#"
+        [[cors.policies]]
+        path = "/api"
+        origins = ["https://*.example.com"]
+        methods = ["GET"]
+
+        [[cors.policies]]
+        path = "/person"
+        origins = ["https://forms.example.org"]
+        methods = ["POST"]
+        headers = ["content-type", "accept"]
+        credentials = true
+
+        [[cors.policies]]
+        path = "/app"
+        origins = ["*"]
+        "#,
+    )
+    .unwrap()
+    .cors
+    .policies;
+    let app = start(&config).await;
+
+    let preflight = |uri: &str, origin: &str, method: &str| {
+        TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri(uri)
+            .insert_header((header::ORIGIN, origin))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method))
+    };
+    let allowed_origin = |res: &ServiceResponse<_>| {
+        res.headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|v| v.to_str().unwrap().to_string())
+    };
+
+    let res = call_service(
+        &app,
+        preflight("/api/test", "https://app.example.com", "GET").to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(allowed_origin(&res).unwrap(), "https://app.example.com");
+
+    // each scope has its own origins
+    let req = preflight("/person/auto", "https://app.example.com", "POST");
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::FORBIDDEN, String::from("forbidden"))
+    );
+    let req = preflight("/person/auto", "https://forms.example.org", "POST")
+        .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type"));
+    let res = call_service(&app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        res.headers()
+            .get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS)
+            .unwrap(),
+        "true"
+    );
+
+    // the actual request
+    let req = TestRequest::post()
+        .uri("/person/auto")
+        .insert_header((header::ORIGIN, "https://forms.example.org"))
+        .set_json(json!({ "username": "bob" }))
+        .to_request();
+    let res = call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(allowed_origin(&res).unwrap(), "https://forms.example.org");
+    assert!(res.headers().get_all(header::VARY).any(|v| v == "origin"));
+
+    // preflights for /app are answered by the middleware, while the HEAD
+    // route still answers 405 to the real request, readable cross-origin
+    let res = call_service(
+        &app,
+        preflight("/app", "https://elsewhere.test", "HEAD").to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(allowed_origin(&res).unwrap(), "*");
+
+    let req = TestRequest::default()
+        .method(Method::HEAD)
+        .uri("/app")
+        .insert_header((header::ORIGIN, "https://elsewhere.test"))
+        .to_request();
+    let res = call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(allowed_origin(&res).unwrap(), "*");
+
+    // an OPTIONS that isn't a preflight goes to the routes
+    let req = TestRequest::default()
+        .method(Method::OPTIONS)
+        .uri("/app")
+        .insert_header((header::ORIGIN, "https://elsewhere.test"));
+    assert_eq!(send(&app, req).await.0, StatusCode::METHOD_NOT_ALLOWED);
+
+    // paths without a policy get no CORS headers
+    let req = TestRequest::get()
+        .uri("/hey")
+        .insert_header((header::ORIGIN, "https://app.example.com"))
+        .to_request();
+    assert_eq!(allowed_origin(&call_service(&app, req).await), None);
+}
+
+#[actix_web::test]
+async fn posted_people_are_stored_and_listed() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = start(&test_config(&dir)).await;
+
+    for name in ["carol", "alice"] {
+        let req = TestRequest::post()
+            .uri("/person/auto")
+            .set_json(json!({ "username": name }));
+        assert_eq!(send(&app, req).await.0, StatusCode::OK);
+    }
+    let req = TestRequest::post()
+        .uri("/person/manual")
+        .set_json(json!({ "name": "Bob", "number": 7 }));
+    assert_eq!(send(&app, req).await.0, StatusCode::OK);
+    // rejected bodies are not stored
+    let req = TestRequest::post()
+        .uri("/person/auto")
+        .set_json(json!({ "username": "" }));
+    assert_eq!(send(&app, req).await.0, StatusCode::UNPROCESSABLE_ENTITY);
+    // nor are the ones whose answer can't be sent
+    let req = TestRequest::post()
+        .uri("/person/manual")
+        .insert_header((header::ACCEPT, "text/html"))
+        .set_json(json!({ "name": "Abe" }));
+    assert_eq!(send(&app, req).await.0, StatusCode::NOT_ACCEPTABLE);
+
+    let req = TestRequest::get()
+        .uri("/person?sort=name&limit=2")
+        .to_request();
+    let page: Value = read_body_json(call_service(&app, req).await).await;
+    let names: Vec<_> = page["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| &p["name"])
+        .collect();
+    assert_eq!(names, ["alice", "Bob"]);
+    assert_eq!(page["items"][1]["source"], "manual");
+    assert_eq!(page["items"][1]["number"], 7);
+
+    let next = page["next"].as_str().unwrap();
+    let req = TestRequest::get()
+        .uri(&format!("/person?sort=name&limit=2&cursor={next}"))
+        .to_request();
+    let page: Value = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(page["items"][0]["name"], "carol");
+    assert!(page.get("next").is_none());
+
+    let req = TestRequest::get().uri("/person?name=ARO").to_request();
+    let page: Value = read_body_json(call_service(&app, req).await).await;
+    assert_eq!(page["items"].as_array().unwrap().len(), 1);
+
+    for uri in ["/person?sort=age", "/person?cursor=zz"] {
+        let req = TestRequest::get().uri(uri);
+        assert_eq!(
+            send(&app, req).await,
+            (StatusCode::BAD_REQUEST, String::from("invalid_query"))
+        );
+    }
+}
+
+#[actix_web::test]
+async fn sessions_log_in_and_protect_forms() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(&dir);
+    config.limits.form = 256;
+    config.sessions.encrypt = true;
+    config.sessions.store = actix::config::StoreKind::File;
+    let app = start(&config).await;
+
+    let form = |cookie: &Cookie<'static>, token: &str| {
+        TestRequest::post()
+            .uri("/form")
+            .cookie(cookie.clone())
+            .set_form([
+                ("username", "carol"),
+                ("number", "7"),
+                ("csrf_token", token),
+            ])
+    };
+
+    let req = TestRequest::post()
+        .uri("/form")
+        .set_form([("username", "carol"), ("number", "7")]);
+    assert_eq!(
+        send(&app, req).await,
+        (StatusCode::FORBIDDEN, String::from("forbidden"))
+    );
+
+    let (anonymous, info) = session(&app, None).await;
+    assert!(anonymous.http_only().unwrap());
+    assert_eq!(anonymous.same_site(), Some(SameSite::Lax));
+    assert_eq!(info["user"], Value::Null);
+    let token = info["csrf_token"].as_str().unwrap().to_string();
+    assert_eq!(
+        send(&app, form(&anonymous, "nope")).await.0,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(send(&app, form(&anonymous, &token)).await.0, StatusCode::OK);
+
+    let login = |password: &str| {
+        TestRequest::post()
+            .uri("/login")
+            .cookie(anonymous.clone())
+            .set_json(json!({ "username": "alice", "password": password }))
+    };
+    assert_eq!(
+        send(&app, login("rabbit")).await,
+        (StatusCode::UNAUTHORIZED, String::from("unauthorized"))
+    );
+    let res = call_service(&app, login("wonderland").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let cookie = res
+        .response()
+        .cookies()
+        .find(|c| c.name() == "session")
+        .unwrap()
+        .into_owned();
+    let info: Value = read_body_json(res).await;
+    assert_eq!(info["user"], "alice");
+    assert_eq!(std::fs::read_dir(&config.sessions.dir).unwrap().count(), 1);
+
+    // logging in gives a new id and token, the old ones are worthless
+    assert_ne!(cookie.value(), anonymous.value());
+    assert_ne!(info["csrf_token"], token.as_str());
+    assert_eq!(
+        send(&app, form(&cookie, &token)).await.0,
+        StatusCode::FORBIDDEN
+    );
+    let (_, info) = session(&app, Some(anonymous.clone())).await;
+    assert_eq!(info["user"], Value::Null);
+    let (_, info) = session(&app, Some(cookie.clone())).await;
+    assert_eq!(info["user"], "alice");
+
+    let mut forged = cookie.clone();
+    forged.set_value(format!("x{}", cookie.value()));
+    let (_, info) = session(&app, Some(forged)).await;
+    assert_eq!(info["user"], Value::Null);
+
+    let req = TestRequest::post().uri("/logout").cookie(cookie.clone());
+    let res = call_service(&app, req.to_request()).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let removal = res.response().cookies().next().unwrap();
+    assert_eq!(removal.value(), "");
+    let (_, info) = session(&app, Some(cookie)).await;
+    assert_eq!(info["user"], Value::Null);
+}
+
+#[actix_web::test]
+async fn idempotency_keys_are_per_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(&dir);
+    config.limits.form = 256;
+    let app = start(&config).await;
+
+    let form = |cookie: &Cookie<'static>, token: Option<&str>| {
+        let mut req = TestRequest::post()
+            .uri("/form")
+            .cookie(cookie.clone())
+            .insert_header(("idempotency-key", "form-1"))
+            .set_form([("username", "carol"), ("number", "7")]);
+        if let Some(token) = token {
+            req = req.insert_header(("x-csrf-token", token.to_string()));
+        }
+        req.to_request()
+    };
+
+    let (a, info) = session(&app, None).await;
+    let token_a = info["csrf_token"].as_str().unwrap().to_string();
+    let (b, info) = session(&app, None).await;
+    let token_b = info["csrf_token"].as_str().unwrap().to_string();
+
+    let res = call_service(&app, form(&a, Some(&token_a))).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = call_service(&app, form(&a, Some(&token_a))).await;
+    assert!(res.headers().contains_key("idempotent-replayed"));
+
+    // another session with the same key gets its own response
+    let res = call_service(&app, form(&b, Some(&token_b))).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(!res.headers().contains_key("idempotent-replayed"));
+
+    // and a replay without the CSRF token is not the same request
+    let res = call_service(&app, form(&a, None)).await;
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[actix_web::test]
+async fn anonymous_sessions_are_capped() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(&dir);
+    config.sessions.max_sessions = 1;
+    let app = start(&config).await;
+
+    let (anonymous, _) = session(&app, None).await;
+    let req = TestRequest::get().uri("/session");
+    assert_eq!(
+        send(&app, req).await,
+        (
+            StatusCode::INSUFFICIENT_STORAGE,
+            String::from("quota_exceeded")
+        )
+    );
+    // the existing session still works, and logins are never refused
+    session(&app, Some(anonymous)).await;
+    let req = TestRequest::post()
+        .uri("/login")
+        .set_json(json!({ "username": "alice", "password": "wonderland" }));
+    assert_eq!(send(&app, req).await.0, StatusCode::OK);
+}